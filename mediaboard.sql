@@ -1,28 +1,41 @@
-create table item
+create table if not exists item
 (
-    id         INTEGER not null
+    id           INTEGER not null
         constraint item_pk
             primary key,
-    name       TEXT    not null,
-    path       TEXT    not null,
-    file_type  TEXT    not null,
-    created_at TEXT default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
-    parent     INTEGER
+    name         TEXT    not null,
+    path         TEXT    not null,
+    file_type    TEXT    not null,
+    created_at   TEXT default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
+    parent       INTEGER
         references item
             on update cascade on delete cascade,
-    md5        TEXT    not null
+    md5          TEXT    not null,
+    taken_at     TEXT,
+    deleted_at   TEXT,
+    visibility   INTEGER,
+    owner        INTEGER
+        references user
+            on delete set null,
+    access       INTEGER default 0 not null,
+    access_owner INTEGER,
+    original_name TEXT,
+    uploader     INTEGER
+        references user
+            on delete set null,
+    import_method TEXT
 );
 
-create unique index item_id_uindex
+create unique index if not exists item_id_uindex
     on item (id);
 
-create unique index item_md5_uindex
+create unique index if not exists item_md5_uindex
     on item (md5);
 
-create unique index item_path_uindex
+create unique index if not exists item_path_uindex
     on item (path);
 
-create table tag
+create table if not exists tag
 (
     id         INTEGER not null
         primary key,
@@ -32,7 +45,7 @@ create table tag
     alias      integer
 );
 
-create table item_tag
+create table if not exists item_tag
 (
     id   INTEGER not null
         constraint item_tag_pk
@@ -45,13 +58,13 @@ create table item_tag
             on delete cascade
 );
 
-create unique index item_tag_item_tag_uindex
+create unique index if not exists item_tag_item_tag_uindex
     on item_tag (item, tag);
 
-create unique index tag_id_uindex
+create unique index if not exists tag_id_uindex
     on tag (id);
 
-create table tag_tag
+create table if not exists tag_tag
 (
     id  INTEGER not null
         constraint tag_tag_pk
@@ -64,6 +77,241 @@ create table tag_tag
             on delete cascade
 );
 
-create unique index tag_tag_tag_dep_uindex
+create unique index if not exists tag_tag_tag_dep_uindex
     on tag_tag (tag, dep);
 
+create table if not exists transcode
+(
+    id         INTEGER not null
+        constraint transcode_pk
+            primary key,
+    item       INTEGER not null
+        references item
+            on delete cascade,
+    format     TEXT    not null,
+    path       TEXT,
+    status     TEXT default 'pending' not null,
+    created_at TEXT default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null
+);
+
+create unique index if not exists transcode_item_uindex
+    on transcode (item);
+
+create table if not exists metadata
+(
+    id          INTEGER not null
+        constraint metadata_pk
+            primary key,
+    item        INTEGER not null
+        references item
+            on delete cascade,
+    width       INTEGER,
+    height      INTEGER,
+    duration    REAL,
+    frame_rate  REAL,
+    video_codec TEXT,
+    audio_codec TEXT,
+    camera      TEXT,
+    captured_at TEXT,
+    orientation INTEGER,
+    latitude    REAL,
+    longitude   REAL
+);
+
+create unique index if not exists metadata_item_uindex
+    on metadata (item);
+
+create table if not exists phash
+(
+    id    INTEGER not null
+        constraint phash_pk
+            primary key,
+    item  INTEGER not null
+        references item
+            on delete cascade,
+    frame INTEGER default 0 not null,
+    hash  INTEGER not null
+);
+
+create unique index if not exists phash_item_frame_uindex
+    on phash (item, frame);
+
+create table if not exists color
+(
+    id     INTEGER not null
+        constraint color_pk
+            primary key,
+    item   INTEGER not null
+        references item
+            on delete cascade,
+    red    INTEGER not null,
+    green  INTEGER not null,
+    blue   INTEGER not null,
+    weight REAL    not null
+);
+
+create index if not exists color_item_index
+    on color (item);
+
+create table if not exists trash
+(
+    id         INTEGER not null
+        constraint trash_pk
+            primary key,
+    item       INTEGER not null
+        references item
+            on delete cascade,
+    path       TEXT    not null,
+    deleted_at TEXT default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null
+);
+
+create unique index if not exists trash_item_uindex
+    on trash (item);
+
+create table if not exists user
+(
+    id         INTEGER not null
+        constraint user_pk
+            primary key,
+    name       TEXT    not null,
+    password   TEXT    not null,
+    role       TEXT    default 'viewer' not null,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null
+);
+
+create unique index if not exists user_name_uindex
+    on user (name);
+
+create table if not exists session
+(
+    id         INTEGER not null
+        constraint session_pk
+            primary key,
+    token      TEXT    not null,
+    user       INTEGER not null
+        references user
+            on delete cascade,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
+    expires_at TEXT    not null
+);
+
+create unique index if not exists session_token_uindex
+    on session (token);
+
+create table if not exists api_token
+(
+    id           INTEGER not null
+        constraint api_token_pk
+            primary key,
+    user         INTEGER not null
+        references user
+            on delete cascade,
+    name         TEXT    not null,
+    hash         TEXT    not null,
+    scopes       TEXT    not null,
+    created_at   TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
+    last_used_at TEXT
+);
+
+create unique index if not exists api_token_hash_uindex
+    on api_token (hash);
+
+create table if not exists share
+(
+    id         INTEGER not null
+        constraint share_pk
+            primary key,
+    item       INTEGER not null
+        references item
+            on delete cascade,
+    token      TEXT    not null,
+    password   TEXT,
+    download   INTEGER default 0 not null,
+    created_by INTEGER
+        references user
+            on delete set null,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
+    expires_at TEXT
+);
+
+create unique index if not exists share_token_uindex
+    on share (token);
+
+create table if not exists audit
+(
+    id         INTEGER not null
+        constraint audit_pk
+            primary key,
+    user       INTEGER
+        references user
+            on delete set null,
+    action     TEXT    not null,
+    entity     TEXT    not null,
+    entity_id  INTEGER not null,
+    before     TEXT,
+    after      TEXT,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null
+);
+
+create index if not exists audit_entity_index
+    on audit (entity, entity_id);
+
+create table if not exists batch_file
+(
+    id         INTEGER not null
+        constraint batch_file_pk
+            primary key,
+    batch      TEXT    not null,
+    user       INTEGER
+        references user
+            on delete cascade,
+    name       TEXT    not null,
+    folder     TEXT    default '' not null,
+    real_name  TEXT,
+    md5        TEXT,
+    status     TEXT    not null,
+    item       INTEGER
+        references item
+            on delete set null,
+    message    TEXT,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
+    archive    TEXT
+);
+
+create index if not exists batch_file_batch_index
+    on batch_file (batch);
+
+create table if not exists partial_upload
+(
+    id         INTEGER not null
+        constraint partial_upload_pk
+            primary key,
+    token      TEXT    not null,
+    user       INTEGER
+        references user
+            on delete cascade,
+    batch      TEXT    not null,
+    name       TEXT    not null,
+    folder     TEXT    default '' not null,
+    length     INTEGER not null,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null,
+    updated_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null
+);
+
+create unique index if not exists partial_upload_token_uindex
+    on partial_upload (token);
+
+create table if not exists item_source
+(
+    id         INTEGER not null
+        constraint item_source_pk
+            primary key,
+    item       INTEGER not null
+        references item
+            on delete cascade,
+    url        TEXT    not null,
+    created_at TEXT    default (STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')) not null
+);
+
+create index if not exists item_source_item_index
+    on item_source (item);
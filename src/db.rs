@@ -2,4 +2,19 @@ pub mod item;
 pub mod tag;
 pub mod item_tag;
 pub mod tag_tag;
+pub mod transcode;
+pub mod metadata;
+pub mod phash;
+pub mod color;
+pub mod trash;
+pub mod user;
+pub mod session;
+pub mod api_token;
+pub mod share;
+pub mod audit;
+pub mod batch_file;
+pub mod partial_upload;
+pub mod item_source;
+pub mod metatag;
+pub mod migrate;
 mod func;
\ No newline at end of file
@@ -0,0 +1,102 @@
+use serde::Serialize;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+// What a script may do with a token, on top of what the user's role allows
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scope {
+    Read,
+    Upload,
+    Tag,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::Read, Scope::Upload, Scope::Tag, Scope::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Upload => "upload",
+            Scope::Tag => "tag",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+// Only the SHA-256 of the token is stored, the token is shown once when created.
+// `scopes` are separated by spaces.
+#[derive(Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user: i64,
+    pub name: String,
+    pub scopes: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+impl ApiToken {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes
+            .split_whitespace()
+            .any(|name| name == scope.as_str())
+    }
+}
+
+pub async fn insert(
+    pool: &SqlitePool,
+    user: i64,
+    name: &str,
+    hash: &str,
+    scopes: &str,
+) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT INTO api_token (user, name, hash, scopes) VALUES (?, ?, ?, ?)"#,
+        user,
+        name,
+        hash,
+        scopes
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_by_user(pool: &SqlitePool, user: i64) -> Result<Vec<ApiToken>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiToken,
+        "SELECT id, user, name, scopes, created_at, last_used_at FROM api_token
+        WHERE user = ? ORDER BY created_at DESC",
+        user
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_by_hash(pool: &SqlitePool, hash: &str) -> Result<ApiToken, sqlx::Error> {
+    sqlx::query_as!(
+        ApiToken,
+        "SELECT id, user, name, scopes, created_at, last_used_at FROM api_token WHERE hash = ?",
+        hash
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn update_last_used(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE api_token SET last_used_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW') WHERE id = ?",
+        id
+    )
+    .execute(pool)
+    .await
+}
+
+// Only deletes the token if it belongs to `user`
+pub async fn delete(pool: &SqlitePool, id: i64, user: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM api_token WHERE id = ? AND user = ?", id, user)
+        .execute(pool)
+        .await
+}
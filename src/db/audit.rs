@@ -0,0 +1,121 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+// Actions recorded in the log and the kinds of entity they apply to
+pub const ACTIONS: [&str; 6] = ["upload", "update", "tag", "trash", "restore", "delete"];
+pub const ENTITIES: [&str; 2] = ["item", "tag"];
+
+// A change made to an item or a tag. `user` is who made it, or NULL for the server itself,
+// like the purge worker. `before` and `after` are JSON, NULL when the entity did not exist.
+#[derive(Serialize)]
+pub struct Audit {
+    pub id: i64,
+    pub user: Option<i64>,
+    pub user_name: Option<String>,
+    pub action: String,
+    pub entity: String,
+    pub entity_id: i64,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Default)]
+pub struct Filter<'a> {
+    pub user: Option<i64>,
+    pub action: Option<&'a str>,
+    pub entity: Option<&'a str>,
+    pub entity_id: Option<i64>,
+}
+
+pub async fn insert(
+    pool: &SqlitePool,
+    user: Option<i64>,
+    action: &str,
+    entity: &str,
+    entity_id: i64,
+    before: Option<String>,
+    after: Option<String>,
+) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT INTO audit (user, action, entity, entity_id, before, after) VALUES (?, ?, ?, ?, ?, ?)"#,
+        user,
+        action,
+        entity,
+        entity_id,
+        before,
+        after
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+// Failing to record is only reported, the change itself is already done
+pub async fn record<T: Serialize>(
+    pool: &SqlitePool,
+    user: Option<i64>,
+    action: &str,
+    entity: &str,
+    entity_id: i64,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    let before = before.and_then(|value| serde_json::to_string(value).ok());
+    let after = after.and_then(|value| serde_json::to_string(value).ok());
+    if let Err(err) = insert(pool, user, action, entity, entity_id, before, after).await {
+        eprintln!("Failed to record {} of {} {}. {:?}", action, entity, entity_id, err);
+    }
+}
+
+pub async fn find_by_id(pool: &SqlitePool, id: i64) -> Result<Audit, sqlx::Error> {
+    sqlx::query_as!(
+        Audit,
+        r#"SELECT audit.id, audit.user, user.name AS "user_name?", action, entity, entity_id, before, after,
+            audit.created_at
+        FROM audit LEFT JOIN user ON user.id = audit.user WHERE audit.id = ?"#,
+        id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+// Newest first, with the number of events matching the filter
+pub async fn find(
+    pool: &SqlitePool,
+    filter: &Filter<'_>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<Audit>, i64), sqlx::Error> {
+    let events = sqlx::query_as!(
+        Audit,
+        r#"SELECT audit.id, audit.user, user.name AS "user_name?", action, entity, entity_id, before, after,
+            audit.created_at
+        FROM audit LEFT JOIN user ON user.id = audit.user
+        WHERE (?1 IS NULL OR audit.user = ?1) AND (?2 IS NULL OR action = ?2)
+            AND (?3 IS NULL OR entity = ?3) AND (?4 IS NULL OR entity_id = ?4)
+        ORDER BY audit.id DESC LIMIT ?5 OFFSET ?6"#,
+        filter.user,
+        filter.action,
+        filter.entity,
+        filter.entity_id,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let count = sqlx::query!(
+        r#"SELECT COUNT(*) AS count FROM audit
+        WHERE (?1 IS NULL OR user = ?1) AND (?2 IS NULL OR action = ?2)
+            AND (?3 IS NULL OR entity = ?3) AND (?4 IS NULL OR entity_id = ?4)"#,
+        filter.user,
+        filter.action,
+        filter.entity,
+        filter.entity_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok((events, count.count as i64))
+}
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+// A file of an upload waiting in `tmp/` for its album and tags. `folder` is where it was in an
+// uploaded folder, `real_name` its name in `tmp/`. `status` is "new", or "duplicate" of `item`,
+// or "rejected" with a `message`. `archive` is the name of the archive it was extracted from.
+#[derive(Serialize)]
+pub struct BatchFile {
+    pub id: i64,
+    pub batch: String,
+    pub user: Option<i64>,
+    pub name: String,
+    pub folder: String,
+    pub real_name: Option<String>,
+    pub md5: Option<String>,
+    pub status: String,
+    pub item: Option<i64>,
+    pub message: Option<String>,
+    pub created_at: String,
+    pub archive: Option<String>,
+}
+
+impl BatchFile {
+    pub fn new(batch: &str, user: Option<i64>, name: &str, folder: &str) -> BatchFile {
+        BatchFile {
+            id: 0,
+            batch: batch.to_string(),
+            user,
+            name: name.to_string(),
+            folder: folder.to_string(),
+            real_name: None,
+            md5: None,
+            status: "rejected".to_string(),
+            item: None,
+            message: None,
+            created_at: String::new(),
+            archive: None,
+        }
+    }
+}
+
+pub async fn insert(pool: &SqlitePool, file: &BatchFile) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT INTO batch_file (batch, user, name, folder, real_name, md5, status, item, message, archive)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        file.batch,
+        file.user,
+        file.name,
+        file.folder,
+        file.real_name,
+        file.md5,
+        file.status,
+        file.item,
+        file.message,
+        file.archive
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+// In the order they were sent
+pub async fn find_by_batch(
+    pool: &SqlitePool,
+    batch: &str,
+    user: Option<i64>,
+) -> Result<Vec<BatchFile>, sqlx::Error> {
+    sqlx::query_as!(
+        BatchFile,
+        "SELECT * FROM batch_file WHERE batch = ? AND user IS ? ORDER BY id",
+        batch,
+        user
+    )
+    .fetch_all(pool)
+    .await
+}
+
+// A new file of the batch with the same content
+pub async fn find_new_by_md5(pool: &SqlitePool, batch: &str, md5: &str) -> Result<BatchFile, sqlx::Error> {
+    sqlx::query_as!(
+        BatchFile,
+        "SELECT * FROM batch_file WHERE batch = ? AND md5 = ? AND status = 'new'",
+        batch,
+        md5
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn find_older_than(pool: &SqlitePool, hours: i64) -> Result<Vec<BatchFile>, sqlx::Error> {
+    let modifier = format!("-{} hours", hours);
+    sqlx::query_as!(
+        BatchFile,
+        "SELECT * FROM batch_file WHERE created_at < STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?)",
+        modifier
+    )
+    .fetch_all(pool)
+    .await
+}
+
+// A file waiting in `tmp/` that `user` sent
+pub async fn find_new_by_real_name(
+    pool: &SqlitePool,
+    real_name: &str,
+    user: Option<i64>,
+) -> Result<BatchFile, sqlx::Error> {
+    sqlx::query_as!(
+        BatchFile,
+        "SELECT * FROM batch_file WHERE real_name = ? AND user IS ? AND status = 'new' LIMIT 1",
+        real_name,
+        user
+    )
+    .fetch_one(pool)
+    .await
+}
+
+// Batches waiting to be added that have this file in `tmp/`
+pub async fn count_by_real_name(pool: &SqlitePool, real_name: &str) -> Result<i64, sqlx::Error> {
+    let count = sqlx::query!(
+        "SELECT COUNT(*) AS count FROM batch_file WHERE real_name = ? AND status = 'new'",
+        real_name
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(count.count as i64)
+}
+
+pub async fn update_status(
+    pool: &SqlitePool,
+    id: i64,
+    status: &str,
+    message: Option<&str>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("UPDATE batch_file SET status = ?, message = ? WHERE id = ?", status, message, id)
+        .execute(pool)
+        .await
+}
+
+pub async fn delete_by_id(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM batch_file WHERE id = ?", id)
+        .execute(pool)
+        .await
+}
+
+pub async fn delete_by_batch(pool: &SqlitePool, batch: &str) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM batch_file WHERE batch = ?", batch)
+        .execute(pool)
+        .await
+}
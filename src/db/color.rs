@@ -0,0 +1,50 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct Color {
+    pub id: i64,
+    pub item: i64,
+    pub red: i64,
+    pub green: i64,
+    pub blue: i64,
+    pub weight: f64,
+}
+
+pub async fn insert(
+    pool: &SqlitePool,
+    item: i64,
+    (red, green, blue): (i64, i64, i64),
+    weight: f64,
+) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT INTO color (item, red, green, blue, weight) VALUES (?, ?, ?, ?, ?)"#,
+        item,
+        red,
+        green,
+        blue,
+        weight
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_by_item(pool: &SqlitePool, item: i64) -> Result<Vec<Color>, sqlx::Error> {
+    sqlx::query_as!(Color, "SELECT * FROM color WHERE item = ? ORDER BY weight DESC", item)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn find_by_items(pool: &SqlitePool, item_ids: Vec<i64>) -> Result<Vec<Color>, sqlx::Error> {
+    let ids: Vec<String> = item_ids.iter().map(|id| id.to_string()).collect();
+    let ids_join = format!("[{}]", ids.join(","));
+    sqlx::query_as!(
+        Color,
+        "SELECT * FROM color WHERE item IN (SELECT value FROM JSON_EACH(?)) ORDER BY item, weight DESC",
+        ids_join
+    )
+    .fetch_all(pool)
+    .await
+}
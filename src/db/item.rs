@@ -1,13 +1,18 @@
-use std::fs::{remove_dir_all, remove_file};
-use std::path::Path;
+use std::fs::{create_dir_all, remove_dir_all, remove_file, rename};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
 use async_recursion::async_recursion;
+use async_std::task;
 use serde::Serialize;
 use sqlx::sqlite::SqliteQueryResult;
-use sqlx::SqlitePool;
-use super::{item_tag, tag};
+use sqlx::{Row, SqlitePool};
+use super::metatag::{self, MetaTag};
+use super::{audit, item_tag, tag, transcode, trash};
 
-#[derive(Serialize)]
+#[derive(Serialize, sqlx::FromRow)]
 pub struct Item {
     pub id: i64,
     pub name: String,
@@ -16,6 +21,73 @@ pub struct Item {
     pub created_at: String,
     pub parent: Option<i64>,
     pub md5: String,
+    pub taken_at: Option<String>,
+    pub deleted_at: Option<String>,
+    pub visibility: Option<i64>,
+    pub owner: Option<i64>,
+    pub access: i64,
+    pub access_owner: Option<i64>,
+    // Name of the file before it was stored as `md5.ext`
+    pub original_name: Option<String>,
+    pub uploader: Option<i64>,
+    // How the file was added, like `upload`, `url`, `archive` or `inbox`
+    pub import_method: Option<String>,
+}
+
+// Who can see an item. Items without their own visibility take the one of their parent,
+// and are never more visible than it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Public,
+    LoggedIn,
+    Owner,
+}
+
+impl Visibility {
+    pub fn from_level(level: i64) -> Option<Visibility> {
+        match level {
+            0 => Some(Visibility::Public),
+            1 => Some(Visibility::LoggedIn),
+            2 => Some(Visibility::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn level(&self) -> i64 {
+        match self {
+            Visibility::Public => 0,
+            Visibility::LoggedIn => 1,
+            Visibility::Owner => 2,
+        }
+    }
+}
+
+// Someone looking at items: they see items up to `level` and owner-only items of `user`
+#[derive(Clone, Copy)]
+pub struct Viewer {
+    pub level: i64,
+    pub user: Option<i64>,
+}
+
+impl Viewer {
+    pub const GUEST: Viewer = Viewer { level: 0, user: None };
+    pub const ADMIN: Viewer = Viewer { level: 2, user: None };
+}
+
+// Date that drives the default ordering of items
+#[derive(Clone, Copy)]
+pub enum DateOrder {
+    Created,
+    Taken,
+}
+
+impl DateOrder {
+    pub fn column(&self) -> &'static str {
+        match self {
+            DateOrder::Created => "item.created_at",
+            DateOrder::Taken => "COALESCE(item.taken_at, item.created_at)",
+        }
+    }
 }
 
 macro_rules! insert {
@@ -47,15 +119,39 @@ macro_rules! update {
 }
 
 macro_rules! find_by_column {
-    ($pool: expr, $col: literal, $val: expr) => {
-        sqlx::query_as!(Item, "SELECT * FROM item WHERE " + $col + " = ?", $val).fetch_all($pool).await
+    ($pool: expr, $viewer: expr, $col: literal, $val: expr) => {
+        sqlx::query_as!(Item, "SELECT * FROM item WHERE deleted_at IS NULL AND (access <= ? OR access_owner = ?) AND " + $col + " = ?",
+            $viewer.level, $viewer.user, $val).fetch_all($pool).await
     };
-    ($pool: expr, $col: literal, $val: expr, $limit: expr, $offset: expr, $order: literal) => {
+    ($pool: expr, $viewer: expr, $col: literal, $val: expr, $limit: expr, $offset: expr, $order: literal) => {
         sqlx::query_as!(Item, r#"SELECT item.id as "id!", item.name as "name!", item.path as "path!",
                                     item.file_type as "file_type!", item.created_at as "created_at!",
-                                    item.parent as parent, item.md5 as "md5!"
-                        FROM item WHERE "# + $col + " = ? ORDER BY " + $order + " LIMIT ? OFFSET ?"
-        , $val, $limit, $offset).fetch_all($pool).await
+                                    item.parent as parent, item.md5 as "md5!", item.taken_at as taken_at,
+                                    item.deleted_at as deleted_at, item.visibility as visibility, item.owner as owner,
+                                    item.access as "access!", item.access_owner as access_owner,
+                                    item.original_name as original_name, item.uploader as uploader,
+                                    item.import_method as import_method
+                        FROM item WHERE deleted_at IS NULL AND (access <= ? OR access_owner = ?) AND "# + $col + " = ? ORDER BY " + $order + " LIMIT ? OFFSET ?"
+        , $viewer.level, $viewer.user, $val, $limit, $offset).fetch_all($pool).await
+    }
+}
+
+macro_rules! find_not_in_series {
+    ($pool: expr, $viewer: expr, $limit: expr, $offset: expr, $order: literal) => {
+        sqlx::query_as!(Item,
+            r#"SELECT item.id as "id!", item.name as "name!", item.path as "path!",
+                      item.file_type as "file_type!", item.created_at as "created_at!",
+                      item.parent as parent, item.md5 as "md5!", item.taken_at as taken_at,
+                      item.deleted_at as deleted_at, item.visibility as visibility, item.owner as owner,
+                      item.access as "access!", item.access_owner as access_owner,
+                      item.original_name as original_name, item.uploader as uploader,
+                      item.import_method as import_method
+            FROM item WHERE deleted_at IS NULL AND (access <= ? OR access_owner = ?) AND (parent NOT IN (
+                SELECT item.id FROM item LEFT JOIN item_tag ON item_tag.item = item.id
+                LEFT JOIN tag ON item_tag.tag = tag.id
+                WHERE tag.name == "series") OR parent is null)
+            ORDER BY "# + $order + " LIMIT ? OFFSET ?"
+        , $viewer.level, $viewer.user, $limit, $offset).fetch_all($pool).await
     }
 }
 
@@ -85,6 +181,20 @@ macro_rules! find_one_by_column {
 //     };
 // }
 
+macro_rules! bind_values {
+    ($query: expr, $values: expr) => {{
+        let mut query = $query;
+        for value in $values {
+            query = match value {
+                metatag::Value::Real(v) => query.bind(*v),
+                metatag::Value::Text(v) => query.bind(v.clone()),
+                metatag::Value::Integer(v) => query.bind(*v),
+            };
+        }
+        query
+    }};
+}
+
 macro_rules! delete_by_column {
     ($col: expr, $val: expr, $pool: expr) => {
         sqlx::query!("DELETE FROM item WHERE " + $col + " = ?", $val).execute($pool).await
@@ -101,9 +211,22 @@ impl Item {
             created_at: String::new(),
             parent: None,
             md5: String::new(),
+            taken_at: None,
+            deleted_at: None,
+            visibility: None,
+            owner: None,
+            access: 0,
+            access_owner: None,
+            original_name: None,
+            uploader: None,
+            import_method: None,
         }
     }
 
+    pub fn visible_to(&self, viewer: &Viewer) -> bool {
+        self.access <= viewer.level || (viewer.user.is_some() && self.access_owner == viewer.user)
+    }
+
     pub fn new(name: String, path: String, file_type: String) -> Item {
         Item {
             id: 0,
@@ -113,41 +236,124 @@ impl Item {
             created_at: String::new(),
             parent: None,
             md5: String::new(),
+            taken_at: None,
+            deleted_at: None,
+            visibility: None,
+            owner: None,
+            access: 0,
+            access_owner: None,
+            original_name: None,
+            uploader: None,
+            import_method: None,
         }
     }
 }
 
 pub async fn insert(pool: &SqlitePool, item: &Item) -> Result<i64, sqlx::Error> {
-    if item.parent != None {
-        let id = insert!(item.name, item.path, item.file_type, item.md5, item.parent, pool);
-        Ok(id)
+    let id = if item.parent != None {
+        insert!(item.name, item.path, item.file_type, item.md5, item.parent, pool)
     } else {
-        let id = insert!(item.name, item.path, item.file_type, item.md5, pool);
-        Ok(id)
+        insert!(item.name, item.path, item.file_type, item.md5, pool)
+    };
+    if item.owner.is_some() {
+        sqlx::query!("UPDATE item SET owner = ? WHERE id = ?", item.owner, id).execute(pool).await?;
     }
+    if item.import_method.is_some() {
+        sqlx::query!("UPDATE item SET original_name = ?, uploader = ?, import_method = ? WHERE id = ?",
+            item.original_name, item.uploader, item.import_method, id).execute(pool).await?;
+    }
+    update_access(pool, id).await?;
+    Ok(id)
+}
+
+pub async fn update(pool: &SqlitePool, item: Item, actor: Option<i64>) -> Result<SqliteQueryResult, sqlx::Error> {
+    let before = find_by_id(pool, item.id).await.ok();
+    let result = update!(item.id, item.name, item.path, item.parent, pool)?;
+    // The item may have moved to another album
+    update_access(pool, item.id).await?;
+    let after = find_by_id(pool, item.id).await.ok();
+    audit::record(pool, actor, "update", "item", item.id, before.as_ref(), after.as_ref()).await;
+    Ok(result)
+}
+
+// Recompute the visibility that applies to an item and everything under it:
+// the most restrictive of its own and its parent's, with the owner of whichever is used.
+pub async fn update_access(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!(r#"WITH RECURSIVE tree(id, access, access_owner) AS (
+            SELECT item.id, MAX(COALESCE(parent.access, 0), COALESCE(item.visibility, 0)),
+                CASE WHEN item.visibility >= COALESCE(parent.access, 0) THEN item.owner ELSE parent.access_owner END
+            FROM item LEFT JOIN item AS parent ON parent.id = item.parent WHERE item.id = ?
+            UNION
+            SELECT child.id, MAX(tree.access, COALESCE(child.visibility, 0)),
+                CASE WHEN child.visibility >= tree.access THEN child.owner ELSE tree.access_owner END
+            FROM item AS child JOIN tree ON child.parent = tree.id)
+        UPDATE item SET access = (SELECT tree.access FROM tree WHERE tree.id = item.id),
+            access_owner = (SELECT tree.access_owner FROM tree WHERE tree.id = item.id)
+        WHERE id IN (SELECT id FROM tree)"#, id).execute(pool).await
+}
+
+// Whether `id` is `ancestor` or somewhere under it
+pub async fn is_inside(pool: &SqlitePool, id: i64, ancestor: i64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(r#"WITH RECURSIVE up(id) AS (
+            SELECT ? UNION
+            SELECT item.parent FROM item JOIN up ON item.id = up.id WHERE item.parent IS NOT NULL)
+        SELECT EXISTS(SELECT 1 FROM up WHERE id = ?) AS "inside!: bool""#, id, ancestor)
+        .fetch_one(pool).await
 }
 
-pub async fn update(pool: &SqlitePool, item: Item) -> Result<SqliteQueryResult, sqlx::Error> {
-    update!(item.id, item.name, item.path, item.parent, pool)
+// `None` makes the item follow its parent again
+pub async fn update_visibility(pool: &SqlitePool, id: i64, visibility: Option<Visibility>, owner: Option<i64>) -> Result<SqliteQueryResult, sqlx::Error> {
+    let level = visibility.map(|v| v.level());
+    sqlx::query!("UPDATE item SET visibility = ?, owner = COALESCE(owner, ?) WHERE id = ?", level, owner, id)
+        .execute(pool).await?;
+    update_access(pool, id).await
 }
 
-pub async fn find_by_type(pool: &SqlitePool, file_type: &str) -> Result<Vec<Item>, sqlx::Error> {
-    find_by_column!(pool, "file_type", file_type)
+pub async fn find_by_type(pool: &SqlitePool, file_type: &str, viewer: &Viewer) -> Result<Vec<Item>, sqlx::Error> {
+    find_by_column!(pool, viewer, "file_type", file_type)
 }
 
 pub async fn find_by_id(pool: &SqlitePool, id: i64) -> Result<Item, sqlx::Error> {
     find_one_by_column!("id", id, pool)
 }
 
+pub async fn find_by_ids(pool: &SqlitePool, ids: Vec<i64>, viewer: &Viewer) -> Result<Vec<Item>, sqlx::Error> {
+    let ids_join = serde_json::to_string(&ids).unwrap_or_default();
+    sqlx::query_as!(Item, "SELECT * FROM item WHERE deleted_at IS NULL AND (access <= ? OR access_owner = ?) AND id IN (SELECT value FROM JSON_EACH(?))",
+        viewer.level, viewer.user, ids_join)
+        .fetch_all(pool)
+        .await
+}
+
 pub async fn find_by_path(pool: &SqlitePool, path: &str) -> Result<Item, sqlx::Error> {
     find_one_by_column!("path", path, pool)
 }
 
+// Items that are a stored file, not in the trash
+pub async fn find_files(pool: &SqlitePool) -> Result<Vec<Item>, sqlx::Error> {
+    sqlx::query_as!(Item, "SELECT * FROM item WHERE file_type != 'folder' AND deleted_at IS NULL ORDER BY id")
+        .fetch_all(pool).await
+}
+
 pub async fn find_by_md5(pool: &SqlitePool, md5: &str) -> Result<Item, sqlx::Error> {
     find_one_by_column!("md5", md5, pool)
 }
 
-pub async fn find_by_parent(pool: &SqlitePool, parent: Option<i64>, limit: Option<i64>, offset: Option<i64>) -> Result<(Vec<Item>, i64), sqlx::Error> {
+pub async fn update_taken_at(pool: &SqlitePool, id: i64, taken_at: Option<&str>) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("UPDATE item SET taken_at=? WHERE id = ?", taken_at, id).execute(pool).await
+}
+
+pub async fn update_original_name(pool: &SqlitePool, id: i64, original_name: Option<&str>) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("UPDATE item SET original_name=? WHERE id = ?", original_name, id).execute(pool).await
+}
+
+// File times are seconds since epoch, stored in local time like EXIF dates
+pub async fn update_taken_at_from_timestamp(pool: &SqlitePool, id: i64, timestamp: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("UPDATE item SET taken_at=DATETIME(?, 'unixepoch', 'localtime') WHERE id = ?", timestamp, id)
+        .execute(pool).await
+}
+
+pub async fn find_by_parent(pool: &SqlitePool, parent: Option<i64>, viewer: &Viewer, order: DateOrder, limit: Option<i64>, offset: Option<i64>) -> Result<(Vec<Item>, i64), sqlx::Error> {
     let items;
     let mut is_series = false;
     let tags = tag::find_by_items(pool, vec![parent.unwrap()]).await?;
@@ -159,69 +365,137 @@ pub async fn find_by_parent(pool: &SqlitePool, parent: Option<i64>, limit: Optio
     }
 
     if limit == None || offset == None {
-        items = find_by_column!(pool, "parent", parent)?;
+        items = find_by_column!(pool, viewer, "parent", parent)?;
     } else {
         if is_series {
-            items = find_by_column!(pool, "parent", parent, limit, offset, "name ASC")?;
+            items = find_by_column!(pool, viewer, "parent", parent, limit, offset, "name ASC")?;
         }
         else {
-            items = find_by_column!(pool, "parent", parent, limit, offset, "created_at DESC")?;
+            items = match order {
+                DateOrder::Created => find_by_column!(pool, viewer, "parent", parent, limit, offset, "created_at DESC")?,
+                DateOrder::Taken => find_by_column!(pool, viewer, "parent", parent, limit, offset, "COALESCE(taken_at, created_at) DESC")?,
+            };
         }
     }
 
-    let count = sqlx::query!("SELECT COUNT(*) as count FROM item WHERE parent = ?", parent).fetch_one(pool).await?;
+    let count = sqlx::query!("SELECT COUNT(*) as count FROM item WHERE deleted_at IS NULL AND (access <= ? OR access_owner = ?) AND parent = ?",
+        viewer.level, viewer.user, parent).fetch_one(pool).await?;
     Ok((items, count.count as i64))
 }
 
-pub async fn find_by_tag(pool: &SqlitePool, tags: Vec<String>, limit: i64, offset: i64) -> Result<(Vec<Item>, i64), sqlx::Error> {
-    let tags = tags.join(",");
-    let items = sqlx::query_as!(Item, r#"SELECT item.id as "id!", item.name as "name!", item.path as "path!",
-                                    item.file_type as "file_type!", item.created_at as "created_at!",
-                                    item.parent as parent, item.md5 as "md5!"
-    FROM item LEFT JOIN item_tag ON item_tag.item = item.id
-              LEFT JOIN tag ON item_tag.tag = tag.id
-    WHERE tag.name IN (?)
-    GROUP BY item.id
-    ORDER BY item.created_at DESC LIMIT ? OFFSET ?"#
-        ,tags ,limit, offset).fetch_all(pool).await?;
-    let count = sqlx::query!(r#"SELECT COUNT(DISTINCT item.id) as count
-    FROM item LEFT JOIN item_tag ON item_tag.item = item.id
-              LEFT JOIN tag ON item_tag.tag = tag.id
-    WHERE tag.name IN (?)"#
-        ,tags).fetch_one(pool).await?;
-
-    Ok((items, count.count.into()))
-}
-
-pub async fn find_not_in_series(pool: &SqlitePool, limit: i64, offset: i64) -> Result<(Vec<Item>, i64), sqlx::Error> {
-    let items = sqlx::query_as!(Item,
-            r#"SELECT item.id as "id!", item.name as "name!", item.path as "path!",
-                      item.file_type as "file_type!", item.created_at as "created_at!",
-                      item.parent as parent, item.md5 as "md5!"
-            FROM item WHERE parent NOT IN (
-                SELECT item.id FROM item LEFT JOIN item_tag ON item_tag.item = item.id
-                LEFT JOIN tag ON item_tag.tag = tag.id
-                WHERE tag.name == "series") OR parent is null
-            ORDER BY created_at DESC LIMIT ? OFFSET ?"#
-        ,limit, offset).fetch_all(pool).await?;
+// WHERE clause for items the viewer can see having any of the tags and matching all metatags, and its values
+fn search_conditions(tags: Vec<String>, metatags: Vec<MetaTag>, viewer: &Viewer) -> (String, Vec<metatag::Value>) {
+    let mut conditions = vec![
+        "item.deleted_at IS NULL".to_string(),
+        "(item.access <= ? OR item.access_owner = ?)".to_string(),
+    ];
+    let mut values = vec![
+        metatag::Value::Integer(Some(viewer.level)),
+        metatag::Value::Integer(viewer.user),
+    ];
+    if !tags.is_empty() {
+        conditions.push(r#"item.id IN (
+            SELECT item_tag.item FROM item_tag LEFT JOIN tag ON item_tag.tag = tag.id
+            WHERE tag.name IN (SELECT value FROM JSON_EACH(?)))"#.to_string());
+        values.push(metatag::Value::Text(serde_json::to_string(&tags).unwrap_or_default()));
+    }
+    for metatag in metatags {
+        conditions.push(metatag.clause);
+        values.extend(metatag.values);
+    }
+    (conditions.join(" AND "), values)
+}
+
+pub async fn find_by_tag(pool: &SqlitePool, tags: Vec<String>, metatags: Vec<MetaTag>, viewer: &Viewer, order: DateOrder, limit: i64, offset: i64) -> Result<(Vec<Item>, i64), sqlx::Error> {
+    let (conditions, values) = search_conditions(tags, metatags, viewer);
+    let from = format!("FROM item LEFT JOIN metadata ON metadata.item = item.id WHERE {}", conditions);
+    let query = format!("SELECT item.* {} ORDER BY {} DESC LIMIT ? OFFSET ?", from, order.column());
+    let items = bind_values!(sqlx::query_as::<_, Item>(&query), &values)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool).await?;
+    let query = format!("SELECT COUNT(*) {}", from);
+    let count: i64 = bind_values!(sqlx::query(&query), &values)
+        .fetch_one(pool).await?
+        .try_get(0)?;
+
+    Ok((items, count))
+}
+
+// Located items in one cell of the map
+#[derive(Serialize)]
+pub struct Cluster {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub count: i64,
+    pub id: i64,
+    pub path: String,
+}
+
+// Group located items into square cells of `cell` degrees, with the first item of each cell as a sample
+pub async fn cluster_by_location(pool: &SqlitePool, tags: Vec<String>, metatags: Vec<MetaTag>, viewer: &Viewer, cell: f64) -> Result<Vec<Cluster>, sqlx::Error> {
+    let (conditions, values) = search_conditions(tags, metatags, viewer);
+    let query = format!("SELECT AVG(metadata.latitude), AVG(metadata.longitude), COUNT(*), MIN(item.id), item.path
+        FROM item LEFT JOIN metadata ON metadata.item = item.id
+        WHERE metadata.latitude IS NOT NULL AND metadata.longitude IS NOT NULL AND {}
+        GROUP BY ROUND(metadata.latitude / ?), ROUND(metadata.longitude / ?)", conditions);
+    let rows = bind_values!(sqlx::query(&query), &values)
+        .bind(cell)
+        .bind(cell)
+        .fetch_all(pool)
+        .await?;
+
+    let mut clusters = Vec::new();
+    for row in rows {
+        clusters.push(Cluster {
+            latitude: row.try_get(0)?,
+            longitude: row.try_get(1)?,
+            count: row.try_get(2)?,
+            id: row.try_get(3)?,
+            path: row.try_get(4)?,
+        });
+    }
+    Ok(clusters)
+}
+
+// Number of matching items per period, where the period is the item date formatted by STRFTIME
+pub async fn count_by_date(pool: &SqlitePool, tags: Vec<String>, metatags: Vec<MetaTag>, viewer: &Viewer, order: DateOrder, format: &str) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    let (conditions, values) = search_conditions(tags, metatags, viewer);
+    let query = format!("SELECT STRFTIME('{}', {}) as period, COUNT(*) as count
+        FROM item LEFT JOIN metadata ON metadata.item = item.id WHERE {}
+        GROUP BY period ORDER BY period DESC", format, order.column(), conditions);
+    let rows = bind_values!(sqlx::query(&query), &values).fetch_all(pool).await?;
+
+    let mut periods = Vec::new();
+    for row in rows {
+        periods.push((row.try_get(0)?, row.try_get(1)?));
+    }
+    Ok(periods)
+}
+
+pub async fn find_not_in_series(pool: &SqlitePool, viewer: &Viewer, order: DateOrder, limit: i64, offset: i64) -> Result<(Vec<Item>, i64), sqlx::Error> {
+    let items = match order {
+        DateOrder::Created => find_not_in_series!(pool, viewer, limit, offset, "created_at DESC")?,
+        DateOrder::Taken => find_not_in_series!(pool, viewer, limit, offset, "COALESCE(taken_at, created_at) DESC")?,
+    };
 
     let count = sqlx::query!(r#"SELECT COUNT(*) as count
-            FROM item WHERE parent NOT IN (
+            FROM item WHERE deleted_at IS NULL AND (access <= ? OR access_owner = ?) AND (parent NOT IN (
                 SELECT item.id FROM item LEFT JOIN item_tag ON item_tag.item = item.id
                 LEFT JOIN tag ON item_tag.tag = tag.id
                 WHERE tag.name == "series"
-            ) OR parent is null "#
+            ) OR parent is null) "#, viewer.level, viewer.user
         ).fetch_one(pool).await?;
 
     Ok((items, count.count as i64))
 }
 
-pub async fn delete_by_id(pool: &SqlitePool, id: i64) {
-    delete_by_column!("id", id, pool);
+pub async fn delete_by_id(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    delete_by_column!("id", id, pool)
 }
 
-pub async fn delete_by_parent(pool: &SqlitePool, parent: Option<i64>) {
-    delete_by_column!("parent", parent, pool);
+pub async fn delete_by_parent(pool: &SqlitePool, parent: Option<i64>) -> Result<SqliteQueryResult, sqlx::Error> {
+    delete_by_column!("parent", parent, pool)
 }
 
 pub async fn delete_local_file(file_path: &str) -> Result<(), std::io::Error> {
@@ -233,27 +507,205 @@ pub async fn delete_local_file(file_path: &str) -> Result<(), std::io::Error> {
     }
 }
 
+async fn find_children(pool: &SqlitePool, id: i64) -> Result<Vec<Item>, sqlx::Error> {
+    sqlx::query_as!(Item, "SELECT * FROM item WHERE parent = ?", id)
+        .fetch_all(pool)
+        .await
+}
+
+// Mark an item and everything under it as deleted and move their paths from `path` to `trash_path`.
+// Children already in the trash keep their own date and path.
+async fn mark_deleted(pool: &SqlitePool, id: i64, path: &str, trash_path: &str) -> Result<SqliteQueryResult, sqlx::Error> {
+    let rest = path.len() as i64 + 1;
+    sqlx::query!(r#"WITH RECURSIVE tree(id) AS (
+            SELECT ? UNION
+            SELECT item.id FROM item JOIN tree ON item.parent = tree.id WHERE item.deleted_at IS NULL)
+        UPDATE item SET deleted_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'), path = ? || SUBSTR(path, ?)
+        WHERE id IN (SELECT id FROM tree)"#, id, trash_path, rest).execute(pool).await
+}
+
+// Undo `mark_deleted` for the items that were deleted together with the item
+async fn unmark_deleted(pool: &SqlitePool, id: i64, deleted_at: &str, trash_path: &str, path: &str) -> Result<SqliteQueryResult, sqlx::Error> {
+    let rest = trash_path.len() as i64 + 1;
+    sqlx::query!(r#"WITH RECURSIVE tree(id) AS (
+            SELECT ? UNION
+            SELECT item.id FROM item JOIN tree ON item.parent = tree.id WHERE item.deleted_at = ?)
+        UPDATE item SET deleted_at = NULL, path = ? || SUBSTR(path, ?)
+        WHERE id IN (SELECT id FROM tree)"#, id, deleted_at, path, rest).execute(pool).await
+}
+
+// Move the file of an item and its thumbnails, both paths relative to the root folder
+fn move_files(root_dir: &str, from: &str, to: &str) -> std::io::Result<()> {
+    let src = format!("{}/{}", root_dir, from);
+    let dest = format!("{}/{}", root_dir, to);
+    if Path::new(&src).exists() {
+        create_dir_all(Path::new(&dest).parent().unwrap())?;
+        rename(&src, &dest)?;
+    }
+
+    // A folder has a thumbnail and a folder of thumbnails of its children
+    for ext in [".jpg", ""] {
+        let src = format!("{}/thumbnail/{}{}", root_dir, from, ext);
+        let dest = format!("{}/thumbnail/{}{}", root_dir, to, ext);
+        if Path::new(&src).exists() {
+            let moved = create_dir_all(Path::new(&dest).parent().unwrap()).and_then(|_| rename(&src, &dest));
+            if let Err(err) = moved {
+                eprintln!("Failed to move thumbnail {}. {}", src, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Move an item to `trash/<id>/` under the root folder. Tags and the rows of its children are kept,
+// so it can be restored as it was.
+pub async fn trash_item(pool: &SqlitePool, id: i64, root_dir: &str, actor: Option<i64>) {
+    let item = match find_by_id(pool, id).await {
+        Ok(item) if item.deleted_at.is_none() => item,
+        _ => return,
+    };
+    let file_name = Path::new(&item.path).file_name().and_then(|n| n.to_str()).unwrap_or("item");
+    let trash_path = format!("trash/{}/{}", id, file_name);
+
+    if let Err(err) = move_files(root_dir, &item.path, &trash_path) {
+        eprintln!("Failed to move item {} to the trash. {}", id, err);
+        return;
+    }
+    if let Err(err) = mark_deleted(pool, id, &item.path, &trash_path).await {
+        eprintln!("Failed to mark item {} as deleted. {:?}", id, err);
+    }
+    if let Err(err) = trash::insert(pool, id, &item.path).await {
+        eprintln!("Failed to record item {} in the trash. {:?}", id, err);
+    }
+    let after = find_by_id(pool, id).await.ok();
+    audit::record(pool, actor, "trash", "item", id, Some(&item), after.as_ref()).await;
+}
+
 #[async_recursion]
-pub async fn delete_item(pool: &SqlitePool, id: i64, root_dir: &str) {
-    // let trash_dir = format!("{}/trash", root_dir);
-    // let trash_dir_path = Path::new(&trash_dir);
-    // if !trash_dir_path.exists() && !trash_dir_path.is_dir() {
-    //     create_dir_all(trash_dir_path);
-    // }
+pub async fn restore_item(pool: &SqlitePool, id: i64, root_dir: &str, actor: Option<i64>) {
+    let item = match find_by_id(pool, id).await {
+        Ok(item) => item,
+        Err(_) => return,
+    };
+    let deleted_at = match &item.deleted_at {
+        Some(deleted_at) => deleted_at.clone(),
+        None => return,
+    };
 
-    item_tag::delete_by_item(pool, id).await;
+    // Folders above come back first, so the file has somewhere to go
+    if let Some(parent) = item.parent {
+        restore_item(pool, parent, root_dir, actor).await;
+    }
+
+    // Items deleted with a folder come back with it
+    let trash = match trash::find_by_item(pool, id).await {
+        Ok(trash) => trash,
+        Err(_) => return,
+    };
+    if Path::new(&format!("{}/{}", root_dir, trash.path)).exists() {
+        eprintln!("Cannot restore item {}, {} already exists.", id, trash.path);
+        return;
+    }
+    if let Err(err) = move_files(root_dir, &item.path, &trash.path) {
+        eprintln!("Failed to restore item {}. {}", id, err);
+        return;
+    }
+    // Folders left in the trash, items without a thumbnail have none there
+    let _ = delete_local_file(&format!("{}/trash/{}", root_dir, id)).await;
+    let _ = delete_local_file(&format!("{}/thumbnail/trash/{}", root_dir, id)).await;
+
+    if let Err(err) = unmark_deleted(pool, id, &deleted_at, &item.path, &trash.path).await {
+        eprintln!("Failed to mark item {} as restored. {:?}", id, err);
+    }
+    if let Err(err) = trash::delete_by_item(pool, id).await {
+        eprintln!("Failed to remove item {} from the trash. {:?}", id, err);
+    }
+    let after = find_by_id(pool, id).await.ok();
+    audit::record(pool, actor, "restore", "item", id, Some(&item), after.as_ref()).await;
+}
+
+// Remove the items in the trash for more than `days` days
+pub async fn purge_expired(pool: &SqlitePool, root_dir: &str, days: i64, actor: Option<i64>) {
+    for trash in trash::find_older_than(pool, days).await.unwrap_or_default() {
+        delete_item(pool, trash.item, root_dir, actor).await;
+    }
+}
+
+pub fn spawn_purge_worker(pool: SqlitePool, root_dir: PathBuf, days: i64) {
+    thread::spawn(move || {
+        task::block_on(async {
+            loop {
+                purge_expired(&pool, root_dir.to_str().unwrap(), days, None).await;
+                task::sleep(Duration::from_secs(3600)).await;
+            }
+        })
+    });
+}
+
+// Delete an item, its files and everything under it for good
+pub async fn delete_item(pool: &SqlitePool, id: i64, root_dir: &str, actor: Option<i64>) {
+    let before = match find_by_id(pool, id).await {
+        Ok(item) => item,
+        Err(_) => return,
+    };
+    delete_tree(pool, id, root_dir).await;
+    audit::record(pool, actor, "delete", "item", id, Some(&before), None).await;
+}
+
+// Remove a file or folder of a deleted item, which may never have been made
+async fn remove_local_file(file_path: &str) {
+    match delete_local_file(file_path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => eprintln!("Failed to delete {}. {:?}", file_path, err),
+        _ => {}
+    }
+}
 
-    let (items, count) = find_by_parent(pool, Some(id), None, None).await.unwrap_or_default();
+#[async_recursion]
+async fn delete_tree(pool: &SqlitePool, id: i64, root_dir: &str) {
+    if let Err(err) = item_tag::delete_by_item(pool, id).await {
+        eprintln!("Failed to delete tags of item {}. {:?}", id, err);
+    }
+
+    let items = find_children(pool, id).await.unwrap_or_default();
     for item in items {
-        delete_item(pool, item.id, root_dir).await;
+        delete_tree(pool, item.id, root_dir).await;
+    }
+    if let Err(err) = delete_by_parent(pool, Some(id)).await {
+        eprintln!("Failed to delete children of item {}. {:?}", id, err);
+    }
+
+    if let Ok(rendition) = transcode::find_by_item(pool, id).await {
+        if let Some(rendition_path) = rendition.path {
+            let derived_path = format!("{}/derived/{}", root_dir, rendition_path);
+            if rendition.format == "hls" {
+                // HLS rendition is a folder of playlists and segments
+                if let Some(derived_dir) = Path::new(&derived_path).parent() {
+                    remove_local_file(derived_dir.to_str().unwrap()).await;
+                }
+            } else {
+                remove_local_file(&derived_path).await;
+            }
+        }
+        if let Err(err) = transcode::delete_by_item(pool, id).await {
+            eprintln!("Failed to delete rendition of item {}. {:?}", id, err);
+        }
+    }
+
+    if trash::find_by_item(pool, id).await.is_ok() {
+        remove_local_file(&format!("{}/trash/{}", root_dir, id)).await;
+        remove_local_file(&format!("{}/thumbnail/trash/{}", root_dir, id)).await;
+        if let Err(err) = trash::delete_by_item(pool, id).await {
+            eprintln!("Failed to delete trash entry of item {}. {:?}", id, err);
+        }
     }
-    delete_by_parent(pool, Some(id)).await;
 
     if let Ok(item) = find_by_id(pool, id).await {
         let file_path = format!("{}/{}", root_dir, item.path);
         let thumbnail_path = format!("{}/thumbnail/{}.jpg", root_dir, item.path);
-        delete_local_file(&file_path).await;
-        delete_local_file(&thumbnail_path).await;
-        delete_by_id(pool, id).await;
+        remove_local_file(&file_path).await;
+        remove_local_file(&thumbnail_path).await;
+        if let Err(err) = delete_by_id(pool, id).await {
+            eprintln!("Failed to delete item {}. {:?}", id, err);
+        }
     }
 }
\ No newline at end of file
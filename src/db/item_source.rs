@@ -0,0 +1,39 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct ItemSource {
+    pub id: i64,
+    pub item: i64,
+    pub url: String,
+    pub created_at: String,
+}
+
+// Where an item came from, like the page or file it was downloaded from
+pub async fn insert(pool: &SqlitePool, item: i64, url: &str) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(r#"INSERT INTO item_source (item, url) VALUES (?, ?)"#, item, url)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_by_item(pool: &SqlitePool, item: i64) -> Result<Vec<ItemSource>, sqlx::Error> {
+    sqlx::query_as!(ItemSource, "SELECT * FROM item_source WHERE item = ? ORDER BY id", item)
+        .fetch_all(pool)
+        .await
+}
+
+// Replace the sources of an item, keeping the date of those that stay
+pub async fn update_item_sources(pool: &SqlitePool, item: i64, urls: &[&str]) -> Result<(), sqlx::Error> {
+    let urls_json = serde_json::to_string(urls).unwrap_or_default();
+    sqlx::query!("DELETE FROM item_source WHERE item = ? AND url NOT IN (SELECT value FROM JSON_EACH(?))",
+        item, urls_json).execute(pool).await?;
+    let existing: Vec<String> = find_by_item(pool, item).await?.into_iter().map(|source| source.url).collect();
+    for url in urls {
+        if !existing.iter().any(|existing| existing == url) {
+            insert(pool, item, url).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct Metadata {
+    pub id: i64,
+    pub item: i64,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration: Option<f64>,
+    pub frame_rate: Option<f64>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub camera: Option<String>,
+    pub captured_at: Option<String>,
+    pub orientation: Option<i64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl Metadata {
+    pub fn empty() -> Metadata {
+        Metadata {
+            id: 0,
+            item: 0,
+            width: None,
+            height: None,
+            duration: None,
+            frame_rate: None,
+            video_codec: None,
+            audio_codec: None,
+            camera: None,
+            captured_at: None,
+            orientation: None,
+            latitude: None,
+            longitude: None,
+        }
+    }
+}
+
+pub async fn insert(pool: &SqlitePool, metadata: &Metadata) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT OR REPLACE INTO metadata (item, width, height, duration, frame_rate, video_codec,
+                                           audio_codec, camera, captured_at, orientation,
+                                           latitude, longitude)
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        metadata.item,
+        metadata.width,
+        metadata.height,
+        metadata.duration,
+        metadata.frame_rate,
+        metadata.video_codec,
+        metadata.audio_codec,
+        metadata.camera,
+        metadata.captured_at,
+        metadata.orientation,
+        metadata.latitude,
+        metadata.longitude
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_by_item(pool: &SqlitePool, item: i64) -> Result<Metadata, sqlx::Error> {
+    sqlx::query_as!(Metadata, "SELECT * FROM metadata WHERE item = ?", item)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn find_by_items(
+    pool: &SqlitePool,
+    item_ids: Vec<i64>,
+) -> Result<Vec<Metadata>, sqlx::Error> {
+    let ids: Vec<String> = item_ids.iter().map(|id| id.to_string()).collect();
+    let ids_join = format!("[{}]", ids.join(","));
+    sqlx::query_as!(
+        Metadata,
+        "SELECT * FROM metadata WHERE item IN (SELECT value FROM JSON_EACH(?))",
+        ids_join
+    )
+    .fetch_all(pool)
+    .await
+}
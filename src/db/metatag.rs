@@ -0,0 +1,215 @@
+// Search terms in the form `key:value`, like `width:>1920` or `duration:<30`,
+// that filter items on their metadata instead of on a tag.
+
+use super::item::DateOrder;
+
+#[derive(Clone)]
+pub enum Value {
+    Real(f64),
+    Text(String),
+    Integer(Option<i64>),
+}
+
+#[derive(Clone)]
+pub struct MetaTag {
+    pub clause: String,
+    pub values: Vec<Value>,
+}
+
+fn numeric_column(key: &str) -> Option<&'static str> {
+    match key {
+        "width" => Some("metadata.width"),
+        "height" => Some("metadata.height"),
+        "duration" => Some("metadata.duration"),
+        "fps" => Some("metadata.frame_rate"),
+        "orientation" => Some("metadata.orientation"),
+        _ => None,
+    }
+}
+
+// Split a comparison like `>=1920` into its SQL operator and operand
+fn split_operator(value: &str) -> (&'static str, &str) {
+    for op in [">=", "<=", ">", "<", "="] {
+        if let Some(operand) = value.strip_prefix(op) {
+            return (op, operand);
+        }
+    }
+    ("=", value)
+}
+
+// Compare the item date, truncated to the precision of `value` (`2020`, `2020-05` or `2020-05-04`)
+pub fn date(order: DateOrder, op: &str, value: &str) -> Option<MetaTag> {
+    let format = match value.len() {
+        4 => "%Y",
+        7 => "%Y-%m",
+        10 => "%Y-%m-%d",
+        _ => return None,
+    };
+    Some(MetaTag {
+        clause: format!("STRFTIME('{}', {}) {} ?", format, order.column(), op),
+        values: vec![Value::Text(value.to_string())],
+    })
+}
+
+// Items dated on today's month and day in an earlier year
+pub fn on_this_day(order: DateOrder) -> MetaTag {
+    MetaTag {
+        clause: format!(
+            "STRFTIME('%m-%d', {0}) = STRFTIME('%m-%d', 'now', 'localtime')
+             AND STRFTIME('%Y', {0}) < STRFTIME('%Y', 'now', 'localtime')",
+            order.column()
+        ),
+        values: vec![],
+    }
+}
+
+// Kilometres in a degree of latitude
+pub const KM_PER_DEGREE: f64 = 111.32;
+
+// Items within `radius` km of a point. Distances use an equirectangular approximation,
+// which is close enough at the scale of a photo location and needs no trigonometry in SQL.
+pub fn near(lat: f64, lon: f64, radius: f64) -> MetaTag {
+    let lat_delta = radius / KM_PER_DEGREE;
+    let lon_scale = lat.to_radians().cos().max(0.01);
+    let lon_delta = lat_delta / lon_scale;
+    MetaTag {
+        clause: "metadata.latitude BETWEEN ? AND ? AND metadata.longitude BETWEEN ? AND ?
+                 AND (metadata.latitude - ?) * (metadata.latitude - ?)
+                     + (metadata.longitude - ?) * (metadata.longitude - ?) * ? <= ?"
+            .to_string(),
+        values: vec![
+            Value::Real(lat - lat_delta),
+            Value::Real(lat + lat_delta),
+            Value::Real(lon - lon_delta),
+            Value::Real(lon + lon_delta),
+            Value::Real(lat),
+            Value::Real(lat),
+            Value::Real(lon),
+            Value::Real(lon),
+            Value::Real(lon_scale * lon_scale),
+            Value::Real(lat_delta * lat_delta),
+        ],
+    }
+}
+
+fn named_color(name: &str) -> Option<(f64, f64, f64)> {
+    match name {
+        "red" => Some((220.0, 40.0, 40.0)),
+        "orange" => Some((240.0, 140.0, 30.0)),
+        "yellow" => Some((240.0, 220.0, 50.0)),
+        "green" => Some((50.0, 160.0, 60.0)),
+        "cyan" => Some((50.0, 200.0, 210.0)),
+        "blue" => Some((40.0, 80.0, 220.0)),
+        "purple" => Some((130.0, 50.0, 180.0)),
+        "pink" => Some((240.0, 130.0, 180.0)),
+        "brown" => Some((130.0, 80.0, 40.0)),
+        "black" => Some((15.0, 15.0, 15.0)),
+        "white" => Some((245.0, 245.0, 245.0)),
+        "gray" | "grey" => Some((128.0, 128.0, 128.0)),
+        _ => None,
+    }
+}
+
+// Items whose palette has a colour within `tolerance` percent of the largest RGB distance,
+// like `color:red`, `color:#3366ff` or `color:#3366ff~20`
+fn color(value: &str) -> Option<MetaTag> {
+    let (name, tolerance) = match value.split_once('~') {
+        Some((name, tolerance)) => (name, Some(tolerance.parse::<f64>().ok()?)),
+        None => (value, None),
+    };
+    let name = name.to_lowercase();
+    let ((red, green, blue), default_tolerance) = match name.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 => {
+            let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            ((channel(0)? as f64, channel(2)? as f64, channel(4)? as f64), 10.0)
+        }
+        Some(_) => return None,
+        None => (named_color(&name)?, 25.0),
+    };
+    let distance = tolerance.unwrap_or(default_tolerance) / 100.0 * (3.0 * 255.0 * 255.0f64).sqrt();
+    Some(MetaTag {
+        clause: "item.id IN (SELECT color.item FROM color WHERE color.weight >= 0.05
+                 AND (color.red - ?) * (color.red - ?) + (color.green - ?) * (color.green - ?)
+                     + (color.blue - ?) * (color.blue - ?) <= ?)"
+            .to_string(),
+        values: vec![
+            Value::Real(red),
+            Value::Real(red),
+            Value::Real(green),
+            Value::Real(green),
+            Value::Real(blue),
+            Value::Real(blue),
+            Value::Real(distance * distance),
+        ],
+    })
+}
+
+pub fn not_folder() -> MetaTag {
+    MetaTag {
+        clause: "item.file_type != 'folder'".to_string(),
+        values: vec![],
+    }
+}
+
+fn parse_one(term: &str, order: DateOrder) -> Option<MetaTag> {
+    let (key, value) = term.split_once(':')?;
+    let key = key.to_lowercase();
+
+    if let Some(column) = numeric_column(&key) {
+        let (op, operand) = split_operator(value);
+        let number: f64 = operand.parse().ok()?;
+        return Some(MetaTag {
+            clause: format!("{} {} ?", column, op),
+            values: vec![Value::Real(number)],
+        });
+    }
+
+    match key.as_str() {
+        "date" => {
+            let (op, operand) = split_operator(value);
+            date(order, op, operand)
+        }
+        "near" => {
+            let parts: Vec<f64> = value
+                .split(',')
+                .map(|v| v.parse().ok())
+                .collect::<Option<Vec<f64>>>()?;
+            match parts[..] {
+                [lat, lon, radius] => Some(near(lat, lon, radius)),
+                [lat, lon] => Some(near(lat, lon, 1.0)),
+                _ => None,
+            }
+        }
+        "color" | "colour" => color(value),
+        "camera" => Some(MetaTag {
+            clause: "metadata.camera LIKE ?".to_string(),
+            values: vec![Value::Text(format!("%{}%", value))],
+        }),
+        // Part of a source URL, like `source:example.com`
+        "source" => Some(MetaTag {
+            clause: "item.id IN (SELECT item_source.item FROM item_source WHERE item_source.url LIKE ?)".to_string(),
+            values: vec![Value::Text(format!("%{}%", value))],
+        }),
+        "codec" => Some(MetaTag {
+            clause: "(metadata.video_codec = ? OR metadata.audio_codec = ?)".to_string(),
+            values: vec![
+                Value::Text(value.to_lowercase()),
+                Value::Text(value.to_lowercase()),
+            ],
+        }),
+        _ => None,
+    }
+}
+
+// Separate plain tags from metatags. Terms with an unknown key are kept as tags.
+pub fn parse(terms: &str, order: DateOrder) -> (Vec<String>, Vec<MetaTag>) {
+    let mut tags = Vec::new();
+    let mut metatags = Vec::new();
+    for term in terms.split_whitespace() {
+        match parse_one(term, order) {
+            Some(metatag) => metatags.push(metatag),
+            None => tags.push(term.to_lowercase()),
+        }
+    }
+    (tags, metatags)
+}
@@ -0,0 +1,51 @@
+// Bring databases created by older versions up to date with mediaboard.sql, which only
+// creates what is missing.
+
+use sqlx::{Executor, SqlitePool};
+
+use crate::db::item;
+
+// Columns added to tables after they were created. Rows are read by position, so they are
+// in the order of mediaboard.sql.
+const COLUMNS: &[(&str, &str, &str)] = &[
+    ("item", "taken_at", "TEXT"),
+    ("item", "deleted_at", "TEXT"),
+    ("item", "visibility", "INTEGER"),
+    ("item", "owner", "INTEGER references user on delete set null"),
+    ("item", "access", "INTEGER default 0 not null"),
+    ("item", "access_owner", "INTEGER"),
+    ("item", "original_name", "TEXT"),
+    ("item", "uploader", "INTEGER references user on delete set null"),
+    ("item", "import_method", "TEXT"),
+    ("batch_file", "archive", "TEXT"),
+];
+
+pub async fn run(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut added_access = false;
+    for (table, column, definition) in COLUMNS {
+        let columns: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+            .bind(table)
+            .fetch_all(pool)
+            .await?;
+        // Missing tables are created whole below
+        if columns.is_empty() || columns.iter().any(|name| name == column) {
+            continue;
+        }
+        println!("Adding column {}.{}", table, column);
+        pool.execute(format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition).as_str())
+            .await?;
+        added_access |= *table == "item" && *column == "access";
+    }
+    pool.execute(include_str!("../../mediaboard.sql")).await?;
+
+    if added_access {
+        // From the top albums, as the access of an item depends on its parent's
+        let roots: Vec<i64> = sqlx::query_scalar("SELECT id FROM item WHERE parent IS NULL")
+            .fetch_all(pool)
+            .await?;
+        for id in roots {
+            item::update_access(pool, id).await?;
+        }
+    }
+    Ok(())
+}
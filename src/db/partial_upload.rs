@@ -0,0 +1,137 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use async_std::task;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+use super::batch_file;
+
+// A file sent in chunks, received so far in `tmp/<token>.part`. The size of that file is how much
+// was received; `updated_at` is when the last chunk came.
+pub struct PartialUpload {
+    pub id: i64,
+    pub token: String,
+    pub user: Option<i64>,
+    pub batch: String,
+    pub name: String,
+    pub folder: String,
+    pub length: i64,
+}
+
+pub async fn insert(
+    pool: &SqlitePool,
+    token: &str,
+    user: Option<i64>,
+    batch: &str,
+    name: &str,
+    folder: &str,
+    length: i64,
+) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT INTO partial_upload (token, user, batch, name, folder, length) VALUES (?, ?, ?, ?, ?, ?)"#,
+        token,
+        user,
+        batch,
+        name,
+        folder,
+        length
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+// Only the user who started an upload can continue it
+pub async fn find_by_token(
+    pool: &SqlitePool,
+    token: &str,
+    user: Option<i64>,
+) -> Result<PartialUpload, sqlx::Error> {
+    sqlx::query_as!(
+        PartialUpload,
+        "SELECT id, token, user, batch, name, folder, length FROM partial_upload WHERE token = ? AND user IS ?",
+        token,
+        user
+    )
+    .fetch_one(pool)
+    .await
+}
+
+// Not continued for more than `hours` hours
+pub async fn find_older_than(pool: &SqlitePool, hours: i64) -> Result<Vec<PartialUpload>, sqlx::Error> {
+    let modifier = format!("-{} hours", hours);
+    sqlx::query_as!(
+        PartialUpload,
+        "SELECT id, token, user, batch, name, folder, length FROM partial_upload
+            WHERE updated_at < STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?)",
+        modifier
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn update_time(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE partial_upload SET updated_at = STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW') WHERE id = ?",
+        id
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn delete_by_id(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM partial_upload WHERE id = ?", id)
+        .execute(pool)
+        .await
+}
+
+// Remove files of uploads left unfinished, and of uploaded files never added, for more than `hours` hours.
+// Returns the tokens of the unfinished uploads removed.
+pub async fn purge_expired(pool: &SqlitePool, root_dir: &Path, hours: i64) -> Vec<String> {
+    let tmp_dir = root_dir.join("tmp");
+    let mut tokens = Vec::new();
+    for upload in find_older_than(pool, hours).await.unwrap_or_default() {
+        let part = tmp_dir.join(format!("{}.part", upload.token));
+        if let Err(err) = std::fs::remove_file(&part) {
+            eprintln!("Failed to remove {:?}. {}", part, err);
+        }
+        if let Err(err) = delete_by_id(pool, upload.id).await {
+            eprintln!("Failed to delete upload {}. {:?}", upload.id, err);
+        }
+        tokens.push(upload.token);
+    }
+
+    for file in batch_file::find_older_than(pool, hours).await.unwrap_or_default() {
+        // Another batch may have the same file
+        if let Some(real_name) = &file.real_name {
+            let others = batch_file::count_by_real_name(pool, real_name).await.unwrap_or_default();
+            if file.status == "new" && others <= 1 {
+                if let Err(err) = std::fs::remove_file(tmp_dir.join(real_name)) {
+                    eprintln!("Failed to remove {}. {}", real_name, err);
+                }
+            }
+        }
+        if let Err(err) = batch_file::delete_by_id(pool, file.id).await {
+            eprintln!("Failed to delete upload {}. {:?}", file.id, err);
+        }
+    }
+    tokens
+}
+
+// `purged` is given the tokens of the unfinished uploads removed
+pub fn spawn_purge_worker<F>(pool: SqlitePool, root_dir: PathBuf, hours: i64, purged: F)
+where
+    F: Fn(&[String]) + Send + 'static,
+{
+    thread::spawn(move || {
+        task::block_on(async {
+            loop {
+                purged(&purge_expired(&pool, &root_dir, hours).await);
+                task::sleep(Duration::from_secs(3600)).await;
+            }
+        })
+    });
+}
@@ -0,0 +1,43 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct Phash {
+    pub id: i64,
+    pub item: i64,
+    pub frame: i64,
+    pub hash: i64,
+}
+
+pub async fn insert(pool: &SqlitePool, item: i64, frame: i64, hash: i64) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT OR REPLACE INTO phash (item, frame, hash) VALUES (?, ?, ?)"#,
+        item,
+        frame,
+        hash
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_by_item(pool: &SqlitePool, item: i64) -> Result<Vec<Phash>, sqlx::Error> {
+    sqlx::query_as!(Phash, "SELECT * FROM phash WHERE item = ? ORDER BY frame", item)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn find_all(pool: &SqlitePool) -> Result<Vec<Phash>, sqlx::Error> {
+    sqlx::query_as!(Phash, "SELECT * FROM phash ORDER BY item, frame")
+        .fetch_all(pool)
+        .await
+}
+
+// Changes whenever hashes are added or removed
+pub async fn version(pool: &SqlitePool) -> Result<(i64, i64), sqlx::Error> {
+    let row = sqlx::query!(r#"SELECT MAX(id) AS "last?: i64", COUNT(*) AS "count!: i64" FROM phash"#)
+        .fetch_one(pool)
+        .await?;
+    Ok((row.last.unwrap_or_default(), row.count))
+}
@@ -0,0 +1,59 @@
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+use super::user::User;
+
+// Sessions are found by the SHA-256 of their token, the token itself is only in the cookie
+pub async fn insert(
+    pool: &SqlitePool,
+    hash: &str,
+    user: i64,
+    days: i64,
+) -> Result<i64, sqlx::Error> {
+    let modifier = format!("+{} days", days);
+    let id = sqlx::query!(
+        r#"INSERT INTO session (token, user, expires_at)
+        VALUES (?, ?, STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?))"#,
+        hash,
+        user,
+        modifier
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+// Owner of a session that has not expired
+pub async fn find_user(pool: &SqlitePool, hash: &str) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT user.* FROM user
+        JOIN session ON session.user = user.id
+        WHERE session.token = ? AND session.expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')"#,
+        hash
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn delete_by_token(
+    pool: &SqlitePool,
+    hash: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM session WHERE token = ?", hash)
+        .execute(pool)
+        .await
+}
+
+pub async fn delete_by_user(pool: &SqlitePool, user: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM session WHERE user = ?", user)
+        .execute(pool)
+        .await
+}
+
+pub async fn delete_expired(pool: &SqlitePool) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM session WHERE expires_at <= STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')")
+        .execute(pool)
+        .await
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+// A link that shows an item, or an album and everything under it, to anyone who has it.
+// `password` is an argon2 hash like user passwords.
+#[derive(Serialize)]
+pub struct Share {
+    pub id: i64,
+    pub item: i64,
+    pub token: String,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    pub download: i64,
+    pub created_by: Option<i64>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+pub async fn insert(
+    pool: &SqlitePool,
+    item: i64,
+    token: &str,
+    password: Option<&str>,
+    download: bool,
+    days: Option<i64>,
+    created_by: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let modifier = days.map(|days| format!("+{} days", days));
+    let id = sqlx::query!(
+        r#"INSERT INTO share (item, token, password, download, created_by, expires_at)
+        VALUES (?, ?, ?, ?, ?, STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?))"#,
+        item,
+        token,
+        password,
+        download,
+        created_by,
+        modifier
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+// Links that have not expired, newest first
+pub async fn find_active(pool: &SqlitePool) -> Result<Vec<Share>, sqlx::Error> {
+    sqlx::query_as!(
+        Share,
+        r#"SELECT * FROM share
+        WHERE expires_at IS NULL OR expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')
+        ORDER BY created_at DESC"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_active_by_token(pool: &SqlitePool, token: &str) -> Result<Share, sqlx::Error> {
+    sqlx::query_as!(
+        Share,
+        r#"SELECT * FROM share WHERE token = ?
+        AND (expires_at IS NULL OR expires_at > STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))"#,
+        token
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn delete_by_id(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM share WHERE id = ?", id)
+        .execute(pool)
+        .await
+}
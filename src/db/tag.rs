@@ -1,4 +1,5 @@
-use super::{item_tag, tag_tag};
+use super::item::Viewer;
+use super::{audit, item_tag, tag_tag};
 
 use std::collections::HashMap;
 
@@ -15,6 +16,13 @@ pub struct Tag {
     pub created_at: String,
 }
 
+// What the audit log keeps of a tag
+#[derive(Serialize)]
+struct TagState {
+    name: String,
+    deps: Vec<String>,
+}
+
 macro_rules! insert {
     ($pool: expr, $name: expr) => {
         sqlx::query!(r#"INSERT INTO tag (name) VALUES (?)"#, $name)
@@ -95,11 +103,29 @@ async fn update_name(
         .await
 }
 
+async fn find_state(pool: &SqlitePool, id: i64) -> Option<TagState> {
+    let tag = find_by_id(pool, id).await.ok()?;
+    let deps = find_depend_tags(pool, id).await.unwrap_or_default();
+    Some(TagState {
+        name: tag.name,
+        deps: deps.into_iter().map(|dep| dep.name).collect(),
+    })
+}
+
+// Sorted names of the tags of an item
+pub async fn find_names_by_item(pool: &SqlitePool, item_id: i64) -> Result<Vec<String>, sqlx::Error> {
+    let tags = find_by_items(pool, vec![item_id]).await?;
+    Ok(tags.into_iter().map(|tag| tag.name).collect())
+}
+
+// Recorded in the audit log when the tags change
 pub async fn update_item_tags(
     pool: &SqlitePool,
     item_id: i64,
     tag_names: Vec<&str>,
+    actor: Option<i64>,
 ) -> Result<(), sqlx::Error> {
+    let before = find_names_by_item(pool, item_id).await?;
     let mut tags = Vec::new();
     for tag_name in tag_names {
         if let Ok(tag) = find_or_create(pool, &tag_name.to_lowercase()).await {
@@ -109,10 +135,16 @@ pub async fn update_item_tags(
     item_tag::delete_by_item(pool, item_id).await?;
     item_tag::insert_many(pool, item_id, tags).await?;
 
+    let after = find_names_by_item(pool, item_id).await?;
+    if before != after {
+        audit::record(pool, actor, "tag", "item", item_id, Some(&before), Some(&after)).await;
+    }
     Ok(())
 }
 
-pub async fn update_tag(pool: &SqlitePool, id: i64, name: &str, deps: Vec<&str>) {
+pub async fn update_tag(pool: &SqlitePool, id: i64, name: &str, deps: Vec<&str>, actor: Option<i64>) {
+    let before = find_state(pool, id).await;
+
     let mut dep_ids = Vec::new();
     for dep in deps {
         if dep == name {
@@ -140,19 +172,30 @@ pub async fn update_tag(pool: &SqlitePool, id: i64, name: &str, deps: Vec<&str>)
             tag_tag::insert(pool, id, dep_id).await;
         }
     }
+
+    let after = find_state(pool, id).await;
+    audit::record(pool, actor, "update", "tag", id, before.as_ref(), after.as_ref()).await;
 }
 
-pub async fn delete_tag(pool: &SqlitePool, id: i64) {
+pub async fn delete_tag(pool: &SqlitePool, id: i64, actor: Option<i64>) {
+    let before = find_state(pool, id).await;
     item_tag::delete_by_tag(pool, id).await;
     tag_tag::delete_relate_tag(pool, id).await;
     delete_by_id(pool, id).await;
+    audit::record(pool, actor, "delete", "tag", id, before.as_ref(), None).await;
 }
 
-pub async fn count_tags(pool: &SqlitePool) -> Result<HashMap<String, i32>, sqlx::Error> {
+// Number of items the viewer can see per tag
+pub async fn count_tags(pool: &SqlitePool, viewer: &Viewer) -> Result<HashMap<String, i32>, sqlx::Error> {
     let mut ret: HashMap<String, i32> = HashMap::new();
     let recs = sqlx::query!(
-        r#"SELECT tag.name as name, COUNT(item_tag.tag) as count
-    FROM tag LEFT JOIN item_tag ON tag.id = item_tag.tag GROUP BY tag.name ORDER BY count DESC"#
+        r#"SELECT tag.name as name, COUNT(item.id) as count
+    FROM tag LEFT JOIN item_tag ON tag.id = item_tag.tag
+    LEFT JOIN item ON item.id = item_tag.item AND item.deleted_at IS NULL
+        AND (item.access <= ? OR item.access_owner = ?)
+    GROUP BY tag.name ORDER BY count DESC"#,
+        viewer.level,
+        viewer.user
     )
     .fetch_all(pool)
     .await?;
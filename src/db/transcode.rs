@@ -0,0 +1,75 @@
+use serde::Serialize;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+#[derive(Serialize)]
+pub struct Transcode {
+    pub id: i64,
+    pub item: i64,
+    pub format: String,
+    pub path: Option<String>,
+    pub status: String,
+    pub created_at: String,
+}
+
+macro_rules! find_by_column {
+    ($pool: expr, $col: literal, $val: expr) => {
+        sqlx::query_as!(Transcode, "SELECT * FROM transcode WHERE " + $col + " = ? ORDER BY id ASC", $val)
+            .fetch_all($pool)
+            .await
+    };
+}
+
+pub async fn insert(pool: &SqlitePool, item: i64, format: &str) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(
+        r#"INSERT OR IGNORE INTO transcode (item, format) VALUES (?, ?)"#,
+        item,
+        format
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_by_item(pool: &SqlitePool, item: i64) -> Result<Transcode, sqlx::Error> {
+    sqlx::query_as!(Transcode, "SELECT * FROM transcode WHERE item = ?", item)
+        .fetch_one(pool)
+        .await
+}
+
+// The rendition a file under `derived/` belongs to: the mp4 file itself, or a playlist or
+// segment in the folder of an HLS rendition
+pub async fn find_by_file(pool: &SqlitePool, path: &str) -> Result<Transcode, sqlx::Error> {
+    sqlx::query_as!(
+        Transcode,
+        r#"SELECT * FROM transcode WHERE path = ? OR (format = 'hls'
+            AND SUBSTR(?, 1, LENGTH(RTRIM(path, REPLACE(path, '/', '')))) = RTRIM(path, REPLACE(path, '/', '')))
+        LIMIT 1"#,
+        path,
+        path
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn find_by_status(pool: &SqlitePool, status: &str) -> Result<Vec<Transcode>, sqlx::Error> {
+    find_by_column!(pool, "status", status)
+}
+
+pub async fn update_status(
+    pool: &SqlitePool,
+    id: i64,
+    status: &str,
+    path: Option<&str>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!(r#"UPDATE transcode SET status=?, path=? WHERE id = ?"#, status, path, id)
+        .execute(pool)
+        .await
+}
+
+pub async fn delete_by_item(pool: &SqlitePool, item: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM transcode WHERE item = ?", item)
+        .execute(pool)
+        .await
+}
@@ -0,0 +1,50 @@
+use serde::Serialize;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+// An item moved to the trash, with its children if it is a folder.
+// `path` is where it was before, relative to the root folder.
+#[derive(Serialize)]
+pub struct Trash {
+    pub id: i64,
+    pub item: i64,
+    pub path: String,
+    pub deleted_at: String,
+}
+
+pub async fn insert(pool: &SqlitePool, item: i64, path: &str) -> Result<i64, sqlx::Error> {
+    let id = sqlx::query!(r#"INSERT INTO trash (item, path) VALUES (?, ?)"#, item, path)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_all(pool: &SqlitePool) -> Result<Vec<Trash>, sqlx::Error> {
+    sqlx::query_as!(Trash, "SELECT * FROM trash ORDER BY deleted_at DESC")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn find_by_item(pool: &SqlitePool, item: i64) -> Result<Trash, sqlx::Error> {
+    sqlx::query_as!(Trash, "SELECT * FROM trash WHERE item = ?", item)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn find_older_than(pool: &SqlitePool, days: i64) -> Result<Vec<Trash>, sqlx::Error> {
+    let modifier = format!("-{} days", days);
+    sqlx::query_as!(
+        Trash,
+        "SELECT * FROM trash WHERE deleted_at < STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW', ?)",
+        modifier
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn delete_by_item(pool: &SqlitePool, item: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM trash WHERE item = ?", item)
+        .execute(pool)
+        .await
+}
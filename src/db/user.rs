@@ -0,0 +1,120 @@
+use serde::Serialize;
+use sqlx::sqlite::SqliteQueryResult;
+use sqlx::SqlitePool;
+
+// Viewers browse, editors upload and change items, admins manage tags, users and the library.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(name: &str) -> Option<Role> {
+        match name {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// `password` is an argon2 hash in PHC string format
+#[derive(Serialize, Clone)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+    pub role: String,
+    pub created_at: String,
+}
+
+impl User {
+    pub fn role(&self) -> Role {
+        Role::parse(&self.role).unwrap_or(Role::Viewer)
+    }
+}
+
+pub async fn insert(
+    pool: &SqlitePool,
+    name: &str,
+    password: &str,
+    role: Role,
+) -> Result<i64, sqlx::Error> {
+    let role = role.as_str();
+    let id = sqlx::query!(
+        r#"INSERT INTO user (name, password, role) VALUES (?, ?, ?)"#,
+        name,
+        password,
+        role
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+pub async fn find_all(pool: &SqlitePool) -> Result<Vec<User>, sqlx::Error> {
+    sqlx::query_as!(User, "SELECT * FROM user ORDER BY name ASC")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn find_by_id(pool: &SqlitePool, id: i64) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(User, "SELECT * FROM user WHERE id = ?", id)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn find_by_name(pool: &SqlitePool, name: &str) -> Result<User, sqlx::Error> {
+    sqlx::query_as!(User, "SELECT * FROM user WHERE name = ?", name)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn count_by_role(pool: &SqlitePool, role: Role) -> Result<i32, sqlx::Error> {
+    let role = role.as_str();
+    let count = sqlx::query!("SELECT COUNT(*) as count FROM user WHERE role = ?", role)
+        .fetch_one(pool)
+        .await?
+        .count;
+    Ok(count)
+}
+
+pub async fn update_role(
+    pool: &SqlitePool,
+    id: i64,
+    role: Role,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    let role = role.as_str();
+    sqlx::query!("UPDATE user SET role = ? WHERE id = ?", role, id)
+        .execute(pool)
+        .await
+}
+
+pub async fn update_password(
+    pool: &SqlitePool,
+    id: i64,
+    password: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("UPDATE user SET password = ? WHERE id = ?", password, id)
+        .execute(pool)
+        .await
+}
+
+pub async fn delete_by_id(pool: &SqlitePool, id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+    sqlx::query!("DELETE FROM user WHERE id = ?", id)
+        .execute(pool)
+        .await
+}
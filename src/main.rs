@@ -1,13 +1,20 @@
 mod db;
+mod media;
 mod route;
+#[cfg(test)]
+mod test_util;
 
 use actix_files::Files;
+use actix_web::middleware::from_fn;
 use actix_web::web::Data;
 use actix_web::{App, HttpServer};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use configparser::ini::Ini;
+use db::item::DateOrder;
+use db::user::Role;
 use dotenv::dotenv;
 use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
 use std::path::Path;
 use tera::Tera;
 
@@ -15,6 +22,54 @@ use tera::Tera;
 struct Cli {
     #[clap(short, long, default_value = "config.ini")]
     config: String,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a user with the admin role, e.g. the first one. Asks for the password.
+    CreateAdmin { name: String },
+    /// Move stored files to where `store_path` places them, with their thumbnails
+    Reorganise {
+        /// Only print what would move
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+// Without a terminal, e.g. when piped in, the password is read from stdin
+fn read_password(prompt: &str) -> String {
+    rpassword::prompt_password(prompt)
+        .or_else(|_| {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).map(|_| line.trim_end().to_string())
+        })
+        .unwrap_or_default()
+}
+
+async fn create_admin(pool: &SqlitePool, name: &str) {
+    let password = read_password("Password: ");
+    if password.is_empty() {
+        eprintln!("Password must not be empty");
+        return;
+    }
+    if password != read_password("Repeat password: ") {
+        eprintln!("Passwords do not match");
+        return;
+    }
+    let hash = match route::auth::hash_password(&password) {
+        Some(hash) => hash,
+        None => {
+            eprintln!("Failed to hash password");
+            return;
+        }
+    };
+    match db::user::insert(pool, name, &hash, Role::Admin).await {
+        Ok(_) => println!("Created admin {}", name),
+        Err(err) => eprintln!("Failed to create user {}. {:?}", name, err),
+    }
 }
 
 #[actix_web::main]
@@ -26,6 +81,7 @@ async fn main() -> std::io::Result<()> {
     let config_root_dir = config.get("default", "root").unwrap();
     let root_dir = Path::new(&config_root_dir).to_path_buf();
     let thumbnail_dir = root_dir.join("thumbnail");
+    let derived_dir = root_dir.join("derived");
     let db_path = config.get("default", "db").unwrap();
     let port = config.get("default", "port").unwrap();
     let ipp: u32 = config
@@ -33,23 +89,140 @@ async fn main() -> std::io::Result<()> {
         .unwrap_or("48".to_owned())
         .parse()
         .unwrap();
+    let transcode = config
+        .get("default", "transcode")
+        .filter(|format| format == "mp4" || format == "hls");
+    let order = match config.get("default", "order").as_deref() {
+        Some("taken_at") => DateOrder::Taken,
+        _ => DateOrder::Created,
+    };
+    let tile_url = config
+        .get("default", "tile_url")
+        .filter(|url| !url.is_empty())
+        .unwrap_or("https://tile.openstreetmap.org/{z}/{x}/{y}.png".to_owned());
+    let similar_threshold: u32 = config
+        .get("default", "similar_threshold")
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(10);
+    let trash_days: Option<i64> = config
+        .get("default", "trash_days")
+        .and_then(|days| days.parse().ok())
+        .filter(|days| *days > 0);
+    let upload_max_size: Option<u64> = config
+        .get("default", "upload_max_mb")
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .filter(|mb| *mb > 0)
+        .map(|mb| mb * 1024 * 1024);
+    let validation = media::validate::Validation {
+        types: config
+            .get("default", "upload_types")
+            .and_then(|types| media::validate::parse_types(&types)),
+        decode: config
+            .getbool("default", "upload_decode_check")
+            .ok()
+            .flatten()
+            .unwrap_or(false),
+    };
+    let upload_expire_hours: i64 = config
+        .get("default", "upload_expire_hours")
+        .and_then(|hours| hours.parse().ok())
+        .filter(|hours| *hours > 0)
+        .unwrap_or(24);
+    let import_dir = config
+        .get("default", "import")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(&dir).to_path_buf());
+    let inbox_dir = config
+        .get("default", "inbox")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(&dir).to_path_buf());
+    let inbox_album = config
+        .get("default", "inbox_album")
+        .unwrap_or("{year}/{month}".to_owned());
+    let store_template = config
+        .get("default", "store_path")
+        .filter(|template| !template.trim().is_empty())
+        .unwrap_or(media::store::DEFAULT_TEMPLATE.to_owned());
+    if !media::store::is_valid(&store_template) {
+        eprintln!("Invalid store_path {}", store_template);
+        return Ok(());
+    }
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
         .connect(&db_path)
         .await
         .unwrap();
+    if let Err(err) = db::migrate::run(&pool).await {
+        eprintln!("Failed to update the database. {:?}", err);
+        return Ok(());
+    }
+
+    match &args.command {
+        Some(Command::CreateAdmin { name }) => {
+            create_admin(&pool, name).await;
+            return Ok(());
+        }
+        Some(Command::Reorganise { dry_run }) => {
+            media::store::reorganise(&pool, &root_dir, &store_template, *dry_run).await;
+            return Ok(());
+        }
+        None => {}
+    }
+    if db::user::count_by_role(&pool, Role::Admin).await.unwrap_or_default() == 0 {
+        println!("There is no admin yet. Create one with `mediaboard create-admin <name>`.");
+    }
+
+    if let Some(days) = trash_days {
+        db::item::spawn_purge_worker(pool.clone(), root_dir.clone(), days);
+    }
+
+    // Shared by the workers, as chunks of an upload may go to any of them
+    let hashers = Data::new(route::tus::Hashers::default());
+    let purged_hashers = hashers.clone();
+    db::partial_upload::spawn_purge_worker(pool.clone(), root_dir.clone(), upload_expire_hours, move |tokens| {
+        purged_hashers.forget(tokens)
+    });
+
+    // Loaded on the first page that needs it
+    let phashes = Data::new(media::phash::Cache::default());
+
+    if transcode.is_some() {
+        media::transcode::spawn_worker(pool.clone(), root_dir.clone(), derived_dir.clone());
+    }
+
+    // New tokens every start, open pages need a reload after a restart
+    let csrf_secret = route::csrf::new_token();
+    let state = route::AppState::new(
+        pool.clone(),
+        ipp as i64,
+        root_dir.clone(),
+        thumbnail_dir.clone(),
+        derived_dir.clone(),
+        transcode.clone(),
+        order,
+        tile_url.clone(),
+        similar_threshold,
+        upload_max_size,
+        validation,
+        import_dir.clone(),
+        store_template,
+        csrf_secret,
+    );
+
+    if let Some(inbox_dir) = inbox_dir {
+        route::inbox::spawn_worker(state.clone(), inbox_dir, inbox_album);
+    }
 
     HttpServer::new(move || {
         let tera = Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/res/html/**/*")).unwrap();
 
         App::new()
             .app_data(Data::new(tera))
-            .app_data(Data::new(route::AppState::new(
-                pool.clone(),
-                ipp as i64,
-                root_dir.clone(),
-                thumbnail_dir.clone(),
-            )))
+            .app_data(Data::new(state.clone()))
+            .app_data(hashers.clone())
+            .app_data(phashes.clone())
+            .wrap(from_fn(route::csrf::check))
+            .wrap(from_fn(route::auth::check))
             .service(route::index::index)
             .service(route::admin::admin)
             .service(route::admin::manage_tags)
@@ -57,14 +230,54 @@ async fn main() -> std::io::Result<()> {
             .service(route::admin::tag_update)
             .service(route::admin::tag_delete)
             .service(route::admin::reload)
+            .service(route::admin::similar)
+            .service(route::trash::list)
+            .service(route::trash::restore)
+            .service(route::trash::purge)
+            .service(route::trash::empty)
+            .service(route::share::create)
+            .service(route::share::view)
+            .service(route::share::unlock)
+            .service(route::share::file)
+            .service(route::share::list)
+            .service(route::share::revoke)
+            .service(route::audit::log)
+            .service(route::user::list)
+            .service(route::user::create)
+            .service(route::user::update_role)
+            .service(route::user::update_password)
+            .service(route::user::delete)
+            .service(route::auth::login_page)
+            .service(route::auth::login)
+            .service(route::auth::logout)
+            .service(route::auth::account)
+            .service(route::auth::create_token)
+            .service(route::auth::revoke_token)
             .service(route::post::item_update)
             .service(route::post::delete)
+            .service(route::history::revert)
             .service(route::upload::upload)
             .service(route::upload::upload_item)
             .service(route::upload::post_upload)
+            .service(route::upload::batch)
+            .service(route::upload::post_batch)
+            .service(route::upload::upload_direct)
+            .service(route::upload::import)
+            .service(route::upload::post_import)
+            .service(route::fetch::upload_url)
+            .service(route::tus::options)
+            .service(route::tus::create)
+            .service(route::tus::progress)
+            .service(route::tus::append)
+            .service(route::tus::terminate)
             .service(route::album::get_new)
             .service(route::album::post_new)
-            .service(Files::new("/img", root_dir.clone()))
+            .service(route::timeline::timeline)
+            .service(route::map::map)
+            .service(route::map::clusters)
+            .service(route::img::by_id)
+            .service(route::img::thumbnail_by_id)
+            .service(route::img::by_path)
             .service(Files::new(
                 "/css",
                 concat!(env!("CARGO_MANIFEST_DIR"), "/res/css"),
@@ -0,0 +1,63 @@
+use sqlx::SqlitePool;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::db::item::{self, Item};
+use crate::db::metadata;
+
+pub mod archive;
+pub mod palette;
+pub mod phash;
+pub mod probe;
+pub mod store;
+pub mod transcode;
+pub mod validate;
+
+// Collect what can be learned from a stored file. Steps that already ran for the item are skipped,
+// so this is safe to call on every reload.
+pub async fn index(
+    pool: &SqlitePool,
+    item: &Item,
+    file_path: &str,
+    thumbnail_dir: &Path,
+    transcode_format: Option<&str>,
+) {
+    if metadata::find_by_item(pool, item.id).await.is_err() {
+        if let Some(mut meta) = probe::metadata(file_path, &item.file_type) {
+            meta.item = item.id;
+            if let Err(err) = metadata::insert(pool, &meta).await {
+                eprintln!("Failed to save metadata of item {}. {:?}", item.id, err);
+            }
+        }
+    }
+
+    // Capture date comes from EXIF or the video container, falling back to the file time
+    if item.taken_at.is_none() {
+        let captured_at = metadata::find_by_item(pool, item.id)
+            .await
+            .ok()
+            .and_then(|m| m.captured_at);
+        if let Some(captured_at) = captured_at {
+            let _ = item::update_taken_at(pool, item.id, Some(&captured_at)).await;
+        } else if let Ok(modified) = fs::metadata(file_path).and_then(|m| m.modified()) {
+            if let Ok(timestamp) = modified.duration_since(UNIX_EPOCH) {
+                let _ = item::update_taken_at_from_timestamp(
+                    pool,
+                    item.id,
+                    timestamp.as_secs() as i64,
+                )
+                .await;
+            }
+        }
+    }
+
+    phash::index(pool, item, file_path).await;
+
+    let thumbnail_path = thumbnail_dir.join(format!("{}.jpg", item.path));
+    palette::index(pool, item, &thumbnail_path).await;
+
+    if let Some(format) = transcode_format {
+        transcode::enqueue(pool, item, file_path, format).await;
+    }
+}
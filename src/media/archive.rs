@@ -0,0 +1,336 @@
+// Archives are extracted into a folder of `tmp/` and their files then added like uploaded ones.
+
+use std::fs::{create_dir_all, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::Command;
+
+use flate2::read::GzDecoder;
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+pub fn is_archive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    [".zip", ".cbz", ".tar", ".tar.gz", ".tgz", ".rar", ".cbr"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+// Comic book archives, whose files are the pages of one comic
+pub fn is_comic(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with(".cbz") || name.ends_with(".cbr")
+}
+
+// Name of the album an archive is extracted to, e.g. `trip` for `trip.tar.gz`
+pub fn album_name(name: &str) -> &str {
+    let lowercase = name.to_lowercase();
+    let ext_len = if lowercase.ends_with(".tar.gz") {
+        7
+    } else {
+        Path::new(name).extension().map_or(0, |ext| ext.len() + 1)
+    };
+    name.get(..name.len() - ext_len).filter(|stem| !stem.is_empty()).unwrap_or(name)
+}
+
+// Caps on what an archive extracts to, against archives that unpack to far more than their size
+pub struct Limits {
+    // Files larger are left out
+    pub max_size: Option<u64>,
+    pub max_total: u64,
+    pub max_files: usize,
+}
+
+impl Limits {
+    pub fn new(max_size: Option<u64>) -> Self {
+        Limits {
+            max_size,
+            max_total: 16 * 1024 * 1024 * 1024,
+            max_files: 10_000,
+        }
+    }
+
+    fn total_error(&self) -> Error {
+        Error::Limit(format!("Larger than {} MB once extracted", self.max_total / 1024 / 1024))
+    }
+
+    fn files_error(&self) -> Error {
+        Error::Limit(format!("More than {} files", self.max_files))
+    }
+}
+
+pub enum Error {
+    // Over one of the limits, for the user
+    Limit(String),
+    Failed(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Failed(err.to_string())
+    }
+}
+
+// Extract `archive`, named `name`, into `dest`. Files larger than `max_size` are left out and their paths
+// returned. Entries that would end up outside `dest` are never written. An archive over the limits
+// is not extracted, or not completely, and `dest` is left to the caller to remove.
+// RAR archives need the `unrar` command.
+pub fn extract(archive: &Path, name: &str, dest: &Path, limits: &Limits) -> Result<Vec<String>, Error> {
+    create_dir_all(dest)?;
+    let lowercase = name.to_lowercase();
+    if lowercase.ends_with(".zip") || lowercase.ends_with(".cbz") {
+        extract_zip(archive, dest, limits)
+    } else if lowercase.ends_with(".rar") || lowercase.ends_with(".cbr") {
+        extract_rar(archive, dest, limits)
+    } else {
+        let file = File::open(archive)?;
+        if lowercase.ends_with(".tar") {
+            extract_tar(file, dest, limits)
+        } else {
+            extract_tar(GzDecoder::new(file), dest, limits)
+        }
+    }
+}
+
+fn extract_zip(archive: &Path, dest: &Path, limits: &Limits) -> Result<Vec<String>, Error> {
+    let file = File::open(archive)?;
+    let mut zip = ZipArchive::new(file).map_err(|err| Error::Failed(err.to_string()))?;
+    if zip.len() > limits.max_files {
+        return Err(limits.files_error());
+    }
+    let mut skipped = Vec::new();
+    let mut total = 0;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|err| Error::Failed(err.to_string()))?;
+        let path = match entry.enclosed_name() {
+            Some(path) if !entry.is_dir() => path.to_owned(),
+            _ => continue,
+        };
+        let out_path = dest.join(&path);
+        if let Some(parent) = out_path.parent() {
+            create_dir_all(parent)?;
+        }
+        // The size in the archive is not trusted
+        let limit = limits.max_size.unwrap_or(u64::MAX);
+        let remaining = limits.max_total - total;
+        let mut out = File::create(&out_path)?;
+        let written = io::copy(&mut (&mut entry).take(limit.min(remaining).saturating_add(1)), &mut out)?;
+        if written > limit {
+            drop(out);
+            let _ = std::fs::remove_file(&out_path);
+            skipped.push(path.to_string_lossy().to_string());
+        } else if written > remaining {
+            return Err(limits.total_error());
+        } else {
+            total += written;
+        }
+    }
+    Ok(skipped)
+}
+
+fn extract_tar<R: Read>(reader: R, dest: &Path, limits: &Limits) -> Result<Vec<String>, Error> {
+    let mut tar = tar::Archive::new(reader);
+    let mut skipped = Vec::new();
+    let (mut files, mut total) = (0, 0);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        files += 1;
+        if files > limits.max_files {
+            return Err(limits.files_error());
+        }
+        // Exactly the size in the header is read
+        let size = entry.size();
+        if limits.max_size.is_some_and(|max_size| size > max_size) {
+            let path = entry.path()?;
+            skipped.push(path.to_string_lossy().to_string());
+            continue;
+        }
+        total += size;
+        if total > limits.max_total {
+            return Err(limits.total_error());
+        }
+        entry.unpack_in(dest)?;
+    }
+    Ok(skipped)
+}
+
+// Sizes of the files of a RAR archive, from its headers
+fn list_rar(archive: &Path) -> Result<Vec<u64>, Error> {
+    let output = Command::new("unrar")
+        .args(["lt", "-idq"])
+        .arg(archive)
+        .output()
+        .map_err(|err| Error::Failed(format!("unrar: {}", err)))?;
+    if !output.status.success() {
+        return Err(Error::Failed(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    let mut sizes = Vec::new();
+    let mut is_file = false;
+    for line in String::from_utf8_lossy(&output.stdout).lines().map(str::trim) {
+        if let Some(kind) = line.strip_prefix("Type: ") {
+            is_file = kind == "File";
+        } else if let Some(size) = line.strip_prefix("Size: ") {
+            if is_file {
+                sizes.push(size.trim().parse().unwrap_or(u64::MAX));
+            }
+        }
+    }
+    Ok(sizes)
+}
+
+// `unrar` cannot be stopped midway, so the listing is checked before and the files after
+fn extract_rar(archive: &Path, dest: &Path, limits: &Limits) -> Result<Vec<String>, Error> {
+    let sizes = list_rar(archive)?;
+    if sizes.len() > limits.max_files {
+        return Err(limits.files_error());
+    }
+    let kept = sizes
+        .iter()
+        .filter(|size| **size <= limits.max_size.unwrap_or(u64::MAX));
+    if kept.fold(0u64, |total, size| total.saturating_add(*size)) > limits.max_total {
+        return Err(limits.total_error());
+    }
+
+    let output = Command::new("unrar")
+        .args(["x", "-o+", "-idq"])
+        .arg(archive)
+        .arg(dest.join(""))
+        .output()
+        .map_err(|err| Error::Failed(format!("unrar: {}", err)))?;
+    if !output.status.success() {
+        return Err(Error::Failed(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    let mut skipped = Vec::new();
+    let (mut files, mut total) = (0, 0);
+    let entries = WalkDir::new(dest).into_iter().filter_map(|entry| entry.ok());
+    for entry in entries.filter(|entry| entry.file_type().is_file()) {
+        files += 1;
+        if files > limits.max_files {
+            return Err(limits.files_error());
+        }
+        let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+        if limits.max_size.is_some_and(|max_size| size > max_size) {
+            let path = entry.path().strip_prefix(dest).unwrap_or(entry.path());
+            skipped.push(path.to_string_lossy().to_string());
+            std::fs::remove_file(entry.path())?;
+            continue;
+        }
+        total += size;
+        if total > limits.max_total {
+            return Err(limits.total_error());
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn make_zip(dir: &Path, files: &[(&str, usize)]) -> PathBuf {
+        let path = dir.join("test.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for (name, size) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(&vec![b'a'; *size]).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    fn make_tar(dir: &Path, files: &[(&str, usize)]) -> PathBuf {
+        let path = dir.join("test.tar");
+        let mut tar = tar::Builder::new(File::create(&path).unwrap());
+        for (name, size) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(*size as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, &vec![b'a'; *size][..]).unwrap();
+        }
+        tar.finish().unwrap();
+        path
+    }
+
+    type Make = fn(&Path, &[(&str, usize)]) -> PathBuf;
+
+    fn formats() -> [(&'static str, Make); 2] {
+        [("test.zip", make_zip), ("test.tar", make_tar)]
+    }
+
+    fn limits(max_size: Option<u64>, max_total: u64, max_files: usize) -> Limits {
+        Limits {
+            max_size,
+            max_total,
+            max_files,
+        }
+    }
+
+    #[test]
+    fn skips_large_files() {
+        for (name, make) in formats() {
+            let dir = temp_dir(&format!("skip-{}", name));
+            let archive = make(&dir, &[("a.jpg", 10), ("b.jpg", 100)]);
+            let dest = dir.join("out");
+            let skipped = extract(&archive, name, &dest, &limits(Some(50), 1000, 10));
+            assert!(matches!(skipped.as_deref(), Ok([b]) if b == "b.jpg"), "{}", name);
+            assert!(dest.join("a.jpg").exists());
+            assert!(!dest.join("b.jpg").exists());
+            let _ = remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn caps_total_size() {
+        for (name, make) in formats() {
+            let dir = temp_dir(&format!("total-{}", name));
+            let archive = make(&dir, &[("a.jpg", 60), ("b.jpg", 60)]);
+            let extracted = extract(&archive, name, &dir.join("out"), &limits(None, 100, 10));
+            assert!(matches!(extracted, Err(Error::Limit(_))), "{}", name);
+            let _ = remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn caps_file_count() {
+        for (name, make) in formats() {
+            let dir = temp_dir(&format!("count-{}", name));
+            let archive = make(&dir, &[("a.jpg", 1), ("b.jpg", 1), ("c.jpg", 1)]);
+            let extracted = extract(&archive, name, &dir.join("out"), &limits(None, 100, 2));
+            assert!(matches!(extracted, Err(Error::Limit(_))), "{}", name);
+            let _ = remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn keeps_entries_inside() {
+        let dir = temp_dir("inside");
+        let archive = make_zip(&dir, &[("../evil.jpg", 1), ("/abs.jpg", 1), ("ok/a.jpg", 1)]);
+        let dest = dir.join("out");
+        assert!(extract(&archive, "test.zip", &dest, &limits(None, 100, 10)).is_ok());
+        assert!(!dir.join("evil.jpg").exists());
+        assert!(dest.join("ok/a.jpg").exists());
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn names_albums() {
+        assert_eq!(album_name("trip.zip"), "trip");
+        assert_eq!(album_name("trip.TAR.GZ"), "trip");
+        assert_eq!(album_name("vol.1.cbz"), "vol.1");
+        assert_eq!(album_name("été.tar"), "été");
+        assert_eq!(album_name("noext"), "noext");
+        // Nothing left without the extension
+        assert_eq!(album_name(".tar.gz"), ".tar.gz");
+        assert_eq!(album_name(".zip"), ".zip");
+    }
+}
@@ -0,0 +1,73 @@
+use sqlx::SqlitePool;
+use std::path::Path;
+use std::process::Command;
+
+use crate::db::color;
+use crate::db::item::Item;
+
+// Number of colours kept per item
+const PALETTE_SIZE: usize = 5;
+
+fn parse_hex(hex: &str) -> Option<(i64, i64, i64)> {
+    let hex = hex.get(..6)?;
+    let channel = |range| i64::from_str_radix(hex.get(range)?, 16).ok();
+    Some((channel(0..2)?, channel(2..4)?, channel(4..6)?))
+}
+
+// Dominant colours of a thumbnail with the share of pixels each covers, most common first.
+// Lines of the histogram look like `   1234: (255,0,0) #FF0000 red`.
+fn palette(thumbnail_path: &Path) -> Vec<((i64, i64, i64), f64)> {
+    let output = Command::new("convert")
+        .args([
+            "-quiet",
+            thumbnail_path.to_str().unwrap_or_default(),
+            "-resize",
+            "64x64",
+            "+dither",
+            "-colors",
+            &PALETTE_SIZE.to_string(),
+            "-depth",
+            "8",
+            "-format",
+            "%c",
+            "histogram:info:-",
+        ])
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        _ => return vec![],
+    };
+
+    let mut colors: Vec<((i64, i64, i64), f64)> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (count, rest) = line.split_once(':')?;
+            let count: f64 = count.trim().parse().ok()?;
+            let (_, hex) = rest.split_once('#')?;
+            Some((parse_hex(hex)?, count))
+        })
+        .collect();
+    let total: f64 = colors.iter().map(|(_, count)| count).sum();
+    if total == 0.0 {
+        return vec![];
+    }
+    for (_, count) in colors.iter_mut() {
+        *count /= total;
+    }
+    colors.sort_by(|a, b| b.1.total_cmp(&a.1));
+    colors
+}
+
+pub async fn index(pool: &SqlitePool, item: &Item, thumbnail_path: &Path) {
+    if item.file_type != "image" && !item.file_type.starts_with("video") {
+        return;
+    }
+    if !color::find_by_item(pool, item.id).await.unwrap_or_default().is_empty() {
+        return;
+    }
+    for (rgb, weight) in palette(thumbnail_path) {
+        if let Err(err) = color::insert(pool, item.id, rgb, weight).await {
+            eprintln!("Failed to save colour of item {}. {:?}", item.id, err);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// Difference hash: the file is scaled down to 9x8 grey pixels and each bit records whether a pixel
+// is darker than its right neighbour. Resized or re-encoded copies keep nearly the same bits.
+
+use async_std::task;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use crate::db::item::Item;
+use crate::db::metadata;
+use crate::db::phash::{self, Phash};
+
+const WIDTH: usize = 9;
+const HEIGHT: usize = 8;
+
+// Number of keyframes hashed per video
+const VIDEO_FRAMES: usize = 8;
+
+// Flat images hash to (almost) no bits set, or all of them, and would match each other
+// whatever they show. Hashes with fewer set or unset bits than this are not compared.
+const MIN_BITS: u32 = 8;
+
+fn dhash(pixels: &[u8]) -> i64 {
+    let mut hash: u64 = 0;
+    for row in 0..HEIGHT {
+        for col in 0..WIDTH - 1 {
+            hash <<= 1;
+            if pixels[row * WIDTH + col] < pixels[row * WIDTH + col + 1] {
+                hash |= 1;
+            }
+        }
+    }
+    hash as i64
+}
+
+fn image_hashes(file_path: &str) -> Vec<i64> {
+    let output = Command::new("convert")
+        .args([
+            "-quiet",
+            &format!("{}[0]", file_path),
+            "-colorspace",
+            "Gray",
+            "-resize",
+            &format!("{}x{}!", WIDTH, HEIGHT),
+            "-depth",
+            "8",
+            "gray:-",
+        ])
+        .output();
+    match output {
+        Ok(output) if output.stdout.len() == WIDTH * HEIGHT => vec![dhash(&output.stdout)],
+        _ => vec![],
+    }
+}
+
+// Keyframes spread over the video, so a trimmed copy still shares most of its hashes
+fn video_hashes(file_path: &str, duration: Option<f64>) -> Vec<i64> {
+    let interval = duration
+        .map(|d| (d / VIDEO_FRAMES as f64).max(1.0))
+        .unwrap_or(10.0);
+    let output = Command::new("ffmpeg")
+        .args([
+            "-loglevel",
+            "quiet",
+            "-skip_frame",
+            "nokey",
+            "-i",
+            file_path,
+            "-vf",
+            &format!(
+                "fps=1/{:.3},scale={}:{}:flags=area,format=gray",
+                interval, WIDTH, HEIGHT
+            ),
+            "-frames:v",
+            &VIDEO_FRAMES.to_string(),
+            "-f",
+            "rawvideo",
+            "-",
+        ])
+        .output();
+    match output {
+        Ok(output) => output
+            .stdout
+            .chunks_exact(WIDTH * HEIGHT)
+            .map(dhash)
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+fn hashes(file_path: &str, file_type: &str, duration: Option<f64>) -> Vec<i64> {
+    if file_type == "image" {
+        image_hashes(file_path)
+    } else if file_type.starts_with("video") {
+        video_hashes(file_path, duration)
+    } else {
+        vec![]
+    }
+}
+
+pub async fn index(pool: &SqlitePool, item: &Item, file_path: &str) {
+    if !phash::find_by_item(pool, item.id).await.unwrap_or_default().is_empty() {
+        return;
+    }
+    let duration = metadata::find_by_item(pool, item.id)
+        .await
+        .ok()
+        .and_then(|m| m.duration);
+    for (frame, hash) in hashes(file_path, &item.file_type, duration).into_iter().enumerate() {
+        if let Err(err) = phash::insert(pool, item.id, frame as i64, hash).await {
+            eprintln!("Failed to save perceptual hash of item {}. {:?}", item.id, err);
+        }
+    }
+}
+
+fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn is_flat(hash: i64) -> bool {
+    let bits = hash.count_ones();
+    !(MIN_BITS..=64 - MIN_BITS).contains(&bits)
+}
+
+// Hashes within `threshold` bits of each other are equal in at least one of `threshold + 1`
+// bands of their bits
+fn bands(hash: i64, threshold: u32) -> impl Iterator<Item = (u32, u64)> {
+    let count = threshold.saturating_add(1).min(64);
+    (0..count).map(move |band| {
+        let from = band * 64 / count;
+        let width = (band + 1) * 64 / count - from;
+        let mask = if width == 64 { u64::MAX } else { (1 << width) - 1 };
+        (band, (hash as u64 >> from) & mask)
+    })
+}
+
+// Other items with a hash within `threshold` bits of one of the item's hashes, closest first
+pub fn similar_to(hashes: &[Phash], item: i64, threshold: u32) -> Vec<(i64, u32)> {
+    let own: Vec<i64> = hashes
+        .iter()
+        .filter(|h| h.item == item && !is_flat(h.hash))
+        .map(|h| h.hash)
+        .collect();
+    let mut best: HashMap<i64, u32> = HashMap::new();
+    for other in hashes.iter().filter(|h| h.item != item && !is_flat(h.hash)) {
+        for hash in &own {
+            let d = distance(*hash, other.hash);
+            if d <= threshold {
+                let entry = best.entry(other.item).or_insert(d);
+                *entry = (*entry).min(d);
+            }
+        }
+    }
+    let mut similar: Vec<(i64, u32)> = best.into_iter().collect();
+    similar.sort_by_key(|(item, d)| (*d, *item));
+    similar
+}
+
+fn find_root(parents: &mut HashMap<i64, i64>, item: i64) -> i64 {
+    let parent = *parents.get(&item).unwrap_or(&item);
+    if parent == item {
+        return item;
+    }
+    let root = find_root(parents, parent);
+    parents.insert(item, root);
+    root
+}
+
+// Items linked by any pair of hashes within `threshold` bits, largest groups first. Only
+// hashes sharing a band are compared.
+pub fn groups(hashes: &[Phash], threshold: u32) -> Vec<Vec<i64>> {
+    let hashes: Vec<&Phash> = hashes.iter().filter(|h| !is_flat(h.hash)).collect();
+    let mut buckets: HashMap<(u32, u64), Vec<&Phash>> = HashMap::new();
+    for hash in &hashes {
+        for band in bands(hash.hash, threshold) {
+            buckets.entry(band).or_default().push(hash);
+        }
+    }
+
+    let mut parents: HashMap<i64, i64> = HashMap::new();
+    for bucket in buckets.values() {
+        for (i, a) in bucket.iter().enumerate() {
+            for b in &bucket[i + 1..] {
+                if a.item != b.item && distance(a.hash, b.hash) <= threshold {
+                    parents.entry(a.item).or_insert(a.item);
+                    parents.entry(b.item).or_insert(b.item);
+                    let root_a = find_root(&mut parents, a.item);
+                    let root_b = find_root(&mut parents, b.item);
+                    if root_a != root_b {
+                        parents.insert(root_a.max(root_b), root_a.min(root_b));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<i64, Vec<i64>> = HashMap::new();
+    let items: Vec<i64> = parents.keys().cloned().collect();
+    for item in items {
+        let root = find_root(&mut parents, item);
+        groups.entry(root).or_default().push(item);
+    }
+    let mut groups: Vec<Vec<i64>> = groups
+        .into_values()
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+    groups
+}
+
+// Hashes of all items and the groups found in them, kept until hashes are added or removed
+#[derive(Default)]
+pub struct Cache {
+    cached: Mutex<Cached>,
+}
+
+#[derive(Default)]
+struct Cached {
+    version: Option<(i64, i64)>,
+    hashes: Arc<Vec<Phash>>,
+    // By threshold
+    groups: HashMap<u32, Arc<Vec<Vec<i64>>>>,
+}
+
+impl Cache {
+    pub async fn hashes(&self, pool: &SqlitePool) -> Arc<Vec<Phash>> {
+        let version = phash::version(pool).await.ok();
+        {
+            let cached = self.cached.lock().unwrap();
+            if version.is_some() && cached.version == version {
+                return cached.hashes.clone();
+            }
+        }
+        let hashes = Arc::new(phash::find_all(pool).await.unwrap_or_default());
+        if version.is_some() {
+            *self.cached.lock().unwrap() = Cached {
+                version,
+                hashes: hashes.clone(),
+                groups: HashMap::new(),
+            };
+        }
+        hashes
+    }
+
+    pub async fn groups(&self, pool: &SqlitePool, threshold: u32) -> Arc<Vec<Vec<i64>>> {
+        let hashes = self.hashes(pool).await;
+        {
+            let cached = self.cached.lock().unwrap();
+            if Arc::ptr_eq(&cached.hashes, &hashes) {
+                if let Some(groups) = cached.groups.get(&threshold) {
+                    return groups.clone();
+                }
+            }
+        }
+        let all = hashes.clone();
+        let found = Arc::new(task::spawn_blocking(move || groups(&all, threshold)).await);
+        let mut cached = self.cached.lock().unwrap();
+        if Arc::ptr_eq(&cached.hashes, &hashes) {
+            cached.groups.insert(threshold, found.clone());
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(item: i64, hash: i64) -> Phash {
+        Phash {
+            id: 0,
+            item,
+            frame: 0,
+            hash,
+        }
+    }
+
+    #[test]
+    fn groups_close_hashes() {
+        let base = 0x0f0f_0f0f_0f0f_0f0f;
+        // Differences spread over every band
+        let spread = base ^ 0x0101_0101_0101_0101;
+        let hashes = [hash(1, base), hash(2, spread), hash(3, !base), hash(4, base ^ 0x3)];
+        assert_eq!(groups(&hashes, 8), vec![vec![1, 2, 4]]);
+        assert_eq!(groups(&hashes, 2), vec![vec![1, 4]]);
+        assert_eq!(similar_to(&hashes, 1, 8), vec![(4, 2), (2, 8)]);
+        assert_eq!(groups(&hashes, u32::MAX).len(), 1);
+    }
+
+    #[test]
+    fn skips_flat_hashes() {
+        let hashes = [hash(1, 0), hash(2, 0b111), hash(3, -1), hash(4, !0b1)];
+        assert!(groups(&hashes, 10).is_empty());
+        assert!(similar_to(&hashes, 1, 10).is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use serde_json::Value;
+use std::process::Command;
+
+use crate::db::metadata::Metadata;
+
+// EXIF dates look like `2019:05:04 12:30:00` and container dates like
+// `2019-05-04T12:30:00.000000Z`; both are stored as `2019-05-04 12:30:00`.
+fn normalize_date(date: &str) -> Option<String> {
+    let date = date.trim();
+    let day = date.get(..10)?.replace(':', "-");
+    let time = date.get(11..19)?;
+    if day.starts_with("0000") {
+        return None;
+    }
+    Some(format!("{} {}", day, time))
+}
+
+// Videos store a rotation in degrees; map it to the matching EXIF orientation
+fn rotation_to_orientation(rotation: i64) -> i64 {
+    match rotation.rem_euclid(360) {
+        90 => 6,
+        180 => 3,
+        270 => 8,
+        _ => 1,
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+// EXIF stores a coordinate as degrees, minutes and seconds, each a rational like `1234/100`
+fn parse_gps_coordinate(value: &str, reference: &str) -> Option<f64> {
+    let mut parts = value.split(',').map(|part| {
+        let part = part.trim();
+        match part.split_once('/') {
+            Some((num, den)) => {
+                let den: f64 = den.parse().ok()?;
+                if den == 0.0 {
+                    None
+                } else {
+                    Some(num.parse::<f64>().ok()? / den)
+                }
+            }
+            None => part.parse().ok(),
+        }
+    });
+    let degrees = parts.next()??;
+    let minutes = parts.next().flatten().unwrap_or(0.0);
+    let seconds = parts.next().flatten().unwrap_or(0.0);
+    let coordinate = degrees + minutes / 60.0 + seconds / 3600.0;
+    match reference.trim() {
+        "S" | "W" => Some(-coordinate),
+        _ => Some(coordinate),
+    }
+}
+
+// Containers store a location in ISO 6709, like `+37.3317-122.0302+010.000/`
+fn parse_iso6709(value: &str) -> Option<(f64, f64)> {
+    let value = value.trim().trim_end_matches('/');
+    let lon_start = value.get(1..)?.find(['+', '-'])? + 1;
+    let lat = value[..lon_start].parse().ok()?;
+    let rest = &value[lon_start..];
+    let lon_end = rest[1..].find(['+', '-']).map(|i| i + 1).unwrap_or(rest.len());
+    let lon = rest[..lon_end].parse().ok()?;
+    Some((lat, lon))
+}
+
+fn image_metadata(file_path: &str) -> Option<Metadata> {
+    let output = Command::new("identify")
+        .args([
+            "-quiet",
+            "-format",
+            "%w\n%h\n%[EXIF:Model]\n%[EXIF:DateTimeOriginal]\n%[EXIF:Orientation]\n\
+             %[EXIF:GPSLatitude]\n%[EXIF:GPSLatitudeRef]\n%[EXIF:GPSLongitude]\n%[EXIF:GPSLongitudeRef]\n",
+            &format!("{}[0]", file_path),
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = output.lines().collect();
+
+    let mut metadata = Metadata::empty();
+    metadata.width = lines.first().and_then(|v| v.parse().ok());
+    metadata.height = lines.get(1).and_then(|v| v.parse().ok());
+    metadata.camera = lines.get(2).and_then(|v| non_empty(v));
+    metadata.captured_at = lines.get(3).and_then(|v| normalize_date(v));
+    metadata.orientation = lines.get(4).and_then(|v| v.parse().ok());
+
+    let coordinate = |index: usize| {
+        let value = lines.get(index).and_then(|v| non_empty(v))?;
+        parse_gps_coordinate(&value, lines.get(index + 1).unwrap_or(&""))
+    };
+    if let (Some(lat), Some(lon)) = (coordinate(5), coordinate(7)) {
+        metadata.latitude = Some(lat);
+        metadata.longitude = Some(lon);
+    }
+    Some(metadata)
+}
+
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/')?;
+    let num: f64 = num.parse().ok()?;
+    let den: f64 = den.parse().ok()?;
+    if num == 0.0 || den == 0.0 {
+        None
+    } else {
+        Some(num / den)
+    }
+}
+
+fn video_metadata(file_path: &str) -> Option<Metadata> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            file_path,
+        ])
+        .output()
+        .ok()?;
+    let json: Value = serde_json::from_slice(&output.stdout).ok()?;
+
+    let mut metadata = Metadata::empty();
+    for stream in json["streams"].as_array()? {
+        match stream["codec_type"].as_str() {
+            Some("video") if metadata.video_codec.is_none() => {
+                metadata.width = stream["width"].as_i64();
+                metadata.height = stream["height"].as_i64();
+                metadata.video_codec = stream["codec_name"].as_str().map(str::to_string);
+                metadata.frame_rate = stream["avg_frame_rate"].as_str().and_then(parse_frame_rate);
+
+                // Older muxers use a `rotate` tag, newer ones a display matrix
+                // whose rotation goes the other way
+                let rotation = match stream["tags"]["rotate"].as_str() {
+                    Some(rotate) => rotate.parse().ok(),
+                    None => stream["side_data_list"]
+                        .as_array()
+                        .and_then(|list| list.iter().find_map(|d| d["rotation"].as_i64()))
+                        .map(|r| -r),
+                };
+                metadata.orientation = rotation.map(rotation_to_orientation);
+            }
+            Some("audio") if metadata.audio_codec.is_none() => {
+                metadata.audio_codec = stream["codec_name"].as_str().map(str::to_string);
+            }
+            _ => {}
+        }
+    }
+
+    let format = &json["format"];
+    metadata.duration = format["duration"].as_str().and_then(|d| d.parse().ok());
+    let tags = &format["tags"];
+    metadata.captured_at = tags["creation_time"].as_str().and_then(normalize_date);
+    metadata.camera = tags["com.apple.quicktime.model"]
+        .as_str()
+        .or_else(|| tags["model"].as_str())
+        .and_then(non_empty);
+    if let Some((lat, lon)) = tags["com.apple.quicktime.location.ISO6709"]
+        .as_str()
+        .or_else(|| tags["location"].as_str())
+        .and_then(parse_iso6709)
+    {
+        metadata.latitude = Some(lat);
+        metadata.longitude = Some(lon);
+    }
+    Some(metadata)
+}
+
+pub fn metadata(file_path: &str, file_type: &str) -> Option<Metadata> {
+    if file_type == "image" {
+        image_metadata(file_path)
+    } else if file_type.starts_with("video") {
+        video_metadata(file_path)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,460 @@
+// Where stored files go under the root folder, from the `store_path` template, e.g.
+// `{album}/{yyyy}/{mm}/{original_name}` or `{md5[0:2]}/{md5}.{ext}`.
+
+use sqlx::SqlitePool;
+use std::fs;
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::item::{self, Item};
+use crate::db::transcode;
+
+// Files in their album, named after their md5
+pub const DEFAULT_TEMPLATE: &str = "{album}/{md5}.{ext}";
+
+// What a stored path is made of
+pub struct Placement<'a> {
+    // Path of the album under the root, empty for the root folder
+    pub album: &'a str,
+    pub original_name: &'a str,
+    pub md5: &'a str,
+    // Extension of the stored file
+    pub ext: &'a str,
+    // Year, month and day the item was added
+    pub date: (String, String, String),
+}
+
+// Year, month and day of a Unix time, in UTC
+pub fn civil_date(secs: i64) -> (i64, i64, i64) {
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn today() -> (String, String, String) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default();
+    let (year, month, day) = civil_date(now);
+    (year.to_string(), format!("{:02}", month), format!("{:02}", day))
+}
+
+// Date of a `created_at` like `2024-05-04 12:30:00.000`
+fn date_of(created_at: &str) -> Option<(String, String, String)> {
+    Some((
+        created_at.get(..4)?.to_string(),
+        created_at.get(5..7)?.to_string(),
+        created_at.get(8..10)?.to_string(),
+    ))
+}
+
+// Replace `{md5[a:b]}`, the characters of the md5 from `a` to `b`
+fn replace_md5_slices(template: &str, md5: &str) -> Option<String> {
+    let mut path = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{md5[") {
+        path.push_str(&rest[..start]);
+        let end = rest[start..].find("]}")? + start;
+        let (from, to) = rest[start + 5..end].split_once(':')?;
+        let from = if from.is_empty() { 0 } else { from.parse().ok()? };
+        let to = if to.is_empty() { md5.len() } else { to.parse().ok()? };
+        path.push_str(md5.get(from..to)?);
+        rest = &rest[end + 2..];
+    }
+    path.push_str(rest);
+    Some(path)
+}
+
+// Path under the root of a file placed by `template`. None when the template is invalid or
+// the path would leave the root.
+pub fn render(template: &str, placement: &Placement) -> Option<String> {
+    // Only the name, as uploaded folders are already in the album
+    let original_name = Path::new(placement.original_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.starts_with('.'))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{}.{}", placement.md5, placement.ext));
+    let name = Path::new(&original_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(placement.md5)
+        .to_string();
+
+    let path = replace_md5_slices(template, placement.md5)?
+        .replace("{album}", placement.album)
+        .replace("{yyyy}", &placement.date.0)
+        .replace("{mm}", &placement.date.1)
+        .replace("{dd}", &placement.date.2)
+        .replace("{original_name}", &original_name)
+        .replace("{name}", &name)
+        .replace("{ext}", placement.ext)
+        .replace("{md5}", placement.md5);
+    let path = path
+        .split('/')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("/");
+    let inside = Path::new(&path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (inside && !path.is_empty() && !path.contains('{')).then_some(path)
+}
+
+// Whether `template` places a file at all
+pub fn is_valid(template: &str) -> bool {
+    let placement = Placement {
+        album: "album",
+        original_name: "name.jpg",
+        md5: "d41d8cd98f00b204e9800998ecf8427e",
+        ext: "jpg",
+        date: ("2000".to_string(), "01".to_string(), "01".to_string()),
+    };
+    render(template, &placement).is_some()
+}
+
+// What a placeholder of a folder name may stand for
+enum Part<'a> {
+    Text(&'a str),
+    Digits(usize),
+    Hex(usize),
+    Any,
+}
+
+// Parts of a folder name of a template, None when it is invalid
+fn parts_of(name: &str) -> Option<Vec<Part<'_>>> {
+    let mut parts = Vec::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        let end = rest[start..].find('}')? + start;
+        parts.push(match &rest[start + 1..end] {
+            "yyyy" => Part::Digits(4),
+            "mm" | "dd" => Part::Digits(2),
+            "md5" => Part::Hex(32),
+            "album" | "original_name" | "name" | "ext" => Part::Any,
+            slice => {
+                let (from, to) = slice.strip_prefix("md5[")?.strip_suffix(']')?.split_once(':')?;
+                let from: usize = if from.is_empty() { 0 } else { from.parse().ok()? };
+                let to: usize = if to.is_empty() { 32 } else { to.parse().ok()? };
+                Part::Hex(to.checked_sub(from)?)
+            }
+        });
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Some(parts)
+}
+
+fn matches_parts(parts: &[Part], name: &str) -> bool {
+    let fixed = |len: usize, rest: &[Part], is_char: fn(&u8) -> bool| {
+        name.get(..len).is_some_and(|head| head.bytes().all(|byte| is_char(&byte)))
+            && matches_parts(rest, &name[len..])
+    };
+    match parts.split_first() {
+        None => name.is_empty(),
+        Some((Part::Text(text), rest)) => name.strip_prefix(text).is_some_and(|name| matches_parts(rest, name)),
+        Some((Part::Digits(len), rest)) => fixed(*len, rest, u8::is_ascii_digit),
+        Some((Part::Hex(len), rest)) => fixed(*len, rest, u8::is_ascii_hexdigit),
+        Some((Part::Any, rest)) => (1..=name.len())
+            .filter(|&end| name.is_char_boundary(end))
+            .any(|end| matches_parts(rest, &name[end..])),
+    }
+}
+
+// The albums under which `template` could have made the folder at `path`, e.g. `trip` for
+// `trip/2024` with `{album}/{yyyy}/{name}.{ext}`, or the root, empty, for `d4` with
+// `{md5[0:2]}/{md5}.{ext}`. Folders it could not have made are albums.
+pub fn albums_of_folder(template: &str, path: &str) -> Vec<String> {
+    let names: Vec<&str> = template.split('/').map(str::trim).filter(|name| !name.is_empty()).collect();
+    let folders = &names[..names.len().saturating_sub(1)];
+    let has_album = folders.contains(&"{album}");
+    let made = match folders
+        .iter()
+        .skip(folders.iter().rposition(|name| *name == "{album}").map_or(0, |album| album + 1))
+        .map(|name| parts_of(name))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(made) => made,
+        None => return Vec::new(),
+    };
+
+    let path: Vec<&str> = path.split('/').collect();
+    (1..=made.len().min(path.len()))
+        .filter_map(|depth| {
+            let (album, folder) = path.split_at(path.len() - depth);
+            let is_made = folder.iter().zip(&made).all(|(name, parts)| matches_parts(parts, name));
+            (is_made && (has_album || album.is_empty())).then(|| album.join("/"))
+        })
+        .collect()
+}
+
+// `path`, or `name-2.ext`, `name-3.ext`… when a file is already there. `own` is the current
+// path of the file being placed, which it may keep.
+pub fn free_path(root_dir: &Path, path: &str, own: Option<&str>) -> String {
+    let is_free = |candidate: &str| own == Some(candidate) || !root_dir.join(candidate).exists();
+    if is_free(path) {
+        return path.to_string();
+    }
+    let file_path = Path::new(path);
+    let stem = file_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or(path);
+    let ext = file_path.extension().and_then(|ext| ext.to_str());
+    let parent = file_path.parent().and_then(|parent| parent.to_str()).unwrap_or_default();
+    let mut n = 2;
+    loop {
+        let name = match ext {
+            Some(ext) => format!("{}-{}.{}", stem, n, ext),
+            None => format!("{}-{}", stem, n),
+        };
+        let candidate = if parent.is_empty() { name } else { format!("{}/{}", parent, name) };
+        if is_free(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+// Remove the folders emptied by a move, up to the root, except those of albums
+async fn remove_empty_dirs(pool: &SqlitePool, root_dir: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir.filter(|current| !current.as_os_str().is_empty()) {
+        let is_album = item::find_by_path(pool, current.to_str().unwrap_or_default()).await.is_ok();
+        if is_album || fs::remove_dir(root_dir.join(current)).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+// Move a file or folder made from a stored file, if there is one
+fn move_derived(dir: &Path, from: &str, to: &str) {
+    let from = dir.join(from);
+    if !from.exists() {
+        return;
+    }
+    let to = dir.join(to);
+    if let Some(parent) = to.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Err(err) = fs::rename(&from, &to) {
+        eprintln!("Failed to move {:?}. {}", from, err);
+    }
+}
+
+// Move the stored file of an item to `path` with its thumbnail and rendition. The item itself
+// is left to the caller to update.
+pub async fn move_files(pool: &SqlitePool, root_dir: &Path, item: &Item, path: &str) -> Result<(), String> {
+    let dest = root_dir.join(path);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    fs::rename(root_dir.join(&item.path), &dest).map_err(|err| err.to_string())?;
+
+    move_derived(&root_dir.join("thumbnail"), &format!("{}.jpg", item.path), &format!("{}.jpg", path));
+
+    if let Ok(rendition) = transcode::find_by_item(pool, item.id).await {
+        // `{path}.mp4` or `{path}.hls/master.m3u8`
+        let suffix = rendition.path.as_deref().and_then(|rendition| rendition.strip_prefix(item.path.as_str()));
+        if let Some(suffix) = suffix {
+            let moved = suffix.split('/').next().unwrap_or(suffix);
+            move_derived(&root_dir.join("derived"), &format!("{}{}", item.path, moved), &format!("{}{}", path, moved));
+            let rendition_path = format!("{}{}", path, suffix);
+            if let Err(err) = transcode::update_status(pool, rendition.id, &rendition.status, Some(&rendition_path)).await {
+                eprintln!("Failed to update rendition of item {}. {:?}", item.id, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Move the files of an item to `path` and record it
+async fn move_item(pool: &SqlitePool, root_dir: &Path, mut item: Item, path: &str) -> Result<(), String> {
+    move_files(pool, root_dir, &item, path).await?;
+
+    let old_path = Path::new(&item.path).to_path_buf();
+    item.path = path.to_string();
+    item::update(pool, item, None).await.map_err(|err| err.to_string())?;
+    for dir in [root_dir.to_path_buf(), root_dir.join("thumbnail"), root_dir.join("derived")] {
+        remove_empty_dirs(pool, &dir, &old_path).await;
+    }
+    Ok(())
+}
+
+// Move the files of all items to where `template` places them. With `dry_run`, only print
+// what would move.
+pub async fn reorganise(pool: &SqlitePool, root_dir: &Path, template: &str, dry_run: bool) {
+    if !is_valid(template) {
+        eprintln!("Invalid store_path {}", template);
+        return;
+    }
+    let items = match item::find_files(pool).await {
+        Ok(items) => items,
+        Err(err) => {
+            eprintln!("Failed to list items. {:?}", err);
+            return;
+        }
+    };
+    let (mut moved, mut failed) = (0, 0);
+    for item in items {
+        let album = match item.parent {
+            Some(parent) => match item::find_by_id(pool, parent).await {
+                Ok(parent) => parent.path,
+                Err(_) => continue,
+            },
+            None => String::new(),
+        };
+        let current = Path::new(&item.path);
+        let current_name = current.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let placement = Placement {
+            album: &album,
+            original_name: item.original_name.as_deref().unwrap_or(current_name),
+            md5: &item.md5,
+            ext: current.extension().and_then(|ext| ext.to_str()).unwrap_or_default(),
+            date: date_of(&item.created_at).unwrap_or_else(today),
+        };
+        let path = match render(template, &placement) {
+            Some(path) => path,
+            None => {
+                eprintln!("Failed to place item {}", item.id);
+                failed += 1;
+                continue;
+            }
+        };
+        // An item renamed `x-2.jpg` as `x.jpg` was taken keeps its name
+        let path = free_path(root_dir, &path, Some(&item.path));
+        if path == item.path {
+            continue;
+        }
+        println!("{} -> {}", item.path, path);
+        if dry_run {
+            moved += 1;
+            continue;
+        }
+        let id = item.id;
+        match move_item(pool, root_dir, item, &path).await {
+            Ok(()) => moved += 1,
+            Err(err) => {
+                eprintln!("Failed to move item {}. {}", id, err);
+                failed += 1;
+            }
+        }
+    }
+    if dry_run {
+        println!("{} files would move, {} cannot be placed", moved, failed);
+    } else {
+        println!("Moved {} files, {} failed", moved, failed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    const MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    fn placement<'a>(album: &'a str, original_name: &'a str) -> Placement<'a> {
+        Placement {
+            album,
+            original_name,
+            md5: MD5,
+            ext: "jpg",
+            date: ("2024".to_string(), "05".to_string(), "04".to_string()),
+        }
+    }
+
+    #[test]
+    fn replaces_md5_slices() {
+        assert_eq!(replace_md5_slices("{md5[0:2]}/{md5[2:4]}", MD5).as_deref(), Some("d4/1d"));
+        assert_eq!(replace_md5_slices("{md5[:3]}-{md5[30:]}", MD5).as_deref(), Some("d41-7e"));
+        assert_eq!(replace_md5_slices("{md5}", MD5).as_deref(), Some("{md5}"));
+        assert_eq!(replace_md5_slices("{md5[0:40]}", MD5), None);
+        assert_eq!(replace_md5_slices("{md5[2]}", MD5), None);
+        assert_eq!(replace_md5_slices("{md5[a:b]}", MD5), None);
+        assert_eq!(replace_md5_slices("{md5[0:2}", MD5), None);
+    }
+
+    #[test]
+    fn renders_templates() {
+        let photo = placement("trip", "dir/IMG 1.jpeg");
+        assert_eq!(render(DEFAULT_TEMPLATE, &photo), Some(format!("trip/{}.jpg", MD5)));
+        assert_eq!(render("{yyyy}/{mm}/{dd}/{name}.{ext}", &photo).as_deref(), Some("2024/05/04/IMG 1.jpg"));
+        assert_eq!(render("{album}/{original_name}", &photo).as_deref(), Some("trip/IMG 1.jpeg"));
+        assert_eq!(render("{md5[0:2]}/{md5}.{ext}", &photo), Some(format!("d4/{}.jpg", MD5)));
+        // The root album leaves no empty folder
+        assert_eq!(render(DEFAULT_TEMPLATE, &placement("", "a.jpg")), Some(format!("{}.jpg", MD5)));
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        let photo = placement("trip", "a.jpg");
+        assert_eq!(render("{album}/{unknown}.{ext}", &photo), None);
+        assert_eq!(render("{md5[0:99]}.{ext}", &photo), None);
+        assert_eq!(render("", &photo), None);
+        assert_eq!(render("../{md5}.{ext}", &photo), None);
+        assert_eq!(render("{album}/../../{md5}.{ext}", &photo), None);
+        assert_eq!(render("/{album}/{md5}.{ext}", &photo), Some(format!("trip/{}.jpg", MD5)));
+        assert!(is_valid(DEFAULT_TEMPLATE));
+        assert!(!is_valid("{album}/{md5[0:2}"));
+    }
+
+    #[test]
+    fn keeps_names_inside() {
+        let fallback = format!("{}.jpg", MD5);
+        for name in ["..", ".hidden.jpg", "../../etc/passwd", ""] {
+            let rendered = render("{original_name}", &placement("", name));
+            assert!(rendered.is_some(), "{}", name);
+            assert!(!rendered.as_deref().unwrap().contains(".."), "{}", name);
+        }
+        assert_eq!(render("{original_name}", &placement("", ".hidden.jpg")), Some(fallback.clone()));
+        assert_eq!(render("{name}.{ext}", &placement("", "..")), Some(fallback));
+        assert_eq!(render("{original_name}", &placement("", "../../etc/passwd")).as_deref(), Some("passwd"));
+    }
+
+    #[test]
+    fn tells_made_folders() {
+        let template = "{md5[0:2]}/{md5}.{ext}";
+        assert_eq!(albums_of_folder(template, "d4"), vec![""]);
+        assert!(albums_of_folder(template, "trip").is_empty());
+        assert!(albums_of_folder(template, "trip/d4").is_empty());
+        let template = "{album}/{yyyy}/{mm}/{original_name}";
+        assert_eq!(albums_of_folder(template, "2024"), vec![""]);
+        assert_eq!(albums_of_folder(template, "trip/2024/05"), vec!["trip"]);
+        assert_eq!(albums_of_folder(template, "trip/2024"), vec!["trip"]);
+        assert!(albums_of_folder(template, "trip/summer").is_empty());
+        assert!(albums_of_folder(template, "trip/05").is_empty());
+        assert_eq!(albums_of_folder("{album}/x-{yyyy}/{md5}", "trip/x-2024"), vec!["trip"]);
+        assert!(albums_of_folder(DEFAULT_TEMPLATE, "trip").is_empty());
+        assert!(albums_of_folder("{md5}", "d4").is_empty());
+    }
+
+    #[test]
+    fn finds_free_paths() {
+        let root = temp_dir("free");
+        fs::create_dir_all(root.join("album")).unwrap();
+        assert_eq!(free_path(&root, "album/a.jpg", None), "album/a.jpg");
+        fs::write(root.join("album/a.jpg"), b"").unwrap();
+        assert_eq!(free_path(&root, "album/a.jpg", None), "album/a-2.jpg");
+        fs::write(root.join("album/a-2.jpg"), b"").unwrap();
+        assert_eq!(free_path(&root, "album/a.jpg", None), "album/a-3.jpg");
+        // A file may keep its own path, or take a free one next to it
+        assert_eq!(free_path(&root, "album/a.jpg", Some("album/a.jpg")), "album/a.jpg");
+        assert_eq!(free_path(&root, "album/a.jpg", Some("album/a-2.jpg")), "album/a-2.jpg");
+        fs::write(root.join("README"), b"").unwrap();
+        assert_eq!(free_path(&root, "README", None), "README-2");
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,240 @@
+use async_std::task;
+use sqlx::SqlitePool;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use crate::db::{item, transcode};
+
+// Video codecs that every current browser can decode in an mp4 or webm container
+const PLAYABLE_CODECS: [&str; 4] = ["h264", "vp8", "vp9", "av1"];
+
+// Height and video bitrate of each HLS rendition
+const HLS_LADDER: [(u32, &str); 3] = [(360, "800k"), (720, "2800k"), (1080, "5000k")];
+
+fn probe(file_path: &str, stream: &str, entry: &str) -> Option<String> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            stream,
+            "-show_entries",
+            &format!("stream={}", entry),
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            file_path,
+        ])
+        .output()
+        .ok()?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+pub fn needs_transcode(file_path: &str) -> bool {
+    let ext = Path::new(file_path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match ext.as_str() {
+        "mp4" | "webm" => match probe(file_path, "v:0", "codec_name") {
+            Some(codec) => !PLAYABLE_CODECS.contains(&codec.as_str()),
+            None => false,
+        },
+        // HLS playlists are played as they are
+        "m3u8" => false,
+        _ => true,
+    }
+}
+
+pub async fn enqueue(pool: &SqlitePool, item: &item::Item, file_path: &str, format: &str) {
+    if !item.file_type.starts_with("video") {
+        return;
+    }
+    if transcode::find_by_item(pool, item.id).await.is_ok() {
+        return;
+    }
+    if needs_transcode(file_path) {
+        if let Err(err) = transcode::insert(pool, item.id, format).await {
+            eprintln!("Failed to queue transcode of item {}. {:?}", item.id, err);
+        }
+    }
+}
+
+fn transcode_mp4(src: &str, dest: &Path) -> bool {
+    Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-loglevel",
+            "error",
+            "-i",
+            src,
+            "-map",
+            "0:v:0",
+            "-map",
+            "0:a:0?",
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-crf",
+            "23",
+            "-pix_fmt",
+            "yuv420p",
+            "-c:a",
+            "aac",
+            "-b:a",
+            "128k",
+            "-movflags",
+            "+faststart",
+            dest.to_str().unwrap(),
+        ])
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+fn transcode_hls(src: &str, dest_dir: &Path) -> bool {
+    let height: u32 = probe(src, "v:0", "height")
+        .and_then(|h| h.parse().ok())
+        .unwrap_or(0);
+    let has_audio = probe(src, "a:0", "codec_name").is_some();
+
+    let mut ladder: Vec<(u32, &str)> = HLS_LADDER
+        .iter()
+        .filter(|(h, _)| *h <= height)
+        .cloned()
+        .collect();
+    if ladder.is_empty() {
+        ladder.push(HLS_LADDER[0]);
+    }
+
+    let mut filter = format!("[0:v]split={}", ladder.len());
+    for i in 0..ladder.len() {
+        filter.push_str(&format!("[s{}]", i));
+    }
+    let mut stream_map = Vec::new();
+    let mut args = vec!["-y".to_string(), "-loglevel".to_string(), "error".to_string()];
+    args.extend(["-i".to_string(), src.to_string()]);
+    for (i, (h, bitrate)) in ladder.iter().enumerate() {
+        filter.push_str(&format!(";[s{}]scale=-2:{}[v{}]", i, h, i));
+        args.extend([
+            "-map".to_string(),
+            format!("[v{}]", i),
+            format!("-c:v:{}", i),
+            "libx264".to_string(),
+            format!("-b:v:{}", i),
+            bitrate.to_string(),
+        ]);
+        if has_audio {
+            args.extend([
+                "-map".to_string(),
+                "0:a:0".to_string(),
+                format!("-c:a:{}", i),
+                "aac".to_string(),
+                format!("-b:a:{}", i),
+                "128k".to_string(),
+            ]);
+            stream_map.push(format!("v:{},a:{}", i, i));
+        } else {
+            stream_map.push(format!("v:{}", i));
+        }
+    }
+    args.extend([
+        "-filter_complex".to_string(),
+        filter,
+        "-preset".to_string(),
+        "veryfast".to_string(),
+        "-pix_fmt".to_string(),
+        "yuv420p".to_string(),
+        "-f".to_string(),
+        "hls".to_string(),
+        "-hls_time".to_string(),
+        "6".to_string(),
+        "-hls_playlist_type".to_string(),
+        "vod".to_string(),
+        "-hls_segment_filename".to_string(),
+        dest_dir.join("%v/segment%03d.ts").to_str().unwrap().to_string(),
+        "-master_pl_name".to_string(),
+        "master.m3u8".to_string(),
+        "-var_stream_map".to_string(),
+        stream_map.join(" "),
+        dest_dir.join("%v/index.m3u8").to_str().unwrap().to_string(),
+    ]);
+
+    Command::new("ffmpeg")
+        .args(args)
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+// Transcode one queued item and return the rendition path, relative to derived_dir
+fn run_job(src: &str, item_path: &str, format: &str, derived_dir: &Path) -> Option<String> {
+    if format == "hls" {
+        let rel_dir = format!("{}.hls", item_path);
+        let dest_dir = derived_dir.join(&rel_dir);
+        create_dir_all(&dest_dir).ok()?;
+        if transcode_hls(src, &dest_dir) {
+            return Some(format!("{}/master.m3u8", rel_dir));
+        }
+    } else {
+        let rel_path = format!("{}.mp4", item_path);
+        let dest = derived_dir.join(&rel_path);
+        create_dir_all(dest.parent()?).ok()?;
+        if transcode_mp4(src, &dest) {
+            return Some(rel_path);
+        }
+    }
+    None
+}
+
+async fn run(pool: SqlitePool, root_dir: PathBuf, derived_dir: PathBuf) {
+    // Jobs interrupted by a restart are started over
+    for job in transcode::find_by_status(&pool, "running").await.unwrap_or_default() {
+        let _ = transcode::update_status(&pool, job.id, "pending", None).await;
+    }
+
+    loop {
+        let jobs = transcode::find_by_status(&pool, "pending")
+            .await
+            .unwrap_or_default();
+        if jobs.is_empty() {
+            task::sleep(Duration::from_secs(10)).await;
+            continue;
+        }
+
+        for job in jobs {
+            let item = match item::find_by_id(&pool, job.item).await {
+                Ok(item) => item,
+                Err(_) => {
+                    let _ = transcode::delete_by_item(&pool, job.item).await;
+                    continue;
+                }
+            };
+            let _ = transcode::update_status(&pool, job.id, "running", None).await;
+
+            let src = root_dir.join(&item.path);
+            match run_job(src.to_str().unwrap(), &item.path, &job.format, &derived_dir) {
+                Some(path) => {
+                    let _ = transcode::update_status(&pool, job.id, "done", Some(&path)).await;
+                }
+                None => {
+                    eprintln!("Failed to transcode item {}.", item.id);
+                    let _ = transcode::update_status(&pool, job.id, "failed", None).await;
+                }
+            }
+        }
+    }
+}
+
+pub fn spawn_worker(pool: SqlitePool, root_dir: PathBuf, derived_dir: PathBuf) {
+    thread::spawn(move || task::block_on(run(pool, root_dir, derived_dir)));
+}
@@ -0,0 +1,200 @@
+// Checks on the content of uploaded files: that their type is allowed, that their first bytes are
+// those of that type and, optionally, that they can be decoded.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Seconds of a video decoded to check it
+const DECODE_SECONDS: &str = "10";
+
+#[derive(Clone)]
+pub struct Validation {
+    // Extensions of the files accepted, all supported ones when None
+    pub types: Option<Vec<String>>,
+    // Decode images and the start of videos, which takes a while
+    pub decode: bool,
+}
+
+// `jpeg` and `jpg` are the same type
+fn canonical(ext: &str) -> String {
+    match ext.to_lowercase().as_str() {
+        "jpeg" => "jpg".to_string(),
+        ext => ext.to_string(),
+    }
+}
+
+// Extensions from a list like `jpg, png, mp4`
+pub fn parse_types(list: &str) -> Option<Vec<String>> {
+    let types: Vec<String> = list
+        .split(',')
+        .map(|ext| canonical(ext.trim().trim_start_matches('.')))
+        .filter(|ext| !ext.is_empty())
+        .collect();
+    (!types.is_empty()).then_some(types)
+}
+
+// Format a file of an extension is stored in
+fn format_of(ext: &str) -> Option<&'static str> {
+    match canonical(ext).as_str() {
+        "jpg" => Some("jpeg"),
+        "png" => Some("png"),
+        "gif" => Some("gif"),
+        "webp" => Some("webp"),
+        "bmp" => Some("bmp"),
+        "mp4" => Some("mp4"),
+        "mpg" => Some("mpeg"),
+        "webm" | "mkv" => Some("matroska"),
+        "avi" => Some("avi"),
+        "mts" => Some("mpegts"),
+        "flv" => Some("flv"),
+        "m3u8" => Some("m3u8"),
+        _ => None,
+    }
+}
+
+// Format of a file from its first bytes
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    if at(0, b"\xFF\xD8\xFF") {
+        Some("jpeg")
+    } else if at(0, b"\x89PNG\r\n\x1A\n") {
+        Some("png")
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some("gif")
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        Some("webp")
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        Some("avi")
+    } else if at(0, b"BM") {
+        Some("bmp")
+    } else if at(4, b"ftyp") {
+        Some("mp4")
+    } else if at(0, b"\x00\x00\x01\xBA") || at(0, b"\x00\x00\x01\xB3") {
+        Some("mpeg")
+    } else if at(0, b"\x1A\x45\xDF\xA3") {
+        Some("matroska")
+    } else if at(0, b"FLV") {
+        Some("flv")
+    } else if (at(0, b"\x47") && at(188, b"\x47")) || (at(4, b"\x47") && at(196, b"\x47")) {
+        // Transport stream packets, with a 4 byte timecode in front in `.mts` files
+        Some("mpegts")
+    } else if at(0, b"#EXTM3U") || at(0, b"\xEF\xBB\xBF#EXTM3U") {
+        Some("m3u8")
+    } else {
+        None
+    }
+}
+
+fn read_head(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    File::open(path)?.take(512).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+// Whether a file decodes without errors. Files are not rejected when the tool is missing.
+fn decodes(path: &Path, format: &str) -> bool {
+    let path = match path.to_str() {
+        Some(path) => path,
+        None => return false,
+    };
+    let status = match format {
+        // A playlist, its segments are elsewhere
+        "m3u8" => return true,
+        "jpeg" | "png" | "gif" | "webp" | "bmp" => Command::new("convert")
+            .args(["-quiet", "-regard-warnings", &format!("{}[0]", path), "null:"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status(),
+        _ => Command::new("ffmpeg")
+            .args(["-v", "error", "-xerror", "-t", DECODE_SECONDS, "-i", path, "-f", "null", "-"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status(),
+    };
+    match status {
+        Ok(status) => status.success(),
+        Err(err) => {
+            eprintln!("Failed to check {}. {}", path, err);
+            true
+        }
+    }
+}
+
+// Why the file at `path`, uploaded as `name`, is not accepted. The error is for the user.
+pub fn check(validation: &Validation, name: &str, path: &Path) -> Result<(), String> {
+    let ext = Path::new(name).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let ext_name = canonical(ext).to_uppercase();
+    if let Some(types) = &validation.types {
+        if !types.contains(&canonical(ext)) {
+            return Err(format!("{} files are not accepted", ext_name));
+        }
+    }
+    let format = format_of(ext).ok_or("Unsupported file type")?;
+    let head = read_head(path).map_err(|err| {
+        eprintln!("Failed to read {:?}. {}", path, err);
+        "Could not be read".to_string()
+    })?;
+    if head.is_empty() {
+        return Err("Empty file".to_string());
+    }
+    if sniff(&head) != Some(format) {
+        return Err(format!("Not a {} file", ext_name));
+    }
+    if validation.decode && !decodes(path, format) {
+        return Err("Could not be decoded, the file may be damaged".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_types() {
+        assert_eq!(
+            parse_types(" JPEG, .png,,mp4 "),
+            Some(vec!["jpg".to_string(), "png".to_string(), "mp4".to_string()])
+        );
+        assert_eq!(parse_types(""), None);
+        assert_eq!(parse_types(" , ."), None);
+    }
+
+    #[test]
+    fn sniffs_formats() {
+        let mut ts = vec![0u8; 189];
+        ts[0] = 0x47;
+        ts[188] = 0x47;
+        let mut mts = vec![0u8; 197];
+        mts[4] = 0x47;
+        mts[196] = 0x47;
+        let samples: [(&[u8], &str); 11] = [
+            (b"\xFF\xD8\xFF\xE0", "jpeg"),
+            (b"\x89PNG\r\n\x1A\n", "png"),
+            (b"GIF89a", "gif"),
+            (b"RIFF\0\0\0\0WEBPVP8 ", "webp"),
+            (b"RIFF\0\0\0\0AVI LIST", "avi"),
+            (b"\0\0\0\x18ftypmp42", "mp4"),
+            (b"\x1A\x45\xDF\xA3", "matroska"),
+            (b"\x00\x00\x01\xBA", "mpeg"),
+            (b"\xEF\xBB\xBF#EXTM3U\n", "m3u8"),
+            (&ts, "mpegts"),
+            (&mts, "mpegts"),
+        ];
+        for (head, format) in samples {
+            assert_eq!(sniff(head), Some(format), "{}", format);
+        }
+    }
+
+    #[test]
+    fn sniffs_nothing_from_short_or_unknown_heads() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"\xFF\xD8"), None);
+        assert_eq!(sniff(b"RIFF"), None);
+        assert_eq!(sniff(b"<html>"), None);
+        // A lone sync byte is not a transport stream
+        assert_eq!(sniff(b"\x47\x00\x00"), None);
+    }
+}
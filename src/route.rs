@@ -1,29 +1,82 @@
 use serde::Deserialize;
 use sqlx::SqlitePool;
+
+use crate::db::item::DateOrder;
+use crate::media::validate::Validation;
 use std::fs::{create_dir_all, read_dir};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 pub mod admin;
 pub mod album;
+pub mod audit;
+pub mod auth;
+pub mod csrf;
+pub mod fetch;
+pub mod history;
+pub mod img;
+pub mod inbox;
 pub mod index;
+pub mod map;
 pub mod post;
+pub mod share;
+pub mod timeline;
+pub mod trash;
+pub mod tus;
 pub mod upload;
+pub mod user;
 
+#[derive(Clone)]
 pub struct AppState {
     pool: SqlitePool,
     ipp: i64,
     root_dir: PathBuf,
     thumbnail_dir: PathBuf,
+    derived_dir: PathBuf,
+    transcode: Option<String>,
+    order: DateOrder,
+    tile_url: String,
+    similar_threshold: u32,
+    upload_max_size: Option<u64>,
+    validation: Validation,
+    import_dir: Option<PathBuf>,
+    store_template: String,
+    // Key of the CSRF tokens
+    csrf_secret: String,
 }
 
 impl AppState {
-    pub fn new(pool: SqlitePool, ipp: i64, root_dir: PathBuf, thumbnail_dir: PathBuf) -> Self {
+    pub fn new(
+        pool: SqlitePool,
+        ipp: i64,
+        root_dir: PathBuf,
+        thumbnail_dir: PathBuf,
+        derived_dir: PathBuf,
+        transcode: Option<String>,
+        order: DateOrder,
+        tile_url: String,
+        similar_threshold: u32,
+        upload_max_size: Option<u64>,
+        validation: Validation,
+        import_dir: Option<PathBuf>,
+        store_template: String,
+        csrf_secret: String,
+    ) -> Self {
         AppState {
             pool,
             ipp,
             root_dir,
             thumbnail_dir,
+            derived_dir,
+            transcode,
+            order,
+            tile_url,
+            similar_threshold,
+            upload_max_size,
+            validation,
+            import_dir,
+            store_template,
+            csrf_secret,
         }
     }
 }
@@ -37,7 +90,15 @@ pub struct QueryInfo {
     file_name: Option<String>,
     real_file_name: Option<String>,
     md5: Option<String>,
+    source: Option<String>,
+    url: Option<String>,
+    error: Option<String>,
     raw: Option<u8>,
+    year: Option<i32>,
+    month: Option<u32>,
+    day: Option<u32>,
+    zoom: Option<u32>,
+    threshold: Option<u32>,
 }
 
 macro_rules! redirect {
@@ -140,3 +201,33 @@ fn create_thumbnail(
         }
     }
 }
+
+// State on an empty database in memory, for tests of the middlewares and handlers
+#[cfg(test)]
+pub async fn test_state(root_dir: &Path) -> AppState {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    crate::db::migrate::run(&pool).await.unwrap();
+    AppState::new(
+        pool,
+        10,
+        root_dir.to_path_buf(),
+        root_dir.join("thumbnail"),
+        root_dir.join("derived"),
+        None,
+        DateOrder::Created,
+        String::new(),
+        10,
+        None,
+        Validation {
+            types: None,
+            decode: false,
+        },
+        None,
+        crate::media::store::DEFAULT_TEMPLATE.to_string(),
+        csrf::new_token(),
+    )
+}
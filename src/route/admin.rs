@@ -1,13 +1,17 @@
 use actix_web::{error, get, post, web, HttpResponse, Responder};
 use md5::{Digest, Md5};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::Path;
 use walkdir::WalkDir;
 
-use super::{create_thumbnail, guess_file_type, redirect, AppState};
+use super::auth::CurrentUser;
+use super::{create_thumbnail, csrf, guess_file_type, redirect, AppState, QueryInfo};
+use crate::db::item::Viewer;
 use crate::db::{item, tag};
+use crate::media::{self, store};
 
 #[derive(Deserialize)]
 pub struct TagData {
@@ -17,8 +21,9 @@ pub struct TagData {
 }
 
 #[get("/admin/")]
-pub async fn admin(tmpl: web::Data<tera::Tera>) -> impl Responder {
-    let ctx = tera::Context::new();
+pub async fn admin(tmpl: web::Data<tera::Tera>, csrf: csrf::Token) -> impl Responder {
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
     let template = tmpl
         .render("admin.html", &ctx)
         .map_err(|_| error::ErrorInternalServerError("Template error"))
@@ -29,7 +34,7 @@ pub async fn admin(tmpl: web::Data<tera::Tera>) -> impl Responder {
 #[get("/admin/tags/")]
 pub async fn manage_tags(data: web::Data<AppState>, tmpl: web::Data<tera::Tera>) -> impl Responder {
     let mut ctx = tera::Context::new();
-    if let Ok(tags) = tag::count_tags(&data.pool).await {
+    if let Ok(tags) = tag::count_tags(&data.pool, &Viewer::ADMIN).await {
         ctx.insert("tags", &tags);
     }
     let template = tmpl
@@ -44,8 +49,10 @@ pub async fn manage_tag(
     data: web::Data<AppState>,
     name: web::Path<String>,
     tmpl: web::Data<tera::Tera>,
+    csrf: csrf::Token,
 ) -> impl Responder {
     let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
 
     let tag = tag::find_or_create(&data.pool, &name.into_inner())
         .await
@@ -62,7 +69,11 @@ pub async fn manage_tag(
 }
 
 #[post("/admin/tag/")]
-pub async fn tag_update(data: web::Data<AppState>, tagdata: web::Form<TagData>) -> impl Responder {
+pub async fn tag_update(
+    data: web::Data<AppState>,
+    tagdata: web::Form<TagData>,
+    current: CurrentUser,
+) -> impl Responder {
     let name = tagdata.name.as_ref().unwrap();
     let id = tagdata.id.unwrap();
     if let Ok(tag) = tag::find_by_name(&data.pool, name).await {
@@ -77,18 +88,32 @@ pub async fn tag_update(data: web::Data<AppState>, tagdata: web::Form<TagData>)
         deps = post_deps.split_whitespace().collect();
     }
 
-    tag::update_tag(&data.pool, id, &name, deps).await;
+    tag::update_tag(&data.pool, id, &name, deps, current.id()).await;
     redirect!(format!("/admin/tag/{}", name))
 }
 
-#[get("/admin/reload/")]
-pub async fn reload(data: web::Data<AppState>) -> impl Responder {
+// Whether the folder at `path` was made by store_path under an album or the root
+async fn is_store_folder(data: &AppState, path: &str) -> bool {
+    for album in store::albums_of_folder(&data.store_template, path) {
+        if album.is_empty() || item::find_by_path(&data.pool, &album).await.is_ok() {
+            return true;
+        }
+    }
+    false
+}
+
+#[post("/admin/reload/")]
+pub async fn reload(data: web::Data<AppState>, current: CurrentUser) -> impl Responder {
     for entry in WalkDir::new(&data.root_dir)
         .into_iter()
         .filter_map(|e| e.ok())
     {
         let file_path = entry.path().to_str().unwrap();
-        if entry.path().starts_with(Path::new(&data.thumbnail_dir)) {
+        if entry.path().starts_with(Path::new(&data.thumbnail_dir))
+            || entry.path().starts_with(Path::new(&data.derived_dir))
+            || entry.path().starts_with(data.root_dir.join("trash"))
+            || entry.path().starts_with(data.root_dir.join("tmp"))
+        {
             continue;
         }
         let root_path = Path::new(&data.root_dir);
@@ -117,6 +142,8 @@ pub async fn reload(data: web::Data<AppState>) -> impl Responder {
 
         let mut item = match item::find_by_path(&data.pool, rel_path).await {
             Ok(_item) => _item,
+            // Folders made by store_path are not albums
+            Err(_) if file_type == "folder" && is_store_folder(&data, rel_path).await => continue,
             Err(_) => item::Item::new(
                 file_name.to_owned(),
                 rel_path.to_string(),
@@ -134,16 +161,19 @@ pub async fn reload(data: web::Data<AppState>) -> impl Responder {
             force,
         );
 
-        let parent_folder = Path::new(&item.path)
-            .parent()
-            .unwrap_or(Path::new(""))
-            .to_str()
-            .unwrap();
-        if !parent_folder.is_empty() {
-            if let Ok(_item) = item::find_by_path(&data.pool, parent_folder).await {
-                item.parent = Some(_item.id);
-            } else {
-                item.parent = None;
+        // Files are in the album above them, unless store_path does not follow albums
+        let follows_album = file_type == "folder" || data.store_template.contains("{album}");
+        if item.id == 0 || follows_album {
+            item.parent = None;
+            for parent_folder in Path::new(&item.path).ancestors().skip(1) {
+                let parent_folder = parent_folder.to_str().unwrap_or_default();
+                if parent_folder.is_empty() {
+                    break;
+                }
+                if let Ok(_item) = item::find_by_path(&data.pool, parent_folder).await {
+                    item.parent = Some(_item.id);
+                    break;
+                }
             }
         }
 
@@ -166,6 +196,9 @@ pub async fn reload(data: web::Data<AppState>) -> impl Responder {
             }
             item.md5 = format!("{:x}", md5.finalize());
             match item::find_by_md5(&data.pool, &item.md5).await {
+                Ok(trashed) if trashed.deleted_at.is_some() => {
+                    println!("{}: same file as {}, which is in the trash.", item.path, trashed.id);
+                }
                 Ok(_) => {
                     println!("{}: duplicated md5sum {}.", item.path, item.md5);
                     item::delete_local_file(file_path).await;
@@ -176,16 +209,35 @@ pub async fn reload(data: web::Data<AppState>) -> impl Responder {
                     ))
                     .await;
                 }
-                Err(_) => {
-                    if let Err(err) = item::insert(&data.pool, &item).await {
-                        eprintln!("Failed to insert item. {:?}", err);
+                Err(_) => match item::insert(&data.pool, &item).await {
+                    Ok(id) => {
+                        item.id = id;
+                        media::index(
+                            &data.pool,
+                            &item,
+                            file_path,
+                            &data.thumbnail_dir,
+                            data.transcode.as_deref(),
+                        )
+                        .await;
                     }
-                }
+                    Err(err) => eprintln!("Failed to insert item. {:?}", err),
+                },
             };
         } else {
+            if item.id != 0 {
+                media::index(
+                    &data.pool,
+                    &item,
+                    file_path,
+                    &data.thumbnail_dir,
+                    data.transcode.as_deref(),
+                )
+                .await;
+            }
             if item.file_type != file_type {
                 item.file_type = file_type.to_string();
-                item::update(&data.pool, item).await;
+                item::update(&data.pool, item, current.id()).await;
             }
         }
     }
@@ -193,8 +245,45 @@ pub async fn reload(data: web::Data<AppState>) -> impl Responder {
     redirect!("/admin/")
 }
 
-#[get("/delete/tag/{id}")]
-pub async fn tag_delete(data: web::Data<AppState>, id: web::Path<i64>) -> impl Responder {
-    tag::delete_tag(&data.pool, id.into_inner()).await;
+#[post("/delete/tag/{id}")]
+pub async fn tag_delete(
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+    current: CurrentUser,
+) -> impl Responder {
+    tag::delete_tag(&data.pool, id.into_inner(), current.id()).await;
     redirect!("/admin/tags/")
 }
+
+#[get("/admin/similar/")]
+pub async fn similar(
+    data: web::Data<AppState>,
+    tmpl: web::Data<tera::Tera>,
+    query: web::Query<QueryInfo>,
+    phashes: web::Data<media::phash::Cache>,
+) -> impl Responder {
+    let mut ctx = tera::Context::new();
+    // No two hashes are more than 64 bits apart
+    let threshold = query.threshold.unwrap_or(data.similar_threshold).min(64);
+    ctx.insert("threshold", &threshold);
+
+    let groups = phashes.groups(&data.pool, threshold).await;
+    let ids: Vec<i64> = groups.iter().flatten().cloned().collect();
+    let items: HashMap<i64, item::Item> = item::find_by_ids(&data.pool, ids, &Viewer::ADMIN)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|i| (i.id, i))
+        .collect();
+    let groups: Vec<Vec<&item::Item>> = groups
+        .iter()
+        .map(|group| group.iter().filter_map(|id| items.get(id)).collect())
+        .collect();
+    ctx.insert("groups", &groups);
+
+    let template = tmpl
+        .render("similar.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
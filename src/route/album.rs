@@ -1,7 +1,8 @@
 use actix_web::{post, web, get, HttpResponse, Responder, error};
 use serde::Deserialize;
 
-use super::{redirect, AppState};
+use super::auth::CurrentUser;
+use super::{csrf, redirect, AppState};
 use crate::db::item;
 
 #[derive(Deserialize)]
@@ -15,12 +16,15 @@ pub struct FolderForm {
 pub async fn get_new(
     tmpl: web::Data<tera::Tera>,
     data: web::Data<AppState>,
+    current: CurrentUser,
+    csrf: csrf::Token,
 ) -> impl Responder {
     // context to pass data to html template
     let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
 
     // List of folders
-    let folders = item::find_by_type(&data.pool, "folder")
+    let folders = item::find_by_type(&data.pool, "folder", &current.viewer())
         .await
         .unwrap_or(vec![]);
     ctx.insert("folders", &folders);
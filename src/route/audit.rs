@@ -0,0 +1,141 @@
+use actix_web::{error, get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::index::Pages;
+use super::AppState;
+use crate::db::audit::{self, Audit, Filter};
+use crate::db::user;
+
+const PER_PAGE: i64 = 50;
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    user: Option<String>,
+    action: Option<String>,
+    entity: Option<String>,
+    id: Option<String>,
+    page: Option<u32>,
+}
+
+// A field that differs between before and after
+#[derive(Serialize)]
+struct Change {
+    field: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LogEntry {
+    event: Audit,
+    changes: Vec<Change>,
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|value| !value.is_empty())
+}
+
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        Value::Array(values) => values.iter().map(show).collect::<Vec<_>>().join(" "),
+        _ => value.to_string(),
+    }
+}
+
+// Fields of objects that changed, or for lists like tags, what was removed and added
+fn changes(before: Option<&str>, after: Option<&str>) -> Vec<Change> {
+    let parse = |json: Option<&str>| json.and_then(|json| serde_json::from_str::<Value>(json).ok());
+    let (before, after) = (parse(before), parse(after));
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let mut fields: Vec<&String> = before.keys().chain(after.keys()).collect();
+            fields.sort();
+            fields.dedup();
+            fields
+                .into_iter()
+                .filter(|field| before.get(*field) != after.get(*field))
+                .map(|field| Change {
+                    field: field.clone(),
+                    before: before.get(field).map(show),
+                    after: after.get(field).map(show),
+                })
+                .collect()
+        }
+        (Some(Value::Array(before)), Some(Value::Array(after))) => {
+            let removed: Vec<String> = before.iter().filter(|v| !after.contains(v)).map(show).collect();
+            let added: Vec<String> = after.iter().filter(|v| !before.contains(v)).map(show).collect();
+            vec![Change {
+                field: "tags".to_string(),
+                before: Some(removed.join(" ")).filter(|removed| !removed.is_empty()),
+                after: Some(added.join(" ")).filter(|added| !added.is_empty()),
+            }]
+        }
+        (before, after) => vec![Change {
+            field: String::new(),
+            before: before.as_ref().map(show),
+            after: after.as_ref().map(show),
+        }],
+    }
+}
+
+#[get("/admin/log/")]
+pub async fn log(
+    data: web::Data<AppState>,
+    tmpl: web::Data<tera::Tera>,
+    query: web::Query<LogQuery>,
+) -> impl Responder {
+    let filter = Filter {
+        user: non_empty(&query.user).and_then(|user| user.parse().ok()),
+        action: non_empty(&query.action),
+        entity: non_empty(&query.entity),
+        entity_id: non_empty(&query.id).and_then(|id| id.parse().ok()),
+    };
+    let page = query.page.unwrap_or(1).max(1);
+    let offset = (page as i64 - 1) * PER_PAGE;
+    let (events, count) = audit::find(&data.pool, &filter, PER_PAGE, offset)
+        .await
+        .unwrap_or_default();
+
+    let entries: Vec<LogEntry> = events
+        .into_iter()
+        .map(|event| LogEntry {
+            changes: changes(event.before.as_deref(), event.after.as_deref()),
+            event,
+        })
+        .collect();
+
+    // Kept when changing pages
+    let old_query = serde_urlencoded::to_string([
+        ("user", filter.user.map(|user| user.to_string()).unwrap_or_default()),
+        ("action", filter.action.unwrap_or_default().to_string()),
+        ("entity", filter.entity.unwrap_or_default().to_string()),
+        ("id", filter.entity_id.map(|id| id.to_string()).unwrap_or_default()),
+    ])
+    .unwrap_or_default();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("entries", &entries);
+    ctx.insert("users", &user::find_all(&data.pool).await.unwrap_or_default());
+    ctx.insert("actions", &audit::ACTIONS);
+    ctx.insert("entities", &audit::ENTITIES);
+    ctx.insert("user", &filter.user);
+    ctx.insert("action", &filter.action);
+    ctx.insert("entity", &filter.entity);
+    ctx.insert("entity_id", &filter.entity_id);
+    ctx.insert(
+        "pages",
+        &Pages {
+            cur: page,
+            total: count / PER_PAGE + if count % PER_PAGE != 0 { 1 } else { 0 },
+        },
+    );
+    ctx.insert("old_query", &old_query);
+
+    let template = tmpl
+        .render("log.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
@@ -0,0 +1,501 @@
+// Users log in with a name and password and get a session cookie, scripts send an API token in an
+// `Authorization: Bearer` header. `check` looks either up on every request and turns away requests
+// to routes that need a higher role or a scope the token does not have.
+
+use std::future::{ready, Ready};
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{error, get, post, web, Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::{HttpResponse, Responder};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{csrf, redirect, AppState};
+use crate::db::api_token::{self, ApiToken, Scope};
+use crate::db::item::Viewer;
+use crate::db::session;
+use crate::db::user::{self, Role, User};
+
+pub const COOKIE: &str = "session";
+const SESSION_DAYS: i64 = 30;
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    name: Option<String>,
+    password: Option<String>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenForm {
+    id: Option<i64>,
+    name: Option<String>,
+    read: Option<String>,
+    upload: Option<String>,
+    tag: Option<String>,
+    admin: Option<String>,
+}
+
+// The logged in user, if any, set by `check`
+pub struct CurrentUser(pub Option<User>);
+
+impl FromRequest for CurrentUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(CurrentUser(req.extensions().get::<User>().cloned())))
+    }
+}
+
+impl CurrentUser {
+    // Admins see every item, other users their own owner-only items and those for logged in users
+    pub fn viewer(&self) -> Viewer {
+        match &self.0 {
+            Some(user) if user.role() == Role::Admin => Viewer {
+                level: Viewer::ADMIN.level,
+                user: Some(user.id),
+            },
+            Some(user) => Viewer {
+                level: 1,
+                user: Some(user.id),
+            },
+            None => Viewer::GUEST,
+        }
+    }
+
+    // Who the audit log records as having made a change
+    pub fn id(&self) -> Option<i64> {
+        self.0.as_ref().map(|user| user.id)
+    }
+}
+
+pub fn hash_password(password: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .ok()
+        .map(|hash| hash.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Role needed for a route, `None` when anyone may use it
+fn required_role(method: &Method, path: &str) -> Option<Role> {
+    if path.starts_with("/admin/") || path.starts_with("/delete/tag/") {
+        Some(Role::Admin)
+    } else if path.starts_with("/upload/")
+        || path.starts_with("/post_upload/")
+        || path.starts_with("/album/")
+        || path.starts_with("/delete/")
+        || path == "/share/"
+        || path.starts_with("/history/")
+        || (path == "/" && method == Method::POST)
+    {
+        Some(Role::Editor)
+    } else {
+        None
+    }
+}
+
+// Scope an API token needs for a route
+fn required_scope(method: &Method, path: &str) -> Scope {
+    if path.starts_with("/admin/") || path.starts_with("/delete/tag/") || path.starts_with("/account/") {
+        Scope::Admin
+    } else if path.starts_with("/upload/")
+        || path.starts_with("/post_upload/")
+        || path.starts_with("/album/")
+        || path.starts_with("/delete/")
+        || path == "/share/"
+    {
+        Scope::Upload
+    } else if (path == "/" && method == Method::POST) || path.starts_with("/history/") {
+        Scope::Tag
+    } else {
+        Scope::Read
+    }
+}
+
+// Lowest role that can use a scope
+fn scope_role(scope: Scope) -> Role {
+    match scope {
+        Scope::Read => Role::Viewer,
+        Scope::Upload | Scope::Tag => Role::Editor,
+        Scope::Admin => Role::Admin,
+    }
+}
+
+// Only redirect back to a page of the board
+fn local_path(next: Option<&str>) -> &str {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") => next,
+        _ => "/",
+    }
+}
+
+pub async fn check(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let data = match req.app_data::<web::Data<AppState>>() {
+        Some(data) => data.clone(),
+        None => return next.call(req).await,
+    };
+
+    // Routes are found by the decoded path, `/%61dmin/` is `/admin/`, so that is the one checked
+    // below rather than `req.path()`
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| hash_token(token.trim()));
+
+    let (user, token) = match bearer {
+        Some(hash) => {
+            let token = match api_token::find_by_hash(&data.pool, &hash).await {
+                Ok(token) => token,
+                Err(_) => return Err(error::ErrorUnauthorized("Invalid API token.")),
+            };
+            let scope = required_scope(req.method(), req.match_info().as_str());
+            if !token.has_scope(scope) {
+                return Err(error::ErrorForbidden(format!(
+                    "This token needs the {} scope.",
+                    scope.as_str()
+                )));
+            }
+            if let Err(err) = api_token::update_last_used(&data.pool, token.id).await {
+                eprintln!("Failed to update API token {}. {:?}", token.id, err);
+            }
+            match user::find_by_id(&data.pool, token.user).await {
+                Ok(user) => (Some(user), Some(token)),
+                Err(_) => return Err(error::ErrorUnauthorized("Invalid API token.")),
+            }
+        }
+        None => match req.cookie(COOKIE) {
+            Some(cookie) => (session::find_user(&data.pool, &hash_token(cookie.value())).await.ok(), None),
+            None => (None, None),
+        },
+    };
+
+    if let Some(role) = required_role(req.method(), req.match_info().as_str()) {
+        match &user {
+            None => {
+                let target = match req.uri().path_and_query() {
+                    Some(path) if req.method() == Method::GET => path.as_str(),
+                    _ => "/",
+                };
+                let query = serde_urlencoded::to_string([("next", target)]).unwrap_or_default();
+                let response = redirect!(format!("/login/?{}", query));
+                return Err(InternalError::from_response("Login required", response).into());
+            }
+            Some(user) if user.role() < role => {
+                return Err(error::ErrorForbidden(format!(
+                    "This page needs the {} role.",
+                    role.as_str()
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(user) = user {
+        req.extensions_mut().insert(user);
+    }
+    // Marks the request as scripted, see `csrf::check`
+    if let Some(token) = token {
+        req.extensions_mut().insert(token);
+    }
+    next.call(req).await
+}
+
+#[get("/login/")]
+pub async fn login_page(
+    tmpl: web::Data<tera::Tera>,
+    query: web::Query<LoginQuery>,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("next", local_path(query.next.as_deref()));
+
+    let template = tmpl
+        .render("login.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+#[post("/login/")]
+pub async fn login(
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    form: web::Form<LoginForm>,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let name = form.name.as_deref().unwrap_or_default().trim();
+    let password = form.password.as_deref().unwrap_or_default();
+    let next = local_path(form.next.as_deref());
+
+    let user = match user::find_by_name(&data.pool, name).await {
+        Ok(user) if verify_password(password, &user.password) => user,
+        _ => {
+            let mut ctx = tera::Context::new();
+            ctx.insert(csrf::FIELD, &csrf.0);
+            ctx.insert("next", next);
+            ctx.insert("name", name);
+            ctx.insert("error", "Wrong name or password.");
+            let template = tmpl
+                .render("login.html", &ctx)
+                .map_err(|_| error::ErrorInternalServerError("Template error"))
+                .unwrap();
+            return HttpResponse::Unauthorized()
+                .content_type("text/html")
+                .body(template);
+        }
+    };
+
+    if let Err(err) = session::delete_expired(&data.pool).await {
+        eprintln!("Failed to delete expired sessions. {:?}", err);
+    }
+    let token = csrf::new_token();
+    if let Err(err) = session::insert(&data.pool, &hash_token(&token), user.id, SESSION_DAYS).await {
+        eprintln!("Failed to create session for {}. {:?}", user.name, err);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let cookie = Cookie::build(COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::days(SESSION_DAYS))
+        .finish();
+    HttpResponse::Found()
+        .cookie(cookie)
+        .append_header(("Location", next))
+        .finish()
+}
+
+#[post("/logout/")]
+pub async fn logout(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    if let Some(cookie) = req.cookie(COOKIE) {
+        if let Err(err) = session::delete_by_token(&data.pool, &hash_token(cookie.value())).await {
+            eprintln!("Failed to delete session. {:?}", err);
+        }
+    }
+
+    let mut cookie = Cookie::build(COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    HttpResponse::Found()
+        .cookie(cookie)
+        .append_header(("Location", "/"))
+        .finish()
+}
+
+fn render_account(
+    tmpl: &tera::Tera,
+    csrf: &csrf::Token,
+    tokens: Vec<ApiToken>,
+    user: &User,
+    new_token: Option<&str>,
+) -> HttpResponse {
+    let scopes: Vec<&str> = Scope::ALL
+        .iter()
+        .filter(|scope| user.role() >= scope_role(**scope))
+        .map(|scope| scope.as_str())
+        .collect();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("user", user);
+    ctx.insert("tokens", &tokens);
+    ctx.insert("scopes", &scopes);
+    ctx.insert("new_token", &new_token);
+
+    let template = tmpl
+        .render("account.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+#[get("/account/")]
+pub async fn account(
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    current: CurrentUser,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let user = match current.0 {
+        Some(user) => user,
+        None => return redirect!("/login/?next=/account/"),
+    };
+    let tokens = api_token::find_by_user(&data.pool, user.id)
+        .await
+        .unwrap_or_default();
+    render_account(&tmpl, &csrf, tokens, &user, None)
+}
+
+// The token itself is only shown on the page this returns
+#[post("/account/tokens/")]
+pub async fn create_token(
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    current: CurrentUser,
+    form: web::Form<TokenForm>,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let user = match current.0 {
+        Some(user) => user,
+        None => return redirect!("/login/?next=/account/"),
+    };
+
+    let checked = [&form.read, &form.upload, &form.tag, &form.admin];
+    let scopes: Vec<&str> = Scope::ALL
+        .iter()
+        .zip(checked)
+        .filter(|(scope, checked)| checked.is_some() && user.role() >= scope_role(**scope))
+        .map(|(scope, _)| scope.as_str())
+        .collect();
+    let name = form.name.as_deref().unwrap_or_default().trim();
+    if name.is_empty() || scopes.is_empty() {
+        return redirect!("/account/");
+    }
+
+    let token = csrf::new_token();
+    if let Err(err) =
+        api_token::insert(&data.pool, user.id, name, &hash_token(&token), &scopes.join(" ")).await
+    {
+        eprintln!("Failed to create API token for {}. {:?}", user.name, err);
+        return redirect!("/account/");
+    }
+    let tokens = api_token::find_by_user(&data.pool, user.id)
+        .await
+        .unwrap_or_default();
+    render_account(&tmpl, &csrf, tokens, &user, Some(&token))
+}
+
+#[post("/account/tokens/revoke/")]
+pub async fn revoke_token(
+    data: web::Data<AppState>,
+    current: CurrentUser,
+    form: web::Form<TokenForm>,
+) -> impl Responder {
+    if let (Some(user), Some(id)) = (current.0, form.id) {
+        if let Err(err) = api_token::delete(&data.pool, id, user.id).await {
+            eprintln!("Failed to revoke API token {}. {:?}", id, err);
+        }
+    }
+    redirect!("/account/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    async fn probe() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    async fn status<S, B>(app: &S, req: TestRequest) -> StatusCode
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = Error>,
+    {
+        match test::try_call_service(app, req.to_request()).await {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    async fn session_of(data: &AppState, name: &str, role: Role) -> Cookie<'static> {
+        let id = user::insert(&data.pool, name, "", role).await.unwrap();
+        let token = csrf::new_token();
+        session::insert(&data.pool, &hash_token(&token), id, 1).await.unwrap();
+        Cookie::new(COOKIE, token)
+    }
+
+    const PATHS: [&str; 3] = ["/admin/probe/", "/%61dmin/probe/", "/%61%64%6D%69%6E/probe/"];
+
+    #[test]
+    fn requires_roles() {
+        assert!(matches!(required_role(&Method::GET, "/admin/users/"), Some(Role::Admin)));
+        assert!(matches!(required_role(&Method::POST, "/"), Some(Role::Editor)));
+        assert!(required_role(&Method::GET, "/").is_none());
+        assert!(matches!(required_scope(&Method::GET, "/account/"), Scope::Admin));
+        assert!(matches!(required_scope(&Method::POST, "/"), Scope::Tag));
+    }
+
+    #[actix_web::test]
+    async fn checks_the_decoded_path() {
+        let data = super::super::test_state(&std::env::temp_dir()).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data.clone()))
+                .wrap(from_fn(check))
+                .route("/admin/probe/", web::get().to(probe)),
+        )
+        .await;
+        let editor = session_of(&data, "editor", Role::Editor).await;
+        let admin = session_of(&data, "admin", Role::Admin).await;
+
+        for path in PATHS {
+            let anonymous = TestRequest::get().uri(path);
+            assert_eq!(status(&app, anonymous).await, StatusCode::FOUND, "{}", path);
+            let as_editor = TestRequest::get().uri(path).cookie(editor.clone());
+            assert_eq!(status(&app, as_editor).await, StatusCode::FORBIDDEN, "{}", path);
+            let as_admin = TestRequest::get().uri(path).cookie(admin.clone());
+            assert_eq!(status(&app, as_admin).await, StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn checks_token_scopes_on_the_decoded_path() {
+        let data = super::super::test_state(&std::env::temp_dir()).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data.clone()))
+                .wrap(from_fn(check))
+                .route("/admin/probe/", web::get().to(probe)),
+        )
+        .await;
+        let id = user::insert(&data.pool, "script", "", Role::Admin).await.unwrap();
+        api_token::insert(&data.pool, id, "read", &hash_token("read-token"), "read").await.unwrap();
+        api_token::insert(&data.pool, id, "admin", &hash_token("admin-token"), "admin").await.unwrap();
+
+        for path in PATHS {
+            let read = TestRequest::get().uri(path).insert_header((header::AUTHORIZATION, "Bearer read-token"));
+            assert_eq!(status(&app, read).await, StatusCode::FORBIDDEN, "{}", path);
+            let admin = TestRequest::get().uri(path).insert_header((header::AUTHORIZATION, "Bearer admin-token"));
+            assert_eq!(status(&app, admin).await, StatusCode::OK, "{}", path);
+        }
+    }
+}
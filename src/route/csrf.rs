@@ -0,0 +1,270 @@
+// Every form posts back a token printed in the page. Other sites cannot read our pages,
+// so a state-changing request with the token was sent from the board itself. The token is
+// derived from the session cookie, or from a visitor cookie for guests, so it only works
+// for whoever it was shown to.
+
+use std::future::{ready, Ready};
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::{error, Error, FromRequest, HttpMessage, HttpRequest};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::{auth, AppState};
+use crate::db::api_token::ApiToken;
+
+pub const FIELD: &str = "csrf_token";
+pub const HEADER: &str = "X-CSRF-Token";
+// Set for guests, who have no session
+const VISITOR_COOKIE: &str = "visitor";
+// Routes that check the `csrf_token` field of their multipart body themselves
+const MULTIPART_ROUTES: [&str; 2] = ["/upload/", "/upload/direct/"];
+
+#[derive(Deserialize)]
+struct TokenField {
+    csrf_token: Option<String>,
+}
+
+// The token of the current request, for the pages to print as `csrf_token`
+pub struct Token(pub String);
+
+impl FromRequest for Token {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Token>()
+                .map(|token| Token(token.0.clone()))
+                .ok_or_else(|| error::ErrorInternalServerError("Missing CSRF token")),
+        )
+    }
+}
+
+// Set by `check` on multipart requests without the header, whose body is streamed to the
+// handler. It checks the `csrf_token` field, which comes before the files, with `accepts`.
+#[derive(Clone)]
+pub struct Unverified(String);
+
+impl Unverified {
+    pub fn accepts(&self, data: &AppState, token: &str) -> bool {
+        verify(&data.csrf_secret, &self.0, token)
+    }
+}
+
+pub fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn mac(secret: &str, subject: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(subject.as_bytes());
+    mac
+}
+
+fn sign(secret: &str, subject: &str) -> String {
+    URL_SAFE_NO_PAD.encode(mac(secret, subject).finalize().into_bytes())
+}
+
+// Compared in constant time
+fn verify(secret: &str, subject: &str, token: &str) -> bool {
+    match URL_SAFE_NO_PAD.decode(token) {
+        Ok(token) => mac(secret, subject).verify_slice(&token).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn from_form(body: &str) -> Option<String> {
+    web::Query::<TokenField>::from_query(body)
+        .ok()
+        .and_then(|field| field.into_inner().csrf_token)
+}
+
+fn content_type(req: &ServiceRequest) -> &str {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+// The token is read from the header or an urlencoded form body. Multipart bodies are left to
+// the handlers of `MULTIPART_ROUTES` and refused elsewhere.
+pub async fn check(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let secret = match req.app_data::<web::Data<AppState>>() {
+        Some(data) => data.csrf_secret.clone(),
+        None => return next.call(req).await,
+    };
+    let session = req
+        .cookie(auth::COOKIE)
+        .or_else(|| req.cookie(VISITOR_COOKIE))
+        .map(|cookie| cookie.value().to_string());
+    let (subject, visitor) = match session {
+        Some(subject) => (subject, None),
+        None => {
+            let visitor = new_token();
+            (visitor.clone(), Some(visitor))
+        }
+    };
+    req.extensions_mut().insert(Token(sign(&secret, &subject)));
+
+    // Browsers do not add an Authorization header on their own, so a request with an API token
+    // was not forged by another site
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if safe || req.extensions().contains::<ApiToken>() {
+        let mut res = next.call(req).await?;
+        if let Some(visitor) = visitor {
+            let cookie = Cookie::build(VISITOR_COOKIE, visitor)
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .finish();
+            if let Err(err) = res.response_mut().add_cookie(&cookie) {
+                eprintln!("Failed to set visitor cookie. {}", err);
+            }
+        }
+        return Ok(res);
+    }
+
+    let mut token = req
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let deferred = *req.method() == Method::POST && MULTIPART_ROUTES.contains(&req.match_info().as_str());
+    if token.is_none() && deferred && content_type(&req).starts_with("multipart/form-data") {
+        req.extensions_mut().insert(Unverified(subject));
+        return next.call(req).await;
+    }
+    if token.is_none() && content_type(&req).starts_with("application/x-www-form-urlencoded") {
+        let body = req.extract::<Bytes>().await?;
+        token = std::str::from_utf8(&body).ok().and_then(from_form);
+        // Put the body back for the handler
+        req.set_payload(Payload::from(body));
+    }
+
+    if !token.is_some_and(|token| verify(&secret, &subject, &token)) {
+        return Err(forbidden());
+    }
+    next.call(req).await
+}
+
+pub fn forbidden() -> Error {
+    error::ErrorForbidden("Invalid or missing CSRF token. Reload the page and try again.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    // Answers whether the middleware left the token to the handler
+    async fn probe(unverified: Option<web::ReqData<Unverified>>) -> HttpResponse {
+        HttpResponse::Ok().body(if unverified.is_some() { "unverified" } else { "verified" })
+    }
+
+    fn multipart(uri: &str, fields: &[(&str, &str)]) -> TestRequest {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+        TestRequest::post()
+            .uri(uri)
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .cookie(Cookie::new(auth::COOKIE, "session"))
+            .set_payload(body)
+    }
+
+    async fn call<S, B>(app: &S, req: TestRequest) -> (StatusCode, String)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = Error>,
+        B: MessageBody,
+    {
+        match test::try_call_service(app, req.to_request()).await {
+            Ok(res) => {
+                let status = res.status();
+                let body = test::read_body(res).await;
+                (status, String::from_utf8_lossy(&body).to_string())
+            }
+            Err(err) => (err.as_response_error().status_code(), String::new()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn defers_multipart_to_upload_routes_only() {
+        let data = super::super::test_state(&std::env::temp_dir()).await;
+        let token = sign(&data.csrf_secret, "session");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data))
+                .wrap(from_fn(check))
+                .route("/upload/", web::post().to(probe))
+                .route("/upload/direct/", web::post().to(probe))
+                .route("/delete/{id}", web::post().to(probe))
+                .route("/logout/", web::post().to(probe)),
+        )
+        .await;
+
+        for uri in ["/upload/", "/upload/direct/", "/%75pload/"] {
+            let (status, body) = call(&app, multipart(uri, &[])).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, "unverified"), "{}", uri);
+        }
+        for uri in ["/delete/1", "/logout/"] {
+            let (status, _) = call(&app, multipart(uri, &[(FIELD, &token)])).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", uri);
+            let (status, body) = call(&app, multipart(uri, &[]).insert_header((HEADER, token.as_str()))).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, "verified"), "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn reads_form_tokens() {
+        let data = super::super::test_state(&std::env::temp_dir()).await;
+        let token = sign(&data.csrf_secret, "session");
+        let other = sign(&data.csrf_secret, "other session");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data))
+                .wrap(from_fn(check))
+                .route("/delete/{id}", web::post().to(probe)),
+        )
+        .await;
+        let form = |token: &str| {
+            TestRequest::post()
+                .uri("/delete/1")
+                .cookie(Cookie::new(auth::COOKIE, "session"))
+                .set_form([(FIELD, token)])
+        };
+
+        assert_eq!(call(&app, form(&token)).await.0, StatusCode::OK);
+        assert_eq!(call(&app, form(&other)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(call(&app, form("")).await.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,361 @@
+// Uploads from a URL: the server downloads the file into `tmp/` and continues with the same
+// tagging step as a single uploaded file, which records the URL as the source of the item.
+
+use actix_web::{post, web, HttpResponse, Responder};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use super::auth::CurrentUser;
+use super::img::is_safe;
+use super::upload::{remove_file, too_large};
+use super::{csrf, guess_file_type, redirect, AppState};
+use crate::db::batch_file::{self, BatchFile};
+use crate::db::item;
+use crate::media::validate;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Between two reads, so large files can take longer in total
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: u32 = 5;
+
+#[derive(Deserialize)]
+pub struct UrlForm {
+    url: Option<String>,
+}
+
+// Extension of files of a content type, for URLs without one
+fn extension_of(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/bmp" => Some("bmp"),
+        "video/mp4" => Some("mp4"),
+        "video/mpeg" => Some("mpg"),
+        "video/webm" => Some("webm"),
+        "video/x-matroska" => Some("mkv"),
+        "video/x-msvideo" => Some("avi"),
+        "video/x-flv" => Some("flv"),
+        _ => None,
+    }
+}
+
+// Name of a downloaded file: the last part of its URL, with an extension from the content type when
+// that is not a supported file
+fn file_name_of(url: &str, content_type: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let last = path.rsplit('/').next().filter(|last| is_safe(last)).unwrap_or("download");
+    if guess_file_type(last) != "unknown" {
+        return Some(last.to_string());
+    }
+    let stem = Path::new(last).file_stem().and_then(|stem| stem.to_str()).unwrap_or(last);
+    Some(format!("{}.{}", stem, extension_of(content_type)?))
+}
+
+// Whether an address is on the internet, not the server itself or a network it is on
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space of carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_global_v4(ipv4);
+    }
+    // NAT64
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b, c, d] = [segments[6] >> 8, segments[6] & 0xff, segments[7] >> 8, segments[7] & 0xff];
+        return is_global_v4(Ipv4Addr::new(a as u8, b as u8, c as u8, d as u8));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local and the former site-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => is_global_v6(ip),
+    }
+}
+
+// Resolves hosts to their addresses on the internet only, so a URL cannot reach the server
+// itself or its network. Redirects are resolved again, and connections go to the addresses
+// that were checked.
+fn resolve_global(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+    let global: Vec<SocketAddr> = addrs.iter().copied().filter(|addr| is_global(addr.ip())).collect();
+    if global.is_empty() && !addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "not a public address"));
+    }
+    Ok(global)
+}
+
+fn is_refused(transport: &ureq::Transport) -> bool {
+    std::error::Error::source(transport)
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+}
+
+// Download `url` to `path`. Returns its md5 and file name, or why it could not be downloaded.
+fn download(url: &str, path: &Path, max_size: Option<u64>) -> Result<(String, String), String> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .redirects(MAX_REDIRECTS)
+        .resolver(resolve_global)
+        .build();
+    save(&agent, url, path, max_size)
+}
+
+fn save(agent: &ureq::Agent, url: &str, path: &Path, max_size: Option<u64>) -> Result<(String, String), String> {
+    let response = agent.get(url).call().map_err(|err| match err {
+        ureq::Error::Status(status, _) => format!("The server answered {}", status),
+        ureq::Error::Transport(transport) if is_refused(&transport) => {
+            "Addresses of the local network cannot be downloaded".to_string()
+        }
+        ureq::Error::Transport(transport) => {
+            eprintln!("Failed to download {}. {}", url, transport);
+            "Could not be downloaded".to_string()
+        }
+    })?;
+
+    let content_type = response.content_type().to_string();
+    let octet_stream = content_type == "application/octet-stream";
+    if !(content_type.starts_with("image/") || content_type.starts_with("video/") || octet_stream) {
+        return Err(format!("Not an image or a video but {}", content_type));
+    }
+    let file_name = match file_name_of(response.get_url(), &content_type) {
+        Some(file_name) => file_name,
+        None => return Err(format!("Unsupported file type {}", content_type)),
+    };
+    let length = response.header("Content-Length").and_then(|length| length.parse::<u64>().ok());
+    if let Some(max_size) = max_size.filter(|max_size| length.is_some_and(|length| length > *max_size)) {
+        return Err(too_large(max_size));
+    }
+
+    let mut f = File::create(path).map_err(|err| {
+        eprintln!("Failed to create {:?}. {}", path, err);
+        "Could not be saved".to_string()
+    })?;
+    let mut reader = response.into_reader();
+    let mut md5 = Md5::new();
+    let mut buffer = [0; 65536];
+    let mut size = 0;
+    loop {
+        let count = reader.read(&mut buffer).map_err(|err| {
+            eprintln!("Failed to download {}. {}", url, err);
+            "Could not be downloaded".to_string()
+        })?;
+        if count == 0 {
+            break;
+        }
+        size += count as u64;
+        if let Some(max_size) = max_size.filter(|max_size| size > *max_size) {
+            return Err(too_large(max_size));
+        }
+        f.write_all(&buffer[..count]).map_err(|err| {
+            eprintln!("Failed to write {:?}. {}", path, err);
+            "Could not be saved".to_string()
+        })?;
+        md5.update(&buffer[..count]);
+    }
+    Ok((format!("{:x}", md5.finalize()), file_name))
+}
+
+#[post("/upload/url/")]
+pub async fn upload_url(
+    data: web::Data<AppState>,
+    form: web::Form<UrlForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    let url = form.url.as_deref().map(str::trim).unwrap_or_default().to_string();
+    let back = |error: &str| {
+        let query = serde_urlencoded::to_string([("error", error), ("url", &url)]).unwrap_or_default();
+        redirect!(format!("/upload/?{}", query))
+    };
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return back("Only http and https URLs can be downloaded");
+    }
+
+    let tmp_dir = data.root_dir.join("tmp");
+    if create_dir_all(&tmp_dir).is_err() {
+        return back("Could not be saved");
+    }
+    let path = tmp_dir.join(format!("{}.download", csrf::new_token()));
+    let (block_url, block_path, max_size) = (url.clone(), path.clone(), data.upload_max_size);
+    let validation = data.validation.clone();
+    let downloaded = web::block(move || {
+        let (md5sum, file_name) = download(&block_url, &block_path, max_size)?;
+        validate::check(&validation, &file_name, &block_path)?;
+        Ok((md5sum, file_name))
+    })
+    .await
+    .unwrap_or_else(|_| Err("Could not be downloaded".to_string()));
+    let (md5sum, file_name) = match downloaded {
+        Ok(downloaded) => downloaded,
+        Err(error) => {
+            if path.exists() {
+                remove_file(&path);
+            }
+            return back(&error);
+        }
+    };
+
+    if let Ok(item) = item::find_by_md5(&data.pool, &md5sum).await {
+        remove_file(&path);
+        return back(&format!("Already uploaded as item {}", item.id));
+    }
+    let ext = Path::new(&file_name).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let real_name = format!("{}.{}", md5sum, ext);
+    if let Err(err) = rename(&path, tmp_dir.join(&real_name)) {
+        eprintln!("Failed to rename {:?}. {}", path, err);
+        remove_file(&path);
+        return back("Could not be saved");
+    }
+    // Only who downloaded it sees it, and it expires with the uploads never added
+    let mut file = BatchFile::new(&csrf::new_token(), current.id(), &file_name, "");
+    file.status = "new".to_string();
+    file.real_name = Some(real_name.clone());
+    file.md5 = Some(md5sum.clone());
+    if let Err(err) = batch_file::insert(&data.pool, &file).await {
+        eprintln!("Failed to save download {}. {:?}", real_name, err);
+        remove_file(&tmp_dir.join(&real_name));
+        return back("Could not be saved");
+    }
+    let query = serde_urlencoded::to_string([
+        ("file_name", file_name.as_str()),
+        ("real_file_name", &real_name),
+        ("md5", &md5sum),
+        ("source", &url),
+    ])
+    .unwrap_or_default();
+    redirect!(format!("/upload/?{}", query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answers one request with `response`, after `delay`. The URL of the file it serves.
+    fn serve(response: &'static [u8], delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                thread::sleep(delay);
+                let _ = stream.write_all(response);
+            }
+        });
+        format!("http://{}/photo.jpg", addr)
+    }
+
+    // Allows the local test server, which `download` refuses
+    fn test_agent() -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout_read(Duration::from_millis(200))
+            .build()
+    }
+
+    // Where a test saves its download
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        temp_dir(&format!("fetch-{}", name)).join("photo.jpg")
+    }
+
+    #[test]
+    fn saves_image() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\nabcd", Duration::ZERO);
+        let path = temp_path("image");
+        let saved = save(&test_agent(), &url, &path, Some(10));
+        assert_eq!(saved, Ok(("e2fc714c4727ee9395f324cd2e7f331f".to_string(), "photo.jpg".to_string())));
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcd");
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn rejects_other_content_types() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 2\r\n\r\nhi", Duration::ZERO);
+        let saved = save(&test_agent(), &url, &temp_path("html"), None);
+        assert_eq!(saved, Err("Not an image or a video but text/html".to_string()));
+    }
+
+    #[test]
+    fn rejects_declared_size() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 100\r\n\r\n", Duration::ZERO);
+        let saved = save(&test_agent(), &url, &temp_path("declared"), Some(10));
+        assert_eq!(saved, Err(too_large(10)));
+    }
+
+    #[test]
+    fn rejects_streamed_size() {
+        let url = serve(
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nConnection: close\r\n\r\n0123456789abcdef",
+            Duration::ZERO,
+        );
+        let path = temp_path("streamed");
+        let saved = save(&test_agent(), &url, &path, Some(10));
+        assert_eq!(saved, Err(too_large(10)));
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn times_out() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\n\r\n", Duration::from_secs(2));
+        let saved = save(&test_agent(), &url, &temp_path("slow"), None);
+        assert_eq!(saved, Err("Could not be downloaded".to_string()));
+    }
+
+    #[test]
+    fn refuses_local_addresses() {
+        let url = serve(b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\nabcd", Duration::ZERO);
+        let refused = Err("Addresses of the local network cannot be downloaded".to_string());
+        assert_eq!(download(&url, &temp_path("local"), None), refused);
+        assert_eq!(download("http://localhost:1/photo.jpg", &temp_path("localhost"), None), refused);
+        assert_eq!(download("http://169.254.169.254/latest/meta-data/", &temp_path("metadata"), None), refused);
+    }
+
+    #[test]
+    fn global_addresses() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0"] {
+            assert!(!is_global(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "64:ff9b::a00:1", "2001:db8::1"] {
+            assert!(!is_global(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111", "::ffff:93.184.216.34"] {
+            assert!(is_global(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Earlier tag sets of an item come from the audit log, which records every change of
+// `tag::update_item_tags` with the tags before and after.
+
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::auth::CurrentUser;
+use super::{redirect, AppState};
+use crate::db::audit::{self, Filter};
+use crate::db::{item, tag};
+
+// Older changes are not shown
+const MAX_VERSIONS: i64 = 50;
+
+#[derive(Deserialize)]
+pub struct RevertForm {
+    event: Option<i64>,
+    version: Option<String>,
+}
+
+// Tags of an item after a change, or before the oldest change shown.
+// `event` and `version` ("before" or "after") identify it when reverting; `current` is set
+// when the item has these tags now.
+#[derive(Serialize)]
+pub struct TagVersion {
+    event: i64,
+    version: &'static str,
+    user_name: Option<String>,
+    created_at: Option<String>,
+    tags: Vec<String>,
+    added: Vec<String>,
+    removed: Vec<String>,
+    current: bool,
+}
+
+fn parse_tags(json: Option<&str>) -> Vec<String> {
+    json.and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+// Newest first
+pub async fn find_versions(pool: &SqlitePool, item_id: i64) -> Vec<TagVersion> {
+    let filter = Filter {
+        action: Some("tag"),
+        entity: Some("item"),
+        entity_id: Some(item_id),
+        ..Default::default()
+    };
+    let (events, count) = audit::find(pool, &filter, MAX_VERSIONS, 0)
+        .await
+        .unwrap_or_default();
+
+    let current = tag::find_names_by_item(pool, item_id).await.unwrap_or_default();
+    let mut versions = Vec::new();
+    let mut oldest = None;
+    for event in events {
+        let before = parse_tags(event.before.as_deref());
+        let after = parse_tags(event.after.as_deref());
+        versions.push(TagVersion {
+            event: event.id,
+            version: "after",
+            user_name: event.user_name,
+            created_at: Some(event.created_at),
+            added: after.iter().filter(|t| !before.contains(t)).cloned().collect(),
+            removed: before.iter().filter(|t| !after.contains(t)).cloned().collect(),
+            current: after == current,
+            tags: after,
+        });
+        oldest = Some((event.id, before));
+    }
+
+    // Tags the item had before it was first changed, like the ones set before the log existed
+    if let Some((event, before)) = oldest {
+        if count <= MAX_VERSIONS && !before.is_empty() {
+            versions.push(TagVersion {
+                event,
+                version: "before",
+                user_name: None,
+                created_at: None,
+                added: Vec::new(),
+                removed: Vec::new(),
+                current: before == current,
+                tags: before,
+            });
+        }
+    }
+    versions
+}
+
+// Sets the tags of the item back to a version, which is recorded as a new change
+#[post("/history/revert/")]
+pub async fn revert(
+    data: web::Data<AppState>,
+    form: web::Form<RevertForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    let event = match form.event {
+        Some(id) => audit::find_by_id(&data.pool, id).await.ok(),
+        None => None,
+    };
+    let event = match event.filter(|event| event.action == "tag" && event.entity == "item") {
+        Some(event) => event,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+    match item::find_by_id(&data.pool, event.entity_id).await {
+        Ok(item) if item.visible_to(&current.viewer()) => {}
+        _ => return HttpResponse::NotFound().body("Not found!"),
+    }
+
+    let tags = match form.version.as_deref() {
+        Some("before") => parse_tags(event.before.as_deref()),
+        _ => parse_tags(event.after.as_deref()),
+    };
+    let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+    if let Err(err) = tag::update_item_tags(&data.pool, event.entity_id, tags, current.id()).await {
+        eprintln!("Failed to revert tags of item {}. {:?}", event.entity_id, err);
+    }
+    redirect!(format!("/?id={}", event.entity_id))
+}
@@ -0,0 +1,150 @@
+// Files under `root` are only served for indexed items the viewer can see: the original, its
+// thumbnail and its rendition. Other files, like dotfiles or anything not reloaded yet, are not served.
+
+use actix_files::NamedFile;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{route, web, HttpRequest, HttpResponse, Responder};
+use std::path::{Component, Path};
+
+use super::auth::CurrentUser;
+use super::AppState;
+use crate::db::batch_file;
+use crate::db::item::{self, Item};
+use crate::db::transcode;
+use crate::db::user::Role;
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("Not found!")
+}
+
+// Relative, without `..` or hidden files and folders
+pub fn is_safe(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path).components().all(|component| match component {
+            Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+            _ => false,
+        })
+}
+
+// Content type, ETag, Last-Modified and Range come from NamedFile
+async fn serve(req: &HttpRequest, file_path: &Path, cache_control: &'static str) -> HttpResponse {
+    if !file_path.is_file() {
+        return not_found();
+    }
+    match NamedFile::open_async(file_path).await {
+        Ok(file) => {
+            let mut response = file.into_response(req);
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+            response
+        }
+        Err(_) => not_found(),
+    }
+}
+
+// Items in the trash are only shown to admins
+fn can_see(current: &CurrentUser, item: &Item) -> bool {
+    let is_admin = current
+        .0
+        .as_ref()
+        .is_some_and(|user| user.role() == Role::Admin);
+    item.visible_to(&current.viewer()) && (item.deleted_at.is_none() || is_admin)
+}
+
+// Shared caches may only keep files everyone can see
+fn cache_control(item: &Item) -> &'static str {
+    if item.access == 0 && item.deleted_at.is_none() {
+        "public, max-age=86400"
+    } else {
+        "private, max-age=86400"
+    }
+}
+
+#[route("/img/id/{id}", method = "GET", method = "HEAD")]
+pub async fn by_id(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+    current: CurrentUser,
+) -> impl Responder {
+    match item::find_by_id(&data.pool, id.into_inner()).await {
+        Ok(item) if can_see(&current, &item) => {
+            serve(&req, &data.root_dir.join(&item.path), cache_control(&item)).await
+        }
+        _ => not_found(),
+    }
+}
+
+#[route("/img/id/{id}/thumbnail", method = "GET", method = "HEAD")]
+pub async fn thumbnail_by_id(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+    current: CurrentUser,
+) -> impl Responder {
+    match item::find_by_id(&data.pool, id.into_inner()).await {
+        Ok(item) if can_see(&current, &item) => {
+            let thumbnail = data.thumbnail_dir.join(format!("{}.jpg", item.path));
+            serve(&req, &thumbnail, cache_control(&item)).await
+        }
+        _ => not_found(),
+    }
+}
+
+// `<item path>`, `thumbnail/<item path>.jpg`, `derived/<rendition path>` or, for the editor
+// who sent it, `tmp/<file>` of an upload waiting for its details
+#[route("/img/{path:.*}", method = "GET", method = "HEAD")]
+pub async fn by_path(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    current: CurrentUser,
+) -> impl Responder {
+    let path = path.into_inner();
+    if !is_safe(&path) {
+        return not_found();
+    }
+
+    if let Some(name) = path.strip_prefix("tmp/") {
+        let is_editor = current
+            .0
+            .as_ref()
+            .is_some_and(|user| user.role() >= Role::Editor);
+        if !is_editor || name.contains('/') {
+            return not_found();
+        }
+        if batch_file::find_new_by_real_name(&data.pool, name, current.id()).await.is_err() {
+            return not_found();
+        }
+        return serve(&req, &data.root_dir.join(&path), "no-store").await;
+    }
+
+    let (found, file_path) = if let Some(thumbnail) = path.strip_prefix("thumbnail/") {
+        let item_path = match thumbnail.strip_suffix(".jpg") {
+            Some(item_path) => item_path,
+            None => return not_found(),
+        };
+        let found = item::find_by_path(&data.pool, item_path).await;
+        (found, data.thumbnail_dir.join(thumbnail))
+    } else if let Some(derived) = path.strip_prefix("derived/") {
+        let found = match transcode::find_by_file(&data.pool, derived).await {
+            Ok(rendition) => item::find_by_id(&data.pool, rendition.item).await,
+            Err(_) => return not_found(),
+        };
+        (found, data.derived_dir.join(derived))
+    } else {
+        let found = item::find_by_path(&data.pool, &path).await;
+        (found, data.root_dir.join(&path))
+    };
+
+    let item = match found {
+        Ok(item) if item.file_type != "folder" || path.starts_with("thumbnail/") => item,
+        _ => return not_found(),
+    };
+    if !can_see(&current, &item) {
+        return not_found();
+    }
+
+    serve(&req, &file_path, cache_control(&item)).await
+}
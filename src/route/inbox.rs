@@ -0,0 +1,203 @@
+// Files dropped in the `inbox` folder are added without anyone uploading them: each one is hashed,
+// moved to an album under the root named from `inbox_album`, added with the tags of its sidecar
+// `.txt` file and thumbnailed, then removed from the inbox. Duplicates of items are only removed.
+
+use async_std::task;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+use super::img::is_safe;
+use super::upload::{add_item, find_or_create_folder, hash_file, remove_file, Origin};
+use super::{create_thumbnail, guess_file_type, AppState};
+use crate::db::item::{self, Viewer};
+use crate::media::probe;
+use crate::media::store::civil_date;
+
+// Seconds between two looks at the inbox
+const INTERVAL: u64 = 60;
+
+// Files changed more recently may still be copied in, they wait for the next look
+const SETTLE_SECS: u64 = 30;
+
+fn modified_secs(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+// Year, month and day the file was taken, from its metadata or else the file time
+fn taken_date(path: &Path, file_type: &str) -> Option<(String, String, String)> {
+    let captured_at = probe::metadata(path.to_str()?, file_type).and_then(|meta| meta.captured_at);
+    if let Some(date) = captured_at {
+        return Some((date.get(..4)?.to_string(), date.get(5..7)?.to_string(), date.get(8..10)?.to_string()));
+    }
+    let (year, month, day) = civil_date(modified_secs(path)? as i64);
+    Some((year.to_string(), format!("{:02}", month), format!("{:02}", day)))
+}
+
+// Album of a file from a pattern with `{year}`, `{month}` and `{day}` it was taken, its `{type}`
+// and the `{folder}` it was in under the inbox. Empty for the root folder.
+fn album_path(pattern: &str, date: &(String, String, String), file_type: &str, folder: &str) -> String {
+    let path = pattern
+        .replace("{year}", &date.0)
+        .replace("{month}", &date.1)
+        .replace("{day}", &date.2)
+        .replace("{type}", file_type)
+        .replace("{folder}", folder);
+    path.split('/')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+// Tags of a file, one or more per line, from `a.jpg.txt` or `a.txt` next to it
+fn find_sidecar(path: &Path) -> Option<PathBuf> {
+    let mut with_ext = path.as_os_str().to_owned();
+    with_ext.push(".txt");
+    [PathBuf::from(with_ext), path.with_extension("txt")]
+        .iter()
+        .find(|sidecar| sidecar.is_file())
+        .cloned()
+}
+
+// The inbox may be on another file system than the root
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+async fn add_file(data: &AppState, inbox_dir: &Path, path: &Path, pattern: &str, created: &mut Vec<String>) {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_string(),
+        None => return,
+    };
+    let file_type = guess_file_type(&name);
+    let md5sum = match hash_file(path) {
+        Some(md5sum) => md5sum,
+        None => return,
+    };
+    let sidecar = find_sidecar(path);
+
+    if let Ok(item) = item::find_by_md5(&data.pool, &md5sum).await {
+        println!("{:?} is already item {}, removed from the inbox", path, item.id);
+        remove_file(path);
+        if let Some(sidecar) = sidecar {
+            remove_file(&sidecar);
+        }
+        return;
+    }
+
+    let folder = path
+        .parent()
+        .and_then(|parent| parent.strip_prefix(inbox_dir).ok())
+        .and_then(|folder| folder.to_str())
+        .unwrap_or_default();
+    let date = match taken_date(path, file_type) {
+        Some(date) => date,
+        None => return,
+    };
+    let album_path = album_path(pattern, &date, file_type, folder);
+    let album = if album_path.is_empty() {
+        None
+    } else if is_safe(&album_path) {
+        match find_or_create_folder(data, None, &album_path, None, &Viewer::ADMIN, created).await {
+            Some(album) => Some(album),
+            None => {
+                eprintln!("Failed to add {:?}, {} is not an album", path, album_path);
+                return;
+            }
+        }
+    } else {
+        eprintln!("Failed to add {:?}, {} is not a valid album", path, album_path);
+        return;
+    };
+
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let real_name = format!("{}.{}", md5sum, ext);
+    let tmp_file = data.root_dir.join("tmp").join(&real_name);
+    if let Err(err) = fs::create_dir_all(data.root_dir.join("tmp")).and_then(|_| move_file(path, &tmp_file)) {
+        eprintln!("Failed to move {:?} to {:?}. {}", path, tmp_file, err);
+        return;
+    }
+    let tags = sidecar
+        .as_ref()
+        .and_then(|sidecar| fs::read_to_string(sidecar).ok())
+        .unwrap_or_default();
+    let tags: Vec<&str> = tags.split_whitespace().collect();
+    let origin = Origin {
+        original_name: &name,
+        uploader: None,
+        method: "inbox",
+    };
+    if add_item(data, &real_name, &name, &md5sum, album.as_ref(), tags, origin).await.is_none() {
+        // Back to the inbox for the next look
+        if let Err(err) = move_file(&tmp_file, path) {
+            eprintln!("Failed to move {:?} back to the inbox. {}", tmp_file, err);
+        }
+        return;
+    }
+    if let Some(sidecar) = sidecar {
+        remove_file(&sidecar);
+    }
+}
+
+// Add the files of the inbox that are done being copied in. Unsupported files are left there.
+pub async fn scan(data: &AppState, inbox_dir: &Path, pattern: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default();
+    let files: Vec<PathBuf> = WalkDir::new(inbox_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            guess_file_type(name) != "unknown"
+                && modified_secs(path).is_some_and(|modified| modified + SETTLE_SECS <= now)
+        })
+        .collect();
+
+    let mut created = Vec::new();
+    for path in files {
+        add_file(data, inbox_dir, &path, pattern, &mut created).await;
+    }
+
+    for folder in created {
+        create_thumbnail(
+            data.root_dir.to_str().unwrap(),
+            data.thumbnail_dir.to_str().unwrap(),
+            data.root_dir.join(&folder).to_str().unwrap(),
+            "folder",
+            true,
+        );
+    }
+
+    // Folders emptied in the inbox, deepest first
+    let entries = WalkDir::new(inbox_dir).min_depth(1).contents_first(true);
+    for entry in entries.into_iter().filter_map(|entry| entry.ok()) {
+        if entry.file_type().is_dir() {
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+}
+
+pub fn spawn_worker(data: AppState, inbox_dir: PathBuf, pattern: String) {
+    thread::spawn(move || {
+        task::block_on(async {
+            loop {
+                scan(&data, &inbox_dir, &pattern).await;
+                task::sleep(Duration::from_secs(INTERVAL)).await;
+            }
+        })
+    });
+}
@@ -1,13 +1,18 @@
 use actix_web::{error, get, web, HttpResponse, Responder};
 use serde::Serialize;
+use std::collections::HashMap;
 
-use super::{AppState, QueryInfo};
-use crate::db::{item, tag};
+use super::auth::CurrentUser;
+use super::history;
+use super::{csrf, AppState, QueryInfo};
+use crate::db::user::Role;
+use crate::db::{color, item, item_source, metadata, metatag, tag, transcode, user};
+use crate::media;
 
 #[derive(Serialize)]
-struct Pages {
-    cur: u32,
-    total: i64,
+pub struct Pages {
+    pub cur: u32,
+    pub total: i64,
 }
 
 #[get("/")]
@@ -15,9 +20,15 @@ pub async fn index(
     tmpl: web::Data<tera::Tera>,
     data: web::Data<AppState>,
     query: web::Query<QueryInfo>,
+    current: CurrentUser,
+    csrf: csrf::Token,
+    phashes: web::Data<media::phash::Cache>,
 ) -> impl Responder {
+    let viewer = current.viewer();
+
     // context to pass data to html template
     let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
     ctx.insert("listview", &false);
 
     // query to pass to next URL
@@ -27,13 +38,13 @@ pub async fn index(
     let mut page_tags;
 
     // All tags and its count
-    let all_tags = tag::count_tags(&data.pool).await.unwrap_or_default();
+    let all_tags = tag::count_tags(&data.pool, &viewer).await.unwrap_or_default();
     ctx.insert("tags", &all_tags);
 
     // Show original item instead of thumbnail
     let raw = query.raw.unwrap_or_default();
     ctx.insert("raw", &raw);
-    old_query.push(format!("raw={}", raw));
+    old_query.push(("raw", raw.to_string()));
 
     // Items to show
     let mut items = Vec::new();
@@ -43,11 +54,11 @@ pub async fn index(
 
     // View mode
     let view = query.view.as_deref().unwrap_or_default();
-    old_query.push(format!("view={}", view));
+    old_query.push(("view", view.to_string()));
     ctx.insert("view", &view);
 
     // List of folders
-    let folders = item::find_by_type(&data.pool, "folder")
+    let folders = item::find_by_type(&data.pool, "folder", &viewer)
         .await
         .unwrap_or(vec![]);
     ctx.insert("folders", &folders);
@@ -63,9 +74,9 @@ pub async fn index(
 
     let id = query.id.unwrap_or_default();
     if id > 0 {
-        old_query.push(format!("id={}", id));
+        old_query.push(("id", id.to_string()));
         match item::find_by_id(&data.pool, id).await {
-            Ok(item) => {
+            Ok(item) if item.deleted_at.is_none() && item.visible_to(&viewer) => {
                 parent = item.parent.unwrap_or_default();
                 page_tags = tag::find_by_items(&data.pool, vec![id])
                     .await
@@ -82,10 +93,53 @@ pub async fn index(
 
                 if item.file_type == "folder" {
                     (items, count) =
-                        item::find_by_parent(&data.pool, Some(id), Some(data.ipp), Some(offset))
-                            .await
-                            .unwrap_or_default();
+                        item::find_by_parent(
+                            &data.pool,
+                            Some(id),
+                            &viewer,
+                            data.order,
+                            Some(data.ipp),
+                            Some(offset),
+                        )
+                        .await
+                        .unwrap_or_default();
                 } else {
+                    if let Ok(rendition) = transcode::find_by_item(&data.pool, id).await {
+                        ctx.insert("rendition", &rendition);
+                    }
+                    if let Ok(metadata) = metadata::find_by_item(&data.pool, id).await {
+                        ctx.insert("metadata", &metadata);
+                    }
+                    let sources = item_source::find_by_item(&data.pool, id).await.unwrap_or_default();
+                    ctx.insert("sources", &sources);
+                    if let Some(uploader) = item.uploader {
+                        if let Ok(uploader) = user::find_by_id(&data.pool, uploader).await {
+                            ctx.insert("uploader", &uploader.name);
+                        }
+                    }
+                    let hashes = phashes.hashes(&data.pool).await;
+                    let similar: Vec<i64> =
+                        media::phash::similar_to(&hashes, id, data.similar_threshold)
+                            .into_iter()
+                            .map(|(item, _)| item)
+                            .collect();
+                    if !similar.is_empty() {
+                        let mut similar_items =
+                            item::find_by_ids(&data.pool, similar.clone(), &viewer)
+                                .await
+                                .unwrap_or_default();
+                        similar_items.sort_by_key(|i| similar.iter().position(|s| *s == i.id));
+                        ctx.insert("similar_items", &similar_items);
+                    }
+                    // Only editors can revert, and the history shows who changed what
+                    let is_editor = current
+                        .0
+                        .as_ref()
+                        .is_some_and(|user| user.role() >= Role::Editor);
+                    if is_editor {
+                        let versions = history::find_versions(&data.pool, id).await;
+                        ctx.insert("tag_versions", &versions);
+                    }
                     ctx.insert("parent", &parent);
                     let template = tmpl
                         .render("post.html", &ctx)
@@ -94,6 +148,7 @@ pub async fn index(
                     return HttpResponse::Ok().content_type("text/html").body(template);
                 }
             }
+            Ok(_) => return HttpResponse::Ok().body("Not found!"),
             Err(err) => {
                 println!("Cannot find item: {:?}", err);
                 return HttpResponse::Ok().body("Not found!");
@@ -102,19 +157,24 @@ pub async fn index(
     } else {
         // tags that will be searched for
         let searching_tags_str = query.tags.as_deref().unwrap_or_default();
-        let searching_tags: Vec<String> = searching_tags_str
-            .split_whitespace()
-            .map(str::to_lowercase)
-            .collect();
-
-        if searching_tags.len() > 0 {
-            old_query.push(format!("tags={}", searching_tags_str));
-            (items, count) = item::find_by_tag(&data.pool, searching_tags, data.ipp, offset)
-                .await
-                .unwrap_or_default();
+        let (searching_tags, metatags) = metatag::parse(searching_tags_str, data.order);
+
+        if !searching_tags.is_empty() || !metatags.is_empty() {
+            old_query.push(("tags", searching_tags_str.to_string()));
+            (items, count) = item::find_by_tag(
+                &data.pool,
+                searching_tags,
+                metatags,
+                &viewer,
+                data.order,
+                data.ipp,
+                offset,
+            )
+            .await
+            .unwrap_or_default();
         } else {
             // Find all items that not in a series
-            (items, count) = item::find_not_in_series(&data.pool, data.ipp, offset)
+            (items, count) = item::find_not_in_series(&data.pool, &viewer, data.order, data.ipp, offset)
                 .await
                 .unwrap_or_default();
         }
@@ -132,8 +192,29 @@ pub async fn index(
     };
     ctx.insert("pages", &pages);
 
+    let item_metadata: HashMap<i64, metadata::Metadata> =
+        metadata::find_by_items(&data.pool, items.iter().map(|i| i.id).collect())
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|m| (m.item, m))
+            .collect();
+    ctx.insert("item_metadata", &item_metadata);
+
+    let mut item_colors: HashMap<i64, Vec<color::Color>> = HashMap::new();
+    for c in color::find_by_items(&data.pool, items.iter().map(|i| i.id).collect())
+        .await
+        .unwrap_or_default()
+    {
+        item_colors.entry(c.item).or_default().push(c);
+    }
+    ctx.insert("item_colors", &item_colors);
+
     ctx.insert("items", &items);
-    ctx.insert("old_query", &old_query.join("&"));
+    ctx.insert(
+        "old_query",
+        &serde_urlencoded::to_string(&old_query).unwrap_or_default(),
+    );
     ctx.insert("item_id", &id);
     ctx.insert("parent", &parent); // TODO: In template, get parent from item instead
     ctx.insert("page_tags", &page_tags);
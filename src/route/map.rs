@@ -0,0 +1,54 @@
+use actix_web::{error, get, web, HttpResponse, Responder};
+use serde::Serialize;
+
+use super::auth::CurrentUser;
+use super::{AppState, QueryInfo};
+use crate::db::item::{self, Cluster};
+use crate::db::metatag::{self, KM_PER_DEGREE};
+
+#[derive(Serialize)]
+struct Clusters {
+    radius: f64,
+    clusters: Vec<Cluster>,
+}
+
+#[get("/map/")]
+pub async fn map(
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    query: web::Query<QueryInfo>,
+) -> impl Responder {
+    let mut ctx = tera::Context::new();
+    ctx.insert("raw", &query.raw.unwrap_or_default());
+    ctx.insert("search", query.tags.as_deref().unwrap_or_default());
+    ctx.insert("tile_url", &data.tile_url);
+
+    let template = tmpl
+        .render("map.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+// Clusters for a zoom level of the map, about a quarter of a 256px tile wide
+#[get("/map/clusters/")]
+pub async fn clusters(
+    data: web::Data<AppState>,
+    query: web::Query<QueryInfo>,
+    current: CurrentUser,
+) -> impl Responder {
+    let zoom = query.zoom.unwrap_or_default().min(20);
+    let cell = 360.0 / 2f64.powi(zoom as i32) / 4.0;
+    let (tags, metatags) = metatag::parse(query.tags.as_deref().unwrap_or_default(), data.order);
+
+    match item::cluster_by_location(&data.pool, tags, metatags, &current.viewer(), cell).await {
+        Ok(clusters) => HttpResponse::Ok().json(Clusters {
+            radius: cell * KM_PER_DEGREE,
+            clusters,
+        }),
+        Err(err) => {
+            eprintln!("Failed to cluster items. {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -1,10 +1,12 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::{post, web, HttpResponse, Responder};
 use serde::Deserialize;
-use std::fs::{create_dir_all, rename};
 use std::path::{Path, PathBuf};
 
+use super::auth::CurrentUser;
 use super::{redirect, AppState};
-use crate::db::{item, tag};
+use crate::db::item::Visibility;
+use crate::db::{item, item_source, tag};
+use crate::media::store;
 
 #[derive(Deserialize)]
 pub struct PostData {
@@ -14,91 +16,221 @@ pub struct PostData {
     pub(crate) real_name: Option<String>,
     pub(crate) parent: Option<String>,
     pub(crate) md5: Option<String>,
+    pub(crate) source: Option<String>,
+    pub(crate) original_name: Option<String>,
+    // Source URLs, one per line
+    sources: Option<String>,
+    taken_at: Option<String>,
+    visibility: Option<String>,
+}
+
+// datetime-local inputs send `2019-05-04T12:30` or `2019-05-04T12:30:00`; stored as `2019-05-04 12:30:00`
+fn parse_taken_at(value: &str) -> Option<String> {
+    let mut taken_at = value.trim().replacen('T', " ", 1);
+    if taken_at.len() == 16 {
+        taken_at.push_str(":00");
+    }
+    let layout = b"0000-00-00 00:00:00";
+    if taken_at.len() != layout.len()
+        || taken_at
+            .bytes()
+            .zip(layout.iter())
+            .any(|(c, &l)| if l == b'0' { !c.is_ascii_digit() } else { c != l })
+    {
+        return None;
+    }
+    let field = |from: usize, to: usize| taken_at[from..to].parse::<u32>().unwrap_or_default();
+    let (year, month, day) = (field(0, 4), field(5, 7), field(8, 10));
+    let days = match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if day < 1 || day > days || field(11, 13) > 23 || field(14, 16) > 59 || field(17, 19) > 59 {
+        return None;
+    }
+    Some(taken_at)
 }
 
 #[post("/")]
 pub async fn item_update(
     data: web::Data<AppState>,
     postdata: web::Form<PostData>,
+    current: CurrentUser,
 ) -> impl Responder {
-    if let Some(id) = postdata.id {
-        if let Ok(mut item) = item::find_by_id(&data.pool, id).await {
-            if let Some(_tags) = &postdata.tags {
-                let tags: Vec<&str> = _tags.split_whitespace().collect();
-                tag::update_item_tags(&data.pool, id, tags).await;
+    let viewer = current.viewer();
+    let id = match postdata.id {
+        Some(id) => id,
+        None => return redirect!("/"),
+    };
+    let found = item::find_by_id(&data.pool, id).await.ok();
+    let mut item = match found.filter(|found| found.visible_to(&viewer)) {
+        Some(item) => item,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+
+    // Everything is checked before anything is written. Fields that are not sent are left as they are.
+    let name = match postdata.name.as_deref().map(str::trim) {
+        Some("") => return HttpResponse::BadRequest().body("Name must not be empty"),
+        name => name,
+    };
+    // Empty to follow the album again
+    let visibility = match postdata.visibility.as_deref().map(str::trim) {
+        None => None,
+        Some("") => Some(None),
+        Some(level) => match level.parse().ok().and_then(Visibility::from_level) {
+            Some(visibility) => Some(Some(visibility)),
+            None => return HttpResponse::BadRequest().body("Invalid visibility"),
+        },
+    };
+    // Empty to clear the date
+    let taken_at = match postdata.taken_at.as_deref().map(str::trim) {
+        None => None,
+        Some("") => Some(None),
+        Some(value) => match parse_taken_at(value) {
+            Some(taken_at) => Some(Some(taken_at)),
+            None => return HttpResponse::BadRequest().body("Invalid date"),
+        },
+    };
+    let new_parent = match postdata.parent.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(parent) => {
+            let parent_id = match parent.parse::<i64>() {
+                Ok(parent_id) => parent_id,
+                Err(_) => return HttpResponse::BadRequest().body("Invalid album"),
+            };
+            // Only into albums the user can see
+            match item::find_by_id(&data.pool, parent_id).await {
+                Ok(new_parent)
+                    if new_parent.file_type == "folder" && new_parent.visible_to(&viewer) =>
+                {
+                    Some(new_parent)
+                }
+                _ => return HttpResponse::NotFound().body("Album not found"),
             }
+        }
+    };
+    // An album moved under itself would be its own ancestor
+    if let Some(new_parent) = &new_parent {
+        match item::is_inside(&data.pool, new_parent.id, item.id).await {
+            Ok(false) => {}
+            Ok(true) => return HttpResponse::BadRequest().body("An album cannot be moved into itself"),
+            Err(err) => {
+                eprintln!("Failed to find the albums above {}. {:?}", new_parent.id, err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
 
-            let name = postdata.name.as_ref().unwrap();
-            let parent = postdata.parent.as_ref().unwrap();
-            if let Ok(parent_id) = parent.parse::<i64>() {
-                if let Ok(new_parent) = item::find_by_id(&data.pool, parent_id).await {
-                    let new_parent_path = PathBuf::from(&new_parent.path);
-                    let item_path = Path::new(&item.path);
-                    let src_file = data.root_dir.join(item_path);
-                    let mut dest_file = PathBuf::new();
-                    if let Some(parent_id) = item.parent {
-                        if let Ok(old_parent) = item::find_by_id(&data.pool, parent_id).await {
-                            let old_parent_path = PathBuf::from(&old_parent.path);
-                            dest_file =
-                                src_file.strip_prefix(&data.root_dir).unwrap().to_path_buf();
-                            dest_file = dest_file
-                                .strip_prefix(old_parent_path)
-                                .unwrap()
-                                .to_path_buf();
-                        }
-                    } else {
-                        dest_file = src_file.strip_prefix(&data.root_dir).unwrap().to_path_buf();
-                    }
-                    let prefix = data.root_dir.join(new_parent_path);
-                    dest_file = prefix.join(dest_file);
+    if let Some(_tags) = &postdata.tags {
+        let tags: Vec<&str> = _tags.split_whitespace().collect();
+        if let Err(err) = tag::update_item_tags(&data.pool, id, tags, current.id()).await {
+            eprintln!("Failed to update tags of item {}. {:?}", id, err);
+        }
+    }
 
-                    let mut new_path = PathBuf::from(&item.path);
-                    if src_file != dest_file {
-                        if let Err(err) = rename(src_file, &dest_file) {
-                            eprintln!("Failed to move item {}. {}", item.id, err);
-                        } else {
-                            new_path = dest_file
-                                .strip_prefix(&data.root_dir)
-                                .unwrap()
-                                .to_path_buf();
-                            let src_thumb = format!(
-                                "{}/{}.jpg",
-                                data.thumbnail_dir.to_str().unwrap(),
-                                item.path
-                            );
-                            let dest_thumb = format!(
-                                "{}/{}.jpg",
-                                data.thumbnail_dir.to_str().unwrap(),
-                                new_path.to_str().unwrap()
-                            );
-                            let thumb_parent_path = format!(
-                                "{}/{}",
-                                data.thumbnail_dir.to_str().unwrap(),
-                                new_parent.path
-                            );
-                            let thumb_parent = Path::new(&thumb_parent_path);
-                            if !thumb_parent.exists() {
-                                create_dir_all(&thumb_parent);
-                            }
+    if let Some(taken_at) = taken_at {
+        if taken_at != item.taken_at {
+            if let Err(err) = item::update_taken_at(&data.pool, id, taken_at.as_deref()).await {
+                eprintln!("Failed to update date of item {}. {:?}", id, err);
+            }
+        }
+    }
 
-                            rename(src_thumb, dest_thumb);
-                        }
-                    }
-                    item.name = name.to_string();
-                    item.parent = Some(parent_id);
-                    item.path = new_path.to_str().unwrap_or("").to_string();
-                    item::update(&data.pool, item).await;
-                }
+    if let Some(original_name) = &postdata.original_name {
+        let original_name = Some(original_name.trim()).filter(|name| !name.is_empty());
+        if original_name != item.original_name.as_deref() {
+            if let Err(err) = item::update_original_name(&data.pool, id, original_name).await {
+                eprintln!("Failed to update original name of item {}. {:?}", id, err);
+            }
+        }
+    }
+
+    if let Some(sources) = &postdata.sources {
+        let mut urls: Vec<&str> = Vec::new();
+        for url in sources.lines().map(str::trim).filter(|url| !url.is_empty()) {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        if let Err(err) = item_source::update_item_sources(&data.pool, id, &urls).await {
+            eprintln!("Failed to update sources of item {}. {:?}", id, err);
+        }
+    }
+
+    if let Some(visibility) = visibility {
+        if visibility.map(|v| v.level()) != item.visibility {
+            if let Err(err) = item::update_visibility(&data.pool, id, visibility, viewer.user).await {
+                eprintln!("Failed to update visibility of item {}. {:?}", id, err);
             }
         }
+    }
+
+    let moved = new_parent.as_ref().is_some_and(|new_parent| item.parent != Some(new_parent.id));
+    let renamed = name.is_some_and(|name| name != item.name);
+    if !moved && !renamed {
         return redirect!(format!("/?id={}", id));
     }
+    if let Some(new_parent) = new_parent.filter(|_| moved) {
+        let new_parent_path = PathBuf::from(&new_parent.path);
+        let item_path = Path::new(&item.path);
+        // Path of the file under its album. Files that `store_path` does not place in
+        // their album stay where they are.
+        let follows_album = item.file_type == "folder" || data.store_template.contains("{album}");
+        let mut rel_path = None;
+        if follows_album {
+            rel_path = match item.parent {
+                Some(parent_id) => match item::find_by_id(&data.pool, parent_id).await {
+                    Ok(old_parent) => item_path.strip_prefix(&old_parent.path).ok(),
+                    Err(_) => None,
+                },
+                None => Some(item_path),
+            };
+        }
+        let dest_path = match rel_path {
+            Some(rel_path) if new_parent_path.join(rel_path) != item_path => {
+                let dest_path = new_parent_path.join(rel_path);
+                Some(store::free_path(&data.root_dir, dest_path.to_str().unwrap_or_default(), Some(&item.path)))
+            }
+            _ => None,
+        };
 
-    redirect!("/")
+        if let Some(dest_path) = dest_path.filter(|dest_path| *dest_path != item.path) {
+            match store::move_files(&data.pool, &data.root_dir, &item, &dest_path).await {
+                Ok(()) => item.path = dest_path,
+                Err(err) => eprintln!("Failed to move item {}. {}", item.id, err),
+            }
+        }
+        item.parent = Some(new_parent.id);
+    }
+    if let Some(name) = name {
+        item.name = name.to_string();
+    }
+    if let Err(err) = item::update(&data.pool, item, current.id()).await {
+        eprintln!("Failed to update item {}. {:?}", id, err);
+    }
+    redirect!(format!("/?id={}", id))
 }
 
-#[get("/delete/{id}")]
-pub async fn delete(data: web::Data<AppState>, id: web::Path<i64>) -> impl Responder {
-    item::delete_item(&data.pool, id.into_inner(), data.root_dir.to_str().unwrap()).await;
+#[post("/delete/{id}")]
+pub async fn delete(
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+    current: CurrentUser,
+) -> impl Responder {
+    let found = item::find_by_id(&data.pool, id.into_inner()).await.ok();
+    let item = match found.filter(|found| found.visible_to(&current.viewer())) {
+        Some(item) => item,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+    item::trash_item(
+        &data.pool,
+        item.id,
+        data.root_dir.to_str().unwrap(),
+        current.id(),
+    )
+    .await;
     redirect!("/")
 }
@@ -0,0 +1,350 @@
+// Share links show an item, or an album and everything under it, without an account.
+// Files are served through the link so they do not depend on the item's visibility.
+
+use actix_files::NamedFile;
+use actix_web::cookie::Cookie;
+use actix_web::http::header::{ContentDisposition, DispositionType};
+use actix_web::{error, get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use super::auth::{hash_password, hash_token, verify_password, CurrentUser};
+use super::index::Pages;
+use super::{csrf, redirect, AppState};
+use crate::db::item::{self, Item, Viewer};
+use crate::db::share::{self, Share};
+use crate::db::{transcode, user};
+
+// Albums nested deeper than this are not looked up when checking that an item is shared
+const MAX_DEPTH: usize = 64;
+// Links expire after ten years at most
+const MAX_DAYS: i64 = 3650;
+
+#[derive(Deserialize)]
+pub struct ShareForm {
+    id: Option<i64>,
+    days: Option<String>,
+    password: Option<String>,
+    download: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ShareQuery {
+    id: Option<i64>,
+    page: Option<u32>,
+    download: Option<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ShareEntry {
+    share: Share,
+    item: Item,
+    created_by: Option<String>,
+}
+
+fn cookie_name(share: &Share) -> String {
+    format!("share_{}", share.id)
+}
+
+// Cookie value proving the password was given, which changes with the link and the password
+fn unlock_value(share: &Share) -> Option<String> {
+    share
+        .password
+        .as_ref()
+        .map(|password| hash_token(&format!("{}{}", share.token, password)))
+}
+
+fn is_unlocked(req: &HttpRequest, share: &Share) -> bool {
+    match unlock_value(share) {
+        Some(value) => req
+            .cookie(&cookie_name(share))
+            .is_some_and(|cookie| cookie.value() == value),
+        None => true,
+    }
+}
+
+// The item if it is the shared one or under it, and not in the trash
+async fn find_shared(data: &AppState, share: &Share, id: Option<i64>) -> Option<Item> {
+    let item = item::find_by_id(&data.pool, id.unwrap_or(share.item)).await.ok()?;
+    if item.deleted_at.is_some() {
+        return None;
+    }
+    let mut current = Some(item.id);
+    for _ in 0..MAX_DEPTH {
+        match current {
+            Some(id) if id == share.item => return Some(item),
+            Some(id) => current = item::find_by_id(&data.pool, id).await.ok()?.parent,
+            None => return None,
+        }
+    }
+    None
+}
+
+// Whether the page of a link shows the original: images, and videos without an mp4 rendition
+// to play instead
+async fn shows_original(data: &AppState, item: &Item) -> bool {
+    match item.file_type.as_str() {
+        "image" => true,
+        "video" | "video/short" => !matches!(
+            transcode::find_by_item(&data.pool, item.id).await,
+            Ok(rendition) if rendition.status == "done" && rendition.format == "mp4"
+        ),
+        _ => false,
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("Not found!")
+}
+
+fn render(tmpl: &tera::Tera, name: &str, ctx: &tera::Context) -> HttpResponse {
+    let template = tmpl
+        .render(name, ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+#[post("/share/")]
+pub async fn create(
+    req: HttpRequest,
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    form: web::Form<ShareForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    let found = match form.id {
+        Some(id) => item::find_by_id(&data.pool, id).await.ok(),
+        None => None,
+    };
+    let item = match found.filter(|found| found.visible_to(&current.viewer())) {
+        Some(item) => item,
+        None => return not_found(),
+    };
+
+    let days = form
+        .days
+        .as_deref()
+        .and_then(|days| days.trim().parse::<i64>().ok())
+        .filter(|days| *days > 0)
+        .map(|days| days.min(MAX_DAYS));
+    let password = form
+        .password
+        .as_deref()
+        .filter(|password| !password.is_empty())
+        .and_then(hash_password);
+    let token = csrf::new_token();
+    let created_by = current.0.as_ref().map(|user| user.id);
+    if let Err(err) = share::insert(
+        &data.pool,
+        item.id,
+        &token,
+        password.as_deref(),
+        form.download.is_some(),
+        days,
+        created_by,
+    )
+    .await
+    {
+        eprintln!("Failed to create share link for item {}. {:?}", item.id, err);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let info = req.connection_info();
+    let mut ctx = tera::Context::new();
+    ctx.insert("item", &item);
+    ctx.insert(
+        "link",
+        &format!("{}://{}/share/{}/", info.scheme(), info.host(), token),
+    );
+    render(&tmpl, "share_link.html", &ctx)
+}
+
+#[get("/share/{token}/")]
+pub async fn view(
+    req: HttpRequest,
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    token: web::Path<String>,
+    query: web::Query<ShareQuery>,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let share = match share::find_active_by_token(&data.pool, &token).await {
+        Ok(share) => share,
+        Err(_) => return not_found(),
+    };
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("token", token.as_str());
+    ctx.insert("share", &share);
+    if !is_unlocked(&req, &share) {
+        ctx.insert("locked", &true);
+        return render(&tmpl, "share.html", &ctx);
+    }
+
+    let item = match find_shared(&data, &share, query.id).await {
+        Some(item) => item,
+        None => return not_found(),
+    };
+    if item.id != share.item {
+        ctx.insert("parent", &item.parent);
+    }
+
+    if item.file_type == "folder" {
+        let page = query.page.unwrap_or(1);
+        let offset = (page as i64 - 1) * data.ipp;
+        let (items, count) = item::find_by_parent(
+            &data.pool,
+            Some(item.id),
+            &Viewer::ADMIN,
+            data.order,
+            Some(data.ipp),
+            Some(offset),
+        )
+        .await
+        .unwrap_or_default();
+        ctx.insert(
+            "pages",
+            &Pages {
+                cur: page,
+                total: count / data.ipp + if count % data.ipp != 0 { 1 } else { 0 },
+            },
+        );
+        ctx.insert("old_query", &format!("id={}", item.id));
+        ctx.insert("items", &items);
+    } else if let Ok(rendition) = transcode::find_by_item(&data.pool, item.id).await {
+        let playable = rendition.status == "done" && rendition.format == "mp4";
+        ctx.insert("rendition", &playable);
+    }
+    ctx.insert("item", &item);
+    render(&tmpl, "share.html", &ctx)
+}
+
+#[post("/share/{token}/")]
+pub async fn unlock(
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    token: web::Path<String>,
+    form: web::Form<UnlockForm>,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let share = match share::find_active_by_token(&data.pool, &token).await {
+        Ok(share) => share,
+        Err(_) => return not_found(),
+    };
+    let password = form.password.as_deref().unwrap_or_default();
+    let value = match (&share.password, unlock_value(&share)) {
+        (Some(hash), Some(value)) if verify_password(password, hash) => value,
+        _ => {
+            let mut ctx = tera::Context::new();
+            ctx.insert(csrf::FIELD, &csrf.0);
+            ctx.insert("token", token.as_str());
+            ctx.insert("share", &share);
+            ctx.insert("locked", &true);
+            ctx.insert("error", "Wrong password.");
+            return render(&tmpl, "share.html", &ctx);
+        }
+    };
+
+    let path = format!("/share/{}/", token);
+    let cookie = Cookie::build(cookie_name(&share), value)
+        .path(path.clone())
+        .http_only(true)
+        .finish();
+    HttpResponse::Found()
+        .cookie(cookie)
+        .append_header(("Location", path))
+        .finish()
+}
+
+// The original, or its mp4 rendition with `kind` "rendition", or its thumbnail with "thumbnail"
+#[get("/share/{token}/{kind}/{id}")]
+pub async fn file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<(String, String, i64)>,
+    query: web::Query<ShareQuery>,
+) -> impl Responder {
+    let (token, kind, id) = path.into_inner();
+    let share = match share::find_active_by_token(&data.pool, &token).await {
+        Ok(share) if is_unlocked(&req, &share) => share,
+        _ => return not_found(),
+    };
+    let item = match find_shared(&data, &share, Some(id)).await {
+        Some(item) => item,
+        None => return not_found(),
+    };
+
+    let download = query.download.unwrap_or_default() == 1;
+    // Without downloads, the original is only served to be shown on the page
+    if share.download == 0 && (download || (kind == "file" && !shows_original(&data, &item).await)) {
+        return HttpResponse::Forbidden().body("Downloads are not allowed for this link.");
+    }
+    let file_path = match kind.as_str() {
+        "file" => data.root_dir.join(&item.path),
+        "thumbnail" => data.thumbnail_dir.join(format!("{}.jpg", item.path)),
+        "rendition" => match transcode::find_by_item(&data.pool, item.id).await {
+            Ok(rendition) if rendition.format == "mp4" => match rendition.path {
+                Some(path) => data.derived_dir.join(path),
+                None => return not_found(),
+            },
+            _ => return not_found(),
+        },
+        _ => return not_found(),
+    };
+
+    match NamedFile::open_async(&file_path).await {
+        Ok(file) => {
+            let disposition = if download {
+                DispositionType::Attachment
+            } else {
+                DispositionType::Inline
+            };
+            let content_disposition = ContentDisposition {
+                disposition,
+                parameters: file.content_disposition().parameters.clone(),
+            };
+            file.set_content_disposition(content_disposition)
+                .into_response(&req)
+        }
+        Err(_) => not_found(),
+    }
+}
+
+#[get("/admin/shares/")]
+pub async fn list(tmpl: web::Data<tera::Tera>, data: web::Data<AppState>, csrf: csrf::Token) -> impl Responder {
+    let mut entries = Vec::new();
+    for share in share::find_active(&data.pool).await.unwrap_or_default() {
+        if let Ok(item) = item::find_by_id(&data.pool, share.item).await {
+            let created_by = match share.created_by {
+                Some(id) => user::find_by_id(&data.pool, id).await.ok().map(|u| u.name),
+                None => None,
+            };
+            entries.push(ShareEntry {
+                share,
+                item,
+                created_by,
+            });
+        }
+    }
+
+    let mut ctx = tera::Context::new();
+
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("entries", &entries);
+    render(&tmpl, "shares.html", &ctx)
+}
+
+#[post("/admin/shares/revoke/")]
+pub async fn revoke(data: web::Data<AppState>, form: web::Form<ShareForm>) -> impl Responder {
+    if let Some(id) = form.id {
+        if let Err(err) = share::delete_by_id(&data.pool, id).await {
+            eprintln!("Failed to revoke share link {}. {:?}", id, err);
+        }
+    }
+    redirect!("/admin/shares/")
+}
@@ -0,0 +1,320 @@
+use actix_web::{error, get, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::auth::CurrentUser;
+use super::index::Pages;
+use super::{csrf, AppState, QueryInfo};
+use crate::db::item::DateOrder;
+use crate::db::metatag::{self, MetaTag};
+use crate::db::{color, item, metadata};
+
+#[derive(Serialize)]
+struct Period {
+    name: String,
+    count: i64,
+    link: String,
+}
+
+#[derive(Serialize)]
+struct Day {
+    date: String,
+    count: i64,
+    level: u8,
+}
+
+fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Day of week, starting from 0 for Monday
+fn weekday(year: i32, month: u32, day: u32) -> u32 {
+    let t = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let y = if month < 3 { year - 1 } else { year };
+    let sunday_first = (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)
+        + t[month as usize - 1]
+        + day as i32)
+        .rem_euclid(7);
+    (sunday_first as u32 + 6) % 7
+}
+
+// Calendar of a year as 7 rows of weekdays, one column per week
+fn heatmap(year: i32, counts: &HashMap<String, i64>) -> Vec<Vec<Day>> {
+    let max = counts.values().cloned().max().unwrap_or(0);
+    let offset = weekday(year, 1, 1);
+    let mut rows: Vec<Vec<Day>> = (0..7).map(|_| Vec::new()).collect();
+    for i in 0..offset {
+        rows[i as usize].push(Day {
+            date: String::new(),
+            count: 0,
+            level: 0,
+        });
+    }
+
+    let mut position = offset;
+    for month in 1..=12 {
+        for day in 1..=days_in_month(year, month) {
+            let date = format!("{:04}-{:02}-{:02}", year, month, day);
+            let count = counts.get(&date).cloned().unwrap_or(0);
+            let level = if count == 0 {
+                0
+            } else {
+                (1 + count * 3 / max.max(1)).min(4) as u8
+            };
+            rows[(position % 7) as usize].push(Day { date, count, level });
+            position += 1;
+        }
+    }
+    rows
+}
+
+// Query of a period such as "2023-05", keeping the searched tags
+fn period_query(tags: &str, period: &str) -> String {
+    let mut query = vec![("tags", tags)];
+    for (key, part) in ["year", "month", "day"].iter().zip(period.split('-')) {
+        if !part.is_empty() {
+            query.push((*key, part));
+        }
+    }
+    serde_urlencoded::to_string(query).unwrap_or_default()
+}
+
+fn periods(counts: Vec<(String, i64)>, tags: &str) -> Vec<Period> {
+    counts
+        .into_iter()
+        .map(|(period, count)| Period {
+            link: format!("?{}", period_query(tags, &period)),
+            name: period,
+            count,
+        })
+        .collect()
+}
+
+fn with_filter(metatags: &[MetaTag], filter: Option<MetaTag>) -> Vec<MetaTag> {
+    let mut metatags = metatags.to_vec();
+    metatags.push(metatag::not_folder());
+    metatags.extend(filter);
+    metatags
+}
+
+#[get("/timeline/")]
+pub async fn timeline(
+    tmpl: web::Data<tera::Tera>,
+    data: web::Data<AppState>,
+    query: web::Query<QueryInfo>,
+    current: CurrentUser,
+    csrf: csrf::Token,
+) -> impl Responder {
+    if query.year.is_some_and(|year| !(1..=9999).contains(&year)) {
+        return HttpResponse::BadRequest().body("Invalid year");
+    }
+
+    let viewer = current.viewer();
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("listview", &false);
+    let raw = query.raw.unwrap_or_default();
+    ctx.insert("raw", &raw);
+
+    let order: DateOrder = data.order;
+    let search = query.tags.as_deref().unwrap_or_default();
+    let (tags, metatags) = metatag::parse(search, order);
+    ctx.insert("search", search);
+
+    // Selected year, month and day, each narrowing the previous one
+    let year = query.year.map(|y| format!("{:04}", y));
+    let month = year
+        .as_ref()
+        .and_then(|y| query.month.map(|m| format!("{}-{:02}", y, m)));
+    let day = month
+        .as_ref()
+        .and_then(|m| query.day.map(|d| format!("{}-{:02}", m, d)));
+
+    let mut breadcrumbs = vec![Period {
+        name: "All".to_string(),
+        count: 0,
+        link: format!("?{}", period_query(search, "")),
+    }];
+    let mut items = Vec::new();
+    let mut count = 0;
+    let page = query.page.unwrap_or(1);
+
+    if let Some(day) = &day {
+        let offset = (page as i64 - 1) * data.ipp;
+        (items, count) = item::find_by_tag(
+            &data.pool,
+            tags.clone(),
+            with_filter(&metatags, metatag::date(order, "=", day)),
+            &viewer,
+            order,
+            data.ipp,
+            offset,
+        )
+        .await
+        .unwrap_or_default();
+    } else {
+        let (format, filter) = match (&year, &month) {
+            (_, Some(month)) => ("%Y-%m-%d", metatag::date(order, "=", month)),
+            (Some(year), None) => ("%Y-%m", metatag::date(order, "=", year)),
+            (None, None) => ("%Y", None),
+        };
+        let counts = item::count_by_date(
+            &data.pool,
+            tags.clone(),
+            with_filter(&metatags, filter),
+            &viewer,
+            order,
+            format,
+        )
+        .await
+        .unwrap_or_default();
+        ctx.insert("periods", &periods(counts, search));
+
+        if year.is_none() {
+            (items, count) = item::find_by_tag(
+                &data.pool,
+                tags.clone(),
+                with_filter(&metatags, Some(metatag::on_this_day(order))),
+                &viewer,
+                order,
+                data.ipp,
+                0,
+            )
+            .await
+            .unwrap_or_default();
+            ctx.insert("on_this_day", &true);
+        }
+    }
+
+    for name in year.iter().chain(&month).chain(&day) {
+        breadcrumbs.push(Period {
+            name: name.clone(),
+            count: 0,
+            link: format!("?{}", period_query(search, name)),
+        });
+    }
+    ctx.insert("breadcrumbs", &breadcrumbs);
+
+    // Heatmap of the selected year, or of the latest year with items
+    let heatmap_year = match query.year {
+        Some(year) => Some(year),
+        None => item::count_by_date(
+            &data.pool,
+            tags.clone(),
+            with_filter(&metatags, None),
+            &viewer,
+            order,
+            "%Y",
+        )
+        .await
+        .unwrap_or_default()
+        .first()
+        .and_then(|(year, _)| year.parse().ok()),
+    };
+    if let Some(heatmap_year) = heatmap_year {
+        let counts: HashMap<String, i64> = item::count_by_date(
+            &data.pool,
+            tags.clone(),
+            with_filter(
+                &metatags,
+                metatag::date(order, "=", &format!("{:04}", heatmap_year)),
+            ),
+            &viewer,
+            order,
+            "%Y-%m-%d",
+        )
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+        ctx.insert("heatmap_year", &heatmap_year);
+        ctx.insert("heatmap", &heatmap(heatmap_year, &counts));
+    }
+
+    let old_query = format!(
+        "raw={}&{}",
+        raw,
+        period_query(search, day.as_deref().unwrap_or_default())
+    );
+    let total_page = count / data.ipp + if count % data.ipp != 0 { 1 } else { 0 };
+    ctx.insert(
+        "pages",
+        &Pages {
+            cur: page,
+            total: total_page,
+        },
+    );
+    ctx.insert("old_query", &old_query);
+
+    let item_metadata: HashMap<i64, metadata::Metadata> =
+        metadata::find_by_items(&data.pool, items.iter().map(|i| i.id).collect())
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|m| (m.item, m))
+            .collect();
+    ctx.insert("item_metadata", &item_metadata);
+
+    let mut item_colors: HashMap<i64, Vec<color::Color>> = HashMap::new();
+    for c in color::find_by_items(&data.pool, items.iter().map(|i| i.id).collect())
+        .await
+        .unwrap_or_default()
+    {
+        item_colors.entry(c.item).or_default().push(c);
+    }
+    ctx.insert("item_colors", &item_colors);
+    ctx.insert("items", &items);
+    ctx.insert("day", &day);
+
+    let template = tmpl
+        .render("timeline.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_weekdays() {
+        assert_eq!(weekday(2024, 1, 1), 0);
+        assert_eq!(weekday(2024, 2, 29), 3);
+        assert_eq!(weekday(2000, 1, 1), 5);
+        assert_eq!(weekday(1970, 1, 1), 3);
+        assert_eq!(weekday(1, 1, 1), 0);
+        assert_eq!(weekday(0, 1, 1), 5);
+        assert_eq!(weekday(-1, 1, 1), 4);
+    }
+
+    #[test]
+    fn counts_days_in_order() {
+        for year in -800..2800 {
+            let mut expected = weekday(year, 1, 1);
+            for month in 1..=12 {
+                for day in 1..=days_in_month(year, month) {
+                    assert_eq!(weekday(year, month, day), expected, "{}-{}-{}", year, month, day);
+                    expected = (expected + 1) % 7;
+                }
+            }
+            assert_eq!(weekday(year + 1, 1, 1), expected, "{}", year + 1);
+        }
+    }
+
+    #[test]
+    fn encodes_period_links() {
+        assert_eq!(period_query("a&b c", ""), "tags=a%26b+c");
+        assert_eq!(period_query("", "2023-05"), "tags=&year=2023&month=05");
+        assert_eq!(period_query("cat", "2023-05-04"), "tags=cat&year=2023&month=05&day=04");
+    }
+}
@@ -0,0 +1,75 @@
+use actix_web::{error, get, post, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use super::auth::CurrentUser;
+use super::{csrf, redirect, AppState};
+use crate::db::item::{self, Item};
+use crate::db::trash;
+
+#[derive(Deserialize)]
+pub struct TrashForm {
+    id: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct TrashEntry {
+    item: Item,
+    path: String,
+    deleted_at: String,
+}
+
+#[get("/admin/trash/")]
+pub async fn list(data: web::Data<AppState>, tmpl: web::Data<tera::Tera>, csrf: csrf::Token) -> impl Responder {
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
+
+    let mut entries = Vec::new();
+    for trash in trash::find_all(&data.pool).await.unwrap_or_default() {
+        if let Ok(item) = item::find_by_id(&data.pool, trash.item).await {
+            entries.push(TrashEntry {
+                item,
+                path: trash.path,
+                deleted_at: trash.deleted_at,
+            });
+        }
+    }
+    ctx.insert("entries", &entries);
+
+    let template = tmpl
+        .render("trash.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+#[post("/admin/trash/restore/")]
+pub async fn restore(
+    data: web::Data<AppState>,
+    form: web::Form<TrashForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    if let Some(id) = form.id {
+        item::restore_item(&data.pool, id, data.root_dir.to_str().unwrap(), current.id()).await;
+    }
+    redirect!("/admin/trash/")
+}
+
+#[post("/admin/trash/purge/")]
+pub async fn purge(
+    data: web::Data<AppState>,
+    form: web::Form<TrashForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    if let Some(id) = form.id {
+        if trash::find_by_item(&data.pool, id).await.is_ok() {
+            item::delete_item(&data.pool, id, data.root_dir.to_str().unwrap(), current.id()).await;
+        }
+    }
+    redirect!("/admin/trash/")
+}
+
+#[post("/admin/trash/empty/")]
+pub async fn empty(data: web::Data<AppState>, current: CurrentUser) -> impl Responder {
+    item::purge_expired(&data.pool, data.root_dir.to_str().unwrap(), 0, current.id()).await;
+    redirect!("/admin/trash/")
+}
@@ -0,0 +1,343 @@
+// Resumable uploads with the tus protocol (https://tus.io): its core and the creation and
+// termination extensions. A file is received in `tmp/<token>.part` and, once complete, joins
+// a batch like the files of `upload::upload_item`.
+//
+// The name of the file is sent as `filename` in `Upload-Metadata`, with the folder it was in
+// like multipart uploads. `batch` puts files in the same batch; the batch of a file is
+// returned in an `Upload-Batch` header.
+
+use actix_web::http::StatusCode;
+use actix_web::{delete, head, options, patch, post, web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use async_std::io::WriteExt;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::StreamExt;
+use md5::{Digest, Md5};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::auth::CurrentUser;
+use super::upload::{check_name, keep, remove_file, split_name, store_archive, too_large};
+use super::{csrf, AppState};
+use crate::db::batch_file::{self, BatchFile};
+use crate::db::partial_upload::{self, PartialUpload};
+use crate::media::archive;
+
+const VERSION: &str = "1.0.0";
+
+// md5 of what was received so far of each upload, with how much that is. Computed again
+// from the file when missing, e.g. after a restart.
+#[derive(Default)]
+pub struct Hashers {
+    hashes: Mutex<HashMap<String, (u64, Md5)>>,
+    // Uploads a chunk is being received for
+    busy: Mutex<HashSet<String>>,
+}
+
+// Held while an upload is changed, so two requests cannot append to it at once
+struct Busy<'a> {
+    hashers: &'a Hashers,
+    token: String,
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.hashers.busy.lock().unwrap().remove(&self.token);
+    }
+}
+
+impl Hashers {
+    fn lock(&self, token: &str) -> Option<Busy<'_>> {
+        let locked = self.busy.lock().unwrap().insert(token.to_string());
+        locked.then(|| Busy {
+            hashers: self,
+            token: token.to_string(),
+        })
+    }
+
+    // Uploads that expired
+    pub fn forget(&self, tokens: &[String]) {
+        let mut hashes = self.hashes.lock().unwrap();
+        for token in tokens {
+            hashes.remove(token);
+        }
+    }
+}
+
+fn response(status: StatusCode) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    response.insert_header(("Tus-Resumable", VERSION));
+    response
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+// `key base64value,key base64value`
+fn parse_metadata(metadata: &str) -> HashMap<String, String> {
+    metadata
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next().filter(|key| !key.is_empty())?;
+            let value = STANDARD.decode(parts.next().unwrap_or_default()).ok()?;
+            Some((key.to_string(), String::from_utf8(value).ok()?))
+        })
+        .collect()
+}
+
+fn part_path(data: &AppState, upload: &PartialUpload) -> PathBuf {
+    data.root_dir.join("tmp").join(format!("{}.part", upload.token))
+}
+
+fn received_size(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+fn rehash(path: &Path) -> Option<Md5> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut md5 = Md5::new();
+    let mut buffer = [0; 65536];
+    loop {
+        let count = reader.read(&mut buffer).ok()?;
+        if count == 0 {
+            break;
+        }
+        md5.update(&buffer[..count]);
+    }
+    Some(md5)
+}
+
+async fn find(data: &AppState, token: &str, current: &CurrentUser) -> Option<PartialUpload> {
+    partial_upload::find_by_token(&data.pool, token, current.id()).await.ok()
+}
+
+// Move the complete file to its batch, or what is in it for an archive
+async fn complete(data: &AppState, upload: &PartialUpload, part: &Path, md5sum: String) {
+    let mut file = BatchFile::new(&upload.batch, upload.user, &upload.name, &upload.folder);
+    if archive::is_archive(&upload.name) {
+        store_archive(data, part, &file).await;
+        remove_file(part);
+    } else {
+        keep(data, &mut file, part, md5sum).await;
+        if let Err(err) = batch_file::insert(&data.pool, &file).await {
+            eprintln!("Failed to record upload of {}. {:?}", upload.name, err);
+        }
+    }
+    if let Err(err) = partial_upload::delete_by_id(&data.pool, upload.id).await {
+        eprintln!("Failed to delete upload {}. {:?}", upload.token, err);
+    }
+}
+
+#[options("/upload/tus/")]
+pub async fn options(data: web::Data<AppState>) -> HttpResponse {
+    let mut response = response(StatusCode::NO_CONTENT);
+    response
+        .insert_header(("Tus-Version", VERSION))
+        .insert_header(("Tus-Extension", "creation,termination"));
+    if let Some(max_size) = data.upload_max_size {
+        response.insert_header(("Tus-Max-Size", max_size.to_string()));
+    }
+    response.finish()
+}
+
+#[post("/upload/tus/")]
+pub async fn create(req: HttpRequest, data: web::Data<AppState>, current: CurrentUser) -> HttpResponse {
+    let length = match header(&req, "Upload-Length").and_then(|length| length.parse::<u64>().ok()) {
+        Some(length) => length,
+        None => return response(StatusCode::BAD_REQUEST).body("Upload-Length is required"),
+    };
+    if let Some(max_size) = data.upload_max_size.filter(|max_size| length > *max_size) {
+        return response(StatusCode::PAYLOAD_TOO_LARGE).body(too_large(max_size));
+    }
+    let metadata = parse_metadata(header(&req, "Upload-Metadata").unwrap_or_default());
+    let (folder, name) = match metadata.get("filename") {
+        Some(file_name) => split_name(file_name),
+        None => return response(StatusCode::BAD_REQUEST).body("filename is required in Upload-Metadata"),
+    };
+    if let Some(rejection) = check_name(&name, &folder) {
+        return response(StatusCode::UNSUPPORTED_MEDIA_TYPE).body(rejection);
+    }
+    let batch = metadata
+        .get("batch")
+        .filter(|batch| batch.len() == 32 && batch.chars().all(|c| c.is_ascii_alphanumeric()))
+        .cloned()
+        .unwrap_or_else(csrf::new_token);
+
+    let token = csrf::new_token();
+    let tmp_dir = data.root_dir.join("tmp");
+    let created = create_dir_all(&tmp_dir).and_then(|_| File::create(tmp_dir.join(format!("{}.part", token))));
+    if let Err(err) = created {
+        eprintln!("Failed to create upload {}. {}", token, err);
+        return response(StatusCode::INTERNAL_SERVER_ERROR).finish();
+    }
+    let inserted =
+        partial_upload::insert(&data.pool, &token, current.id(), &batch, &name, &folder, length as i64).await;
+    if let Err(err) = inserted {
+        eprintln!("Failed to create upload {}. {:?}", token, err);
+        return response(StatusCode::INTERNAL_SERVER_ERROR).finish();
+    }
+
+    if length == 0 {
+        if let Some(upload) = find(&data, &token, &current).await {
+            let part = part_path(&data, &upload);
+            complete(&data, &upload, &part, format!("{:x}", Md5::new().finalize())).await;
+        }
+    }
+    response(StatusCode::CREATED)
+        .insert_header(("Location", format!("/upload/tus/{}/", token)))
+        .insert_header(("Upload-Batch", batch))
+        .finish()
+}
+
+#[head("/upload/tus/{token}/")]
+pub async fn progress(data: web::Data<AppState>, token: web::Path<String>, current: CurrentUser) -> HttpResponse {
+    let upload = match find(&data, &token, &current).await {
+        Some(upload) => upload,
+        None => return response(StatusCode::NOT_FOUND).finish(),
+    };
+    match received_size(&part_path(&data, &upload)) {
+        Some(size) => response(StatusCode::OK)
+            .insert_header(("Upload-Offset", size.to_string()))
+            .insert_header(("Upload-Length", upload.length.to_string()))
+            .insert_header(("Upload-Batch", upload.batch))
+            .insert_header(("Cache-Control", "no-store"))
+            .finish(),
+        None => response(StatusCode::NOT_FOUND).finish(),
+    }
+}
+
+#[patch("/upload/tus/{token}/")]
+pub async fn append(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    hashers: web::Data<Hashers>,
+    token: web::Path<String>,
+    mut payload: web::Payload,
+    current: CurrentUser,
+) -> HttpResponse {
+    if header(&req, "Content-Type") != Some("application/offset+octet-stream") {
+        return response(StatusCode::UNSUPPORTED_MEDIA_TYPE).finish();
+    }
+    let upload = match find(&data, &token, &current).await {
+        Some(upload) => upload,
+        None => return response(StatusCode::NOT_FOUND).finish(),
+    };
+    // Until the chunk is written, as the offset is checked against it
+    let _busy = match hashers.lock(&upload.token) {
+        Some(busy) => busy,
+        None => return response(StatusCode::LOCKED).finish(),
+    };
+    let part = part_path(&data, &upload);
+    let offset = match received_size(&part) {
+        Some(offset) => offset,
+        None => return response(StatusCode::NOT_FOUND).finish(),
+    };
+    if header(&req, "Upload-Offset").and_then(|offset| offset.parse::<u64>().ok()) != Some(offset) {
+        return response(StatusCode::CONFLICT)
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .finish();
+    }
+
+    let kept = hashers.hashes.lock().unwrap().remove(upload.token.as_str());
+    let mut md5 = match kept {
+        Some((size, md5)) if size == offset => md5,
+        _ => match rehash(&part) {
+            Some(md5) => md5,
+            None => return response(StatusCode::INTERNAL_SERVER_ERROR).finish(),
+        },
+    };
+
+    let mut f = match async_std::fs::OpenOptions::new().append(true).open(&part).await {
+        Ok(f) => f,
+        Err(err) => {
+            eprintln!("Failed to open {:?}. {}", part, err);
+            return response(StatusCode::INTERNAL_SERVER_ERROR).finish();
+        }
+    };
+    let length = upload.length as u64;
+    let mut size = offset;
+    let mut status = StatusCode::NO_CONTENT;
+    // What was received is kept when the connection drops, so it can be resumed from there
+    while let Some(Ok(chunk)) = payload.next().await {
+        if size + chunk.len() as u64 > length {
+            status = StatusCode::BAD_REQUEST;
+            break;
+        }
+        if let Err(err) = f.write_all(&chunk).await {
+            eprintln!("Failed to write {:?}. {}", part, err);
+            status = StatusCode::INTERNAL_SERVER_ERROR;
+            break;
+        }
+        md5.update(&chunk);
+        size += chunk.len() as u64;
+    }
+    if let Err(err) = f.flush().await {
+        eprintln!("Failed to write {:?}. {}", part, err);
+    }
+    if let Err(err) = partial_upload::update_time(&data.pool, upload.id).await {
+        eprintln!("Failed to update upload {}. {:?}", upload.token, err);
+    }
+
+    if size == length {
+        complete(&data, &upload, &part, format!("{:x}", md5.finalize())).await;
+    } else {
+        hashers.hashes.lock().unwrap().insert(upload.token.clone(), (size, md5));
+    }
+    response(status)
+        .insert_header(("Upload-Offset", size.to_string()))
+        .insert_header(("Upload-Batch", upload.batch))
+        .finish()
+}
+
+#[delete("/upload/tus/{token}/")]
+pub async fn terminate(
+    data: web::Data<AppState>,
+    hashers: web::Data<Hashers>,
+    token: web::Path<String>,
+    current: CurrentUser,
+) -> HttpResponse {
+    let upload = match find(&data, &token, &current).await {
+        Some(upload) => upload,
+        None => return response(StatusCode::NOT_FOUND).finish(),
+    };
+    let _busy = match hashers.lock(&upload.token) {
+        Some(busy) => busy,
+        None => return response(StatusCode::LOCKED).finish(),
+    };
+    hashers.hashes.lock().unwrap().remove(upload.token.as_str());
+    remove_file(&part_path(&data, &upload));
+    if let Err(err) = partial_upload::delete_by_id(&data.pool, upload.id).await {
+        eprintln!("Failed to delete upload {}. {:?}", upload.token, err);
+    }
+    response(StatusCode::NO_CONTENT).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_metadata() {
+        let metadata = parse_metadata("filename ZC5qcGc=, folder YS9i,flag");
+        assert_eq!(metadata.get("filename").map(String::as_str), Some("d.jpg"));
+        assert_eq!(metadata.get("folder").map(String::as_str), Some("a/b"));
+        // Keys may come without a value
+        assert_eq!(metadata.get("flag").map(String::as_str), Some(""));
+        assert_eq!(metadata.len(), 3);
+    }
+
+    #[test]
+    fn skips_bad_metadata() {
+        let metadata = parse_metadata("filename not-base64!,folder /w==, ZC5qcGc=,,batch MQ==");
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.get("batch").map(String::as_str), Some("1"));
+        // A value without its key reads as a key
+        assert_eq!(metadata.get("ZC5qcGc=").map(String::as_str), Some(""));
+        assert!(parse_metadata("").is_empty());
+    }
+}
@@ -1,24 +1,103 @@
-use actix_multipart::Multipart;
+use actix_multipart::{Field, Multipart};
 use actix_web::{error, get, post, web, HttpResponse, Responder};
 use async_std::io::WriteExt;
 use futures::{StreamExt, TryStreamExt};
 use md5::{Digest, Md5};
-use std::fs::{create_dir_all, rename};
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs::{create_dir_all, remove_dir_all, rename, File};
+use std::path::{Path, PathBuf};
 
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use super::auth::CurrentUser;
+use super::img::is_safe;
 use super::post::PostData;
-use super::{create_thumbnail, guess_file_type, redirect, AppState, QueryInfo};
-use crate::db::{item, tag};
+use super::{create_thumbnail, csrf, guess_file_type, redirect, AppState, QueryInfo};
+use crate::db::item::{Item, Viewer};
+use crate::db::batch_file::{self, BatchFile};
+use crate::db::{audit, item, item_source, tag};
+use crate::media;
+use crate::media::archive;
+use crate::media::store::{self, Placement};
+use crate::media::validate;
+
+// Applied to every new file of an upload. `name` is a pattern, see `apply_pattern`. With `series`,
+// albums of comic archives are tagged as a series.
+#[derive(Deserialize)]
+pub struct BatchForm {
+    parent: Option<String>,
+    tags: Option<String>,
+    name: Option<String>,
+    series: Option<String>,
+}
+
+// Longest value of a text field sent with files
+const MAX_TEXT_SIZE: usize = 64 * 1024;
+
+// What became of a file of `upload_direct`: "added" as `item`, a "duplicate" of `item`, or
+// "rejected" with a `message`
+#[derive(Serialize)]
+pub struct Uploaded {
+    name: String,
+    status: String,
+    item: Option<i64>,
+    message: Option<String>,
+}
+
+impl Uploaded {
+    fn new(name: String, status: &str, item: Option<i64>, message: Option<String>) -> Uploaded {
+        Uploaded {
+            name,
+            status: status.to_string(),
+            item,
+            message,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImportForm {
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ImportArchive {
+    name: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+pub struct Uploads {
+    files: Vec<Uploaded>,
+}
+
+#[derive(Serialize)]
+pub struct UploadError {
+    error: String,
+}
+
+impl UploadError {
+    fn new(error: &str) -> UploadError {
+        UploadError {
+            error: error.to_string(),
+        }
+    }
+}
 
 #[get("/upload/")]
 pub async fn upload(
     data: web::Data<AppState>,
     tmpl: web::Data<tera::Tera>,
     query: web::Query<QueryInfo>,
+    current: CurrentUser,
+    csrf: csrf::Token,
 ) -> impl Responder {
     let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
     ctx.insert("post_upload", &false);
+    // Files sent in chunks from this page go to the same batch
+    ctx.insert("batch", &csrf::new_token());
+    ctx.insert("has_import_dir", &data.import_dir.is_some());
     ctx.insert("md5", query.md5.as_ref().unwrap_or(&String::new()));
     if let Some(file_name) = &query.file_name {
         ctx.insert("file_type", guess_file_type(file_name));
@@ -28,8 +107,11 @@ pub async fn upload(
             ctx.insert("post_upload", &true);
         }
     }
+    ctx.insert("source", query.source.as_deref().unwrap_or_default());
+    ctx.insert("url", query.url.as_deref().unwrap_or_default());
+    ctx.insert("error", query.error.as_deref().unwrap_or_default());
 
-    let folders = item::find_by_type(&data.pool, "folder")
+    let folders = item::find_by_type(&data.pool, "folder", &current.viewer())
         .await
         .unwrap_or(vec![]);
     ctx.insert("parents", &folders);
@@ -44,124 +126,865 @@ pub async fn upload(
     HttpResponse::Ok().content_type("text/html").body(template)
 }
 
+// Back to the upload page, showing `error` of the query
+fn upload_error(query: &[(&str, &str)]) -> HttpResponse {
+    redirect!(format!("/upload/?{}", serde_urlencoded::to_string(query).unwrap_or_default()))
+}
+
 #[post("/upload/")]
-pub async fn upload_item(data: web::Data<AppState>, mut payload: Multipart) -> impl Responder {
-    let tmp_dir_path = Path::new(&data.root_dir).join("tmp");
-    if !tmp_dir_path.exists() {
-        if let Err(_) = create_dir_all(&tmp_dir_path) {
-            return redirect!("/");
+pub async fn upload_item(
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+    current: CurrentUser,
+    unverified: Option<web::ReqData<csrf::Unverified>>,
+) -> impl Responder {
+    let mut unverified = unverified;
+    let tmp_dir_path = data.root_dir.join("tmp");
+    if !tmp_dir_path.exists() && create_dir_all(&tmp_dir_path).is_err() {
+        return upload_error(&[("error", "Could not save files")]);
+    }
+
+    let batch_id = csrf::new_token();
+    let mut count = 0;
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("Failed to receive upload {}. {}", batch_id, err);
+                if count == 0 {
+                    return upload_error(&[("error", "The upload was interrupted")]);
+                }
+                break;
+            }
         };
+        let file_name = match field
+            .content_disposition()
+            .get_filename()
+        {
+            Some(file_name) if !file_name.is_empty() => file_name.to_string(),
+            _ => {
+                if let Some(pending) = &unverified {
+                    if accepts_token(&data, pending, &mut field).await {
+                        unverified = None;
+                    }
+                }
+                continue;
+            }
+        };
+        if unverified.is_some() {
+            return HttpResponse::from_error(csrf::forbidden());
+        }
+        store(&data, &mut field, &file_name, &batch_id, &current).await;
+        count += 1;
+    }
+
+    if count == 0 {
+        return upload_error(&[("error", "No file was sent")]);
+    }
+    redirect!(format!("/upload/batch/{}/", batch_id))
+}
+
+// Receive a file of a multipart upload into its batch
+async fn store(data: &AppState, field: &mut Field, file_name: &str, batch_id: &str, current: &CurrentUser) {
+    let (folder, name) = split_name(file_name);
+    let mut file = BatchFile::new(batch_id, current.id(), &name, &folder);
+
+    if let Some(rejection) = check_name(&name, &folder) {
+        file.message = Some(rejection.to_string());
+        // Skip the content
+        while field.next().await.is_some() {}
+    } else {
+        let file_path = data.root_dir.join("tmp").join(receiving_name());
+        match receive(field, &file_path, data.upload_max_size).await {
+            Ok(_) if archive::is_archive(&name) => {
+                store_archive(data, &file_path, &file).await;
+                remove_file(&file_path);
+                return;
+            }
+            Ok(md5sum) => keep(data, &mut file, &file_path, md5sum).await,
+            Err(message) => {
+                file.message = Some(message);
+                remove_file(&file_path);
+            }
+        }
     }
 
-    // TODO: Upload multiple files
-    let mut file_name = String::new();
-    let mut real_file_name = String::new();
+    record(data, &file).await;
+}
 
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let mut md5_context = Md5::new();
-        let mut md5sum = String::new();
+async fn record(data: &AppState, file: &BatchFile) {
+    if let Err(err) = batch_file::insert(&data.pool, file).await {
+        eprintln!("Failed to record upload of {}. {:?}", file.name, err);
+    }
+}
+
+// Extract an archive and add its files to the batch of `archive`, in an album named after it.
+// The pages of a comic all go in that album. Returns whether it could be extracted.
+pub async fn store_archive(data: &AppState, path: &Path, archive: &BatchFile) -> bool {
+    let dest = data.root_dir.join("tmp").join(format!("{}.d", receiving_name()));
+    let album = match archive.folder.as_str() {
+        "" => archive::album_name(&archive.name).to_string(),
+        folder => format!("{}/{}", folder, archive::album_name(&archive.name)),
+    };
+    let new_file = |name: &str, folder: &str| {
+        let mut file = BatchFile::new(&archive.batch, archive.user, name, folder);
+        file.archive = Some(archive.name.clone());
+        file
+    };
 
-        file_name = field
+    let (block_path, block_name, block_dest) = (path.to_path_buf(), archive.name.clone(), dest.clone());
+    let limits = archive::Limits::new(data.upload_max_size);
+    let extracted = web::block(move || archive::extract(&block_path, &block_name, &block_dest, &limits))
+        .await
+        .unwrap_or_else(|err| Err(archive::Error::Failed(err.to_string())));
+    match extracted {
+        Ok(skipped) => {
+            for name in skipped {
+                let mut file = new_file(&name, &album);
+                file.message = data.upload_max_size.map(too_large);
+                record(data, &file).await;
+            }
+        }
+        Err(err) => {
+            let message = match err {
+                archive::Error::Limit(message) => message,
+                archive::Error::Failed(err) => {
+                    eprintln!("Failed to extract {:?}. {}", path, err);
+                    "Could not be extracted".to_string()
+                }
+            };
+            let mut file = new_file(&archive.name, &archive.folder);
+            file.message = Some(message);
+            record(data, &file).await;
+            let _ = remove_dir_all(&dest);
+            return false;
+        }
+    }
+
+    let comic = archive::is_comic(&archive.name);
+    let entries = WalkDir::new(&dest)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file());
+    for entry in entries {
+        let rel_path = match entry.path().strip_prefix(&dest).ok().and_then(|path| path.to_str()) {
+            Some(rel_path) => rel_path.to_string(),
+            None => continue,
+        };
+        // Hidden files and what macOS adds to zip files
+        if rel_path.split('/').any(|part| part.starts_with('.') || part == "__MACOSX") {
+            continue;
+        }
+        let (inner_folder, name) = split_name(&rel_path);
+        let mut file = match (comic, inner_folder.is_empty()) {
+            (true, _) => new_file(&rel_path.replace('/', " "), &album),
+            (false, true) => new_file(&name, &album),
+            (false, false) => new_file(&name, &format!("{}/{}", album, inner_folder)),
+        };
+
+        let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+        if archive::is_archive(&file.name) {
+            file.message = Some("Archive inside an archive".to_string());
+        } else if let Some(rejection) = check_name(&file.name, &file.folder) {
+            file.message = Some(rejection.to_string());
+        } else if let Some(max_size) = data.upload_max_size.filter(|max_size| size > *max_size) {
+            file.message = Some(too_large(max_size));
+        } else {
+            let entry_path = entry.path().to_path_buf();
+            match web::block(move || hash_file(&entry_path)).await.ok().flatten() {
+                Some(md5sum) => keep(data, &mut file, entry.path(), md5sum).await,
+                None => file.message = Some("Could not be read".to_string()),
+            }
+        }
+        record(data, &file).await;
+    }
+    if let Err(err) = remove_dir_all(&dest) {
+        eprintln!("Failed to remove {:?}. {}", dest, err);
+    }
+    true
+}
+
+pub fn hash_file(path: &Path) -> Option<String> {
+    let mut md5 = Md5::new();
+    std::io::copy(&mut File::open(path).ok()?, &mut md5).ok()?;
+    Some(format!("{:x}", md5.finalize()))
+}
+
+// Whether `field` is the CSRF token of the page the files are sent from, which comes before them
+async fn accepts_token(data: &AppState, unverified: &csrf::Unverified, field: &mut Field) -> bool {
+    let name = field
+        .content_disposition()
+        .get_name()
+        .map(str::to_string);
+    if name.as_deref() != Some(csrf::FIELD) {
+        return false;
+    }
+    read_text(field)
+        .await
+        .is_some_and(|token| unverified.accepts(data, &token))
+}
+
+// Value of a text field of a multipart upload
+async fn read_text(field: &mut Field) -> Option<String> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.ok()?;
+        if bytes.len() + chunk.len() > MAX_TEXT_SIZE {
+            while field.next().await.is_some() {}
+            return None;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Uploads files and adds them in a single request, for scripts. The fields of `BatchForm` are
+// sent along with the files; the answer tells what became of each file.
+#[post("/upload/direct/")]
+pub async fn upload_direct(
+    data: web::Data<AppState>,
+    mut payload: Multipart,
+    current: CurrentUser,
+    unverified: Option<web::ReqData<csrf::Unverified>>,
+) -> HttpResponse {
+    let mut unverified = unverified;
+    if create_dir_all(data.root_dir.join("tmp")).is_err() {
+        return HttpResponse::InternalServerError().json(UploadError::new("Could not save files"));
+    }
+
+    let batch_id = csrf::new_token();
+    let mut form = BatchForm {
+        parent: None,
+        tags: None,
+        name: None,
+        series: None,
+    };
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => {
+                // What was received waits in `tmp/` until it expires
+                eprintln!("Failed to receive upload {}. {}", batch_id, err);
+                return HttpResponse::BadRequest().json(UploadError::new("The upload was interrupted"));
+            }
+        };
+        let file_name = field
             .content_disposition()
             .get_filename()
-            .ok_or_else(|| actix_web::error::ParseError::Incomplete)
-            .unwrap()
-            .to_string();
-        real_file_name = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros()
-            .to_string();
-        let parts: Vec<&str> = file_name.split(".").collect();
-        let mut ext = "png";
-        if let Some(_ext) = parts.last() {
-            real_file_name.push_str(&format!(".{}", *_ext));
-            ext = _ext;
-        } else {
-            real_file_name.push_str("png");
+            .map(str::to_string);
+        match file_name {
+            Some(_) if unverified.is_some() => return HttpResponse::from_error(csrf::forbidden()),
+            Some(file_name) if !file_name.is_empty() => {
+                store(&data, &mut field, &file_name, &batch_id, &current).await;
+            }
+            Some(_) => while field.next().await.is_some() {},
+            None => {
+                let name = field
+                    .content_disposition()
+                    .get_name()
+                    .map(str::to_string);
+                let value = read_text(&mut field).await;
+                match name.as_deref().unwrap_or_default() {
+                    "parent" => form.parent = value,
+                    "tags" => form.tags = value,
+                    "name" => form.name = value,
+                    "series" => form.series = value,
+                    csrf::FIELD => {
+                        let token = value.unwrap_or_default();
+                        if unverified.as_ref().is_some_and(|pending| pending.accepts(&data, &token)) {
+                            unverified = None;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let files = batch_file::find_by_batch(&data.pool, &batch_id, current.id())
+        .await
+        .unwrap_or_default();
+    if files.is_empty() {
+        return HttpResponse::BadRequest().json(UploadError::new("No file was sent"));
+    }
+    let parent = find_album(&data, &form.parent, &current).await;
+    if wants_album(&form.parent) && parent.is_none() {
+        // The files wait in `tmp/` until they expire
+        return HttpResponse::BadRequest().json(UploadError::new("Album not found"));
+    }
+
+    let results = add_batch(&data, &files, parent.as_ref(), &form, &current, "api").await;
+    let mut uploaded = Vec::new();
+    for file in files {
+        let result = results.iter().find(|(id, _)| *id == file.id).map(|(_, result)| result);
+        uploaded.push(match result {
+            Some(Ok(id)) => Uploaded::new(file.name, "added", Some(*id), None),
+            Some(Err(message)) => Uploaded::new(file.name, "rejected", None, Some(message.to_string())),
+            None => Uploaded::new(file.name, &file.status, file.item, file.message),
+        });
+    }
+    if let Err(err) = batch_file::delete_by_batch(&data.pool, &batch_id).await {
+        eprintln!("Failed to delete upload {}. {:?}", batch_id, err);
+    }
+    HttpResponse::Ok().json(Uploads { files: uploaded })
+}
+
+// A name sent for a file of an uploaded folder includes where it was, e.g. `trip/day 1/a.jpg`
+pub fn split_name(file_name: &str) -> (String, String) {
+    match file_name.rsplit_once('/') {
+        Some((folder, name)) => (folder.to_string(), name.to_string()),
+        None => (String::new(), file_name.to_string()),
+    }
+}
+
+// Name in `tmp/` while a file is received, before its md5 is known. Random, as uploads are
+// received at the same time.
+fn receiving_name() -> String {
+    csrf::new_token()
+}
+
+// Why a file cannot be uploaded, from its name and the folder it was in
+pub fn check_name(name: &str, folder: &str) -> Option<&'static str> {
+    if !is_safe(name) || !(folder.is_empty() || is_safe(folder)) {
+        Some("Invalid file name")
+    } else if guess_file_type(name) == "unknown" && !archive::is_archive(name) {
+        Some("Unsupported file type")
+    } else {
+        None
+    }
+}
+
+pub fn too_large(max_size: u64) -> String {
+    format!("Larger than {} MB", max_size / 1024 / 1024)
+}
+
+// Write a field to `path`, hashing it on the way. The error is for the user.
+async fn receive(field: &mut Field, path: &Path, max_size: Option<u64>) -> Result<String, String> {
+    let mut f = async_std::fs::File::create(path).await.map_err(|err| {
+        eprintln!("Failed to create {:?}. {}", path, err);
+        "Could not be saved".to_string()
+    })?;
+    let mut md5_context = Md5::new();
+    let mut size = 0;
+    while let Some(chunk) = field.next().await {
+        let data = chunk.map_err(|err| {
+            eprintln!("Failed to receive {:?}. {}", path, err);
+            "Could not be received".to_string()
+        })?;
+        size += data.len() as u64;
+        let error = match max_size {
+            Some(max_size) if size > max_size => Some(too_large(max_size)),
+            _ => match f.write_all(&data).await {
+                Ok(()) => None,
+                Err(err) => {
+                    eprintln!("Failed to write {:?}. {}", path, err);
+                    Some("Could not be saved".to_string())
+                }
+            },
+        };
+        if let Some(error) = error {
+            // Skip the rest
+            while field.next().await.is_some() {}
+            return Err(error);
         }
-        // let filepath = format!("{}/{}", tmp_dir, sanitize_filename::sanitize(&filename));
-        let file_path = tmp_dir_path.join(&real_file_name);
-        let mut f = async_std::fs::File::create(file_path.to_str().unwrap())
-            .await
-            .unwrap();
+        md5_context.update(&data);
+    }
+    Ok(format!("{:x}", md5_context.finalize()))
+}
 
-        // Field in turn is stream of *Bytes* object
-        while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
-            md5_context.update(&data);
-            f.write_all(&data).await;
+// Add a received file to its batch: rejected when its content is not accepted, as a duplicate of an item or of another file of the batch,
+// or as new under `tmp/<md5>.<ext>`
+pub async fn keep(data: &AppState, file: &mut BatchFile, received: &Path, md5sum: String) {
+    // Decoding runs convert or ffmpeg
+    let (validation, name, path) = (data.validation.clone(), file.name.clone(), received.to_path_buf());
+    let checked = web::block(move || validate::check(&validation, &name, &path))
+        .await
+        .unwrap_or_else(|_| Err("Could not be checked".to_string()));
+    if let Err(message) = checked {
+        file.message = Some(message);
+        remove_file(received);
+    } else if let Ok(item) = item::find_by_md5(&data.pool, &md5sum).await {
+        file.status = "duplicate".to_string();
+        file.item = Some(item.id);
+        remove_file(received);
+    } else if batch_file::find_new_by_md5(&data.pool, &file.batch, &md5sum).await.is_ok() {
+        file.status = "duplicate".to_string();
+        file.message = Some("Same file as another one in this upload".to_string());
+        remove_file(received);
+    } else {
+        let ext = Path::new(&file.name).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        let real_name = format!("{}.{}", md5sum, ext);
+        match rename(received, data.root_dir.join("tmp").join(&real_name)) {
+            Ok(()) => {
+                file.status = "new".to_string();
+                file.real_name = Some(real_name);
+            }
+            Err(err) => {
+                eprintln!("Failed to rename {:?}. {}", received, err);
+                file.message = Some("Could not be saved".to_string());
+                remove_file(received);
+            }
         }
+    }
+    file.md5 = Some(md5sum);
+}
 
-        md5sum = format!("{:x}", md5_context.finalize());
+pub fn remove_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        eprintln!("Failed to remove {:?}. {}", path, err);
+    }
+}
 
-        if let Ok(item) = item::find_by_md5(&data.pool, &md5sum).await {
-            println!("File existed: {}", item.path);
-            redirect!("/upload/");
+// Name of an item from a pattern with `{name}` and `{ext}` of the uploaded file, and `{n}`,
+// its number in the upload
+fn apply_pattern(pattern: &str, file_name: &str, n: usize) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(file_name);
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or_default();
+    pattern
+        .replace("{name}", stem)
+        .replace("{ext}", ext)
+        .replace("{n}", &n.to_string())
+}
+
+// Where a file came from: its name before it was stored as `md5.ext`, who added it, who also
+// becomes its owner, and how
+pub struct Origin<'a> {
+    pub original_name: &'a str,
+    pub uploader: Option<i64>,
+    pub method: &'a str,
+}
+
+// Move a file from `tmp/` into an album, or the root folder, and add it with its tags,
+// thumbnail and metadata
+pub async fn add_item(
+    data: &AppState,
+    real_name: &str,
+    name: &str,
+    md5: &str,
+    parent: Option<&Item>,
+    tags: Vec<&str>,
+    origin: Origin<'_>,
+) -> Option<i64> {
+    let owner = origin.uploader;
+    let placement = Placement {
+        album: parent.map(|parent| parent.path.as_str()).unwrap_or_default(),
+        original_name: origin.original_name,
+        md5,
+        ext: Path::new(real_name).extension().and_then(|ext| ext.to_str()).unwrap_or_default(),
+        date: store::today(),
+    };
+    let path = match store::render(&data.store_template, &placement) {
+        Some(path) => store::free_path(&data.root_dir, &path, None),
+        None => {
+            eprintln!("Failed to place {}, store_path {} is not valid", real_name, data.store_template);
+            return None;
         }
+    };
+    let tmp_file = data.root_dir.join("tmp").join(real_name);
+    let dest_file = data.root_dir.join(&path);
+    if let Err(err) = dest_file
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| rename(&tmp_file, &dest_file))
+    {
+        eprintln!("Failed to move {:?} to {:?}. {}", tmp_file, dest_file, err);
+        return None;
+    }
 
-        let new_file_name = format!("{}.{}", md5sum, ext);
-        let new_file_path = tmp_dir_path.join(&new_file_name);
-        if let Ok(_) = rename(&file_path, &new_file_path) {
-            return redirect!(format!(
-                "/upload/?file_name={}&real_file_name={}&md5={}",
-                file_name, new_file_name, md5sum
-            ));
+    let mut item = Item::empty();
+    item.owner = owner;
+    item.original_name = Some(origin.original_name.to_string());
+    item.uploader = origin.uploader;
+    item.import_method = Some(origin.method.to_string());
+    item.parent = parent.map(|parent| parent.id);
+    item.name = name.to_string();
+    item.path = path;
+    item.file_type = guess_file_type(real_name).to_string();
+    item.md5 = md5.to_string();
+    let id = match item::insert(&data.pool, &item).await {
+        Ok(id) => id,
+        Err(err) => {
+            eprintln!("Failed to insert item {}. {:?}", item.path, err);
+            return None;
+        }
+    };
+    let uploaded = item::find_by_id(&data.pool, id).await.ok();
+    audit::record(&data.pool, owner, "upload", "item", id, None, uploaded.as_ref()).await;
+    if !tags.is_empty() {
+        if let Err(err) = tag::update_item_tags(&data.pool, id, tags, owner).await {
+            eprintln!("Failed to tag item {}. {:?}", id, err);
         }
     }
 
-    redirect!("/")
+    create_thumbnail(
+        data.root_dir.to_str().unwrap(),
+        data.thumbnail_dir.to_str().unwrap(),
+        dest_file.to_str().unwrap(),
+        &item.file_type,
+        false,
+    );
+
+    item.id = id;
+    media::index(
+        &data.pool,
+        &item,
+        dest_file.to_str().unwrap(),
+        &data.thumbnail_dir,
+        data.transcode.as_deref(),
+    )
+    .await;
+    Some(id)
 }
 
-#[post("/post_upload/")]
-pub async fn post_upload(data: web::Data<AppState>, form: web::Form<PostData>) -> impl Responder {
-    let mut dest_dir = data.root_dir.clone();
-    let mut item = item::Item::empty();
-    if let Some(parent) = &form.parent {
-        if let Ok(parent_id) = parent.parse::<i64>() {
-            if let Ok(parent_item) = item::find_by_id(&data.pool, parent_id).await {
-                if parent_item.file_type == "folder" {
-                    dest_dir = data.root_dir.join(Path::new(&parent_item.path));
-                    item.parent = Some(parent_item.id);
-                }
+// The album a file of an uploaded folder goes to, created with the albums above it under `parent`
+// if needed, owned by `owner`. The albums must be visible to `viewer`. Paths of newly created albums
+// are added to `created`.
+pub async fn find_or_create_folder(
+    data: &AppState,
+    parent: Option<&Item>,
+    folder: &str,
+    owner: Option<i64>,
+    viewer: &Viewer,
+    created: &mut Vec<String>,
+) -> Option<Item> {
+    let mut path = parent.map(|parent| PathBuf::from(&parent.path)).unwrap_or_default();
+    let mut parent_id = parent.map(|parent| parent.id);
+    let mut album = None;
+    for component in Path::new(folder).components() {
+        let name = component.as_os_str().to_str()?;
+        path.push(name);
+        let rel_path = path.to_str()?;
+        let found = match item::find_by_path(&data.pool, rel_path).await {
+            Ok(found) => found,
+            Err(_) => {
+                create_dir_all(data.root_dir.join(rel_path)).ok()?;
+                let mut new_folder = Item::new(name.to_string(), rel_path.to_string(), "folder".to_string());
+                new_folder.parent = parent_id;
+                new_folder.owner = owner;
+                new_folder.md5 = format!("{:x}", Md5::digest(rel_path.as_bytes()));
+                let id = item::insert(&data.pool, &new_folder).await.ok()?;
+                let inserted = item::find_by_id(&data.pool, id).await.ok()?;
+                audit::record(&data.pool, owner, "upload", "item", id, None, Some(&inserted)).await;
+                created.push(inserted.path.clone());
+                inserted
             }
+        };
+        let usable = found.file_type == "folder"
+            && found.deleted_at.is_none()
+            && found.visible_to(viewer);
+        if !usable {
+            return None;
         }
+        parent_id = Some(found.id);
+        album = Some(found);
     }
-    if let Some(real_file_name) = &form.real_name {
-        let tmp_file = data.root_dir.join("tmp").join(real_file_name);
-        let dest_file = dest_dir.join(real_file_name);
-        if let Ok(()) = rename(&tmp_file, &dest_file) {
-            let file_name = match &form.name {
-                Some(name) => name,
-                None => real_file_name,
-            };
+    album
+}
+
+async fn find_album(data: &AppState, parent: &Option<String>, current: &CurrentUser) -> Option<Item> {
+    let parent_id = parent.as_deref()?.trim().parse::<i64>().ok()?;
+    match item::find_by_id(&data.pool, parent_id).await {
+        Ok(parent) if parent.file_type == "folder" && parent.visible_to(&current.viewer()) => Some(parent),
+        _ => None,
+    }
+}
+
+// Whether an album was asked for, which `find_album` may not find
+fn wants_album(parent: &Option<String>) -> bool {
+    parent.as_deref().is_some_and(|parent| !parent.trim().is_empty())
+}
+
+#[get("/upload/batch/{batch}/")]
+pub async fn batch(
+    data: web::Data<AppState>,
+    tmpl: web::Data<tera::Tera>,
+    batch_id: web::Path<String>,
+    query: web::Query<QueryInfo>,
+    current: CurrentUser,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let files = batch_file::find_by_batch(&data.pool, &batch_id, current.id())
+        .await
+        .unwrap_or_default();
+    if files.is_empty() {
+        return HttpResponse::NotFound().body("Not found!");
+    }
+
+    let mut ctx = tera::Context::new();
+
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("error", query.error.as_deref().unwrap_or_default());
+    let file_types: Vec<&str> = files.iter().map(|file| guess_file_type(&file.name)).collect();
+    let new_count = files.iter().filter(|file| file.status == "new").count();
+    let has_comics = files
+        .iter()
+        .any(|file| file.status == "new" && file.archive.as_deref().is_some_and(archive::is_comic));
+    ctx.insert("batch", batch_id.as_str());
+    ctx.insert("has_comics", &has_comics);
+    ctx.insert("files", &files);
+    ctx.insert("file_types", &file_types);
+    ctx.insert("new_count", &new_count);
+
+    let folders = item::find_by_type(&data.pool, "folder", &current.viewer())
+        .await
+        .unwrap_or(vec![]);
+    ctx.insert("parents", &folders);
+    let all_tags = tag::find_all(&data.pool).await.unwrap_or(vec![]);
+    ctx.insert("tags", &all_tags);
+
+    let template = tmpl
+        .render("upload_batch.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
 
-            item.name = file_name.to_string();
-            item.path = dest_file
-                .strip_prefix(data.root_dir.as_path())
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            item.file_type = guess_file_type(real_file_name).to_string();
-            item.md5 = form.md5.as_ref().unwrap().clone();
-            if let Ok(id) = item::insert(&data.pool, &item).await {
-                if let Some(_tags) = &form.tags {
-                    let tags: Vec<&str> = _tags.split_whitespace().collect();
-                    tag::update_item_tags(&data.pool, id, tags).await;
+// Adds the new files of an upload with the same album and tags. Files that cannot be added stay
+// on the upload page with the reason.
+#[post("/upload/batch/{batch}/")]
+pub async fn post_batch(
+    data: web::Data<AppState>,
+    batch_id: web::Path<String>,
+    form: web::Form<BatchForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    let files = batch_file::find_by_batch(&data.pool, &batch_id, current.id())
+        .await
+        .unwrap_or_default();
+    if files.is_empty() {
+        return HttpResponse::NotFound().body("Not found!");
+    }
+
+    let parent = find_album(&data, &form.parent, &current).await;
+    if wants_album(&form.parent) && parent.is_none() {
+        return redirect!(format!("/upload/batch/{}/?error=Album+not+found", batch_id));
+    }
+    let results = add_batch(&data, &files, parent.as_ref(), &form, &current, "upload").await;
+    let failed = results.iter().any(|(_, result)| result.is_err());
+
+    if failed {
+        return redirect!(format!("/upload/batch/{}/", batch_id));
+    }
+    if let Err(err) = batch_file::delete_by_batch(&data.pool, &batch_id).await {
+        eprintln!("Failed to delete upload {}. {:?}", batch_id, err);
+    }
+    let added: Vec<i64> = results.into_iter().filter_map(|(_, result)| result.ok()).collect();
+    match (parent, added.as_slice()) {
+        (Some(parent), _) => redirect!(format!("/?id={}", parent.id)),
+        (None, [id]) => redirect!(format!("/?id={}", id)),
+        _ => redirect!("/"),
+    }
+}
+
+// Add the new files of a batch under `parent` with the name pattern and tags of `form`. Returns
+// the id of the item of each file, or why it could not be added; those files are marked rejected.
+// `method` is how the files were sent, files extracted from an archive are added as `archive`.
+async fn add_batch(
+    data: &AppState,
+    files: &[BatchFile],
+    parent: Option<&Item>,
+    form: &BatchForm,
+    current: &CurrentUser,
+    method: &str,
+) -> Vec<(i64, Result<i64, &'static str>)> {
+    let pattern = form
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .unwrap_or("{name}.{ext}");
+    let tags: Vec<&str> = form.tags.as_deref().unwrap_or_default().split_whitespace().collect();
+
+    let mut created = Vec::new();
+    let mut series = Vec::new();
+    let mut results = Vec::new();
+    for (n, file) in files.iter().filter(|file| file.status == "new").enumerate() {
+        let folder = if file.folder.is_empty() {
+            None
+        } else {
+            find_or_create_folder(data, parent, &file.folder, current.id(), &current.viewer(), &mut created)
+                .await
+        };
+        let result = if !file.folder.is_empty() && folder.is_none() {
+            Err("Could not create its album")
+        } else {
+            let name = apply_pattern(pattern, &file.name, n + 1);
+            let real_name = file.real_name.as_deref().unwrap_or_default();
+            let md5 = file.md5.as_deref().unwrap_or_default();
+            let album = folder.as_ref().or(parent);
+            let origin = Origin {
+                original_name: &file.name,
+                uploader: current.id(),
+                method: if file.archive.is_some() { "archive" } else { method },
+            };
+            add_item(data, real_name, &name, md5, album, tags.clone(), origin)
+                .await
+                .ok_or("Could not be added")
+        };
+        match result {
+            Ok(_) => {
+                let comic = file.archive.as_deref().is_some_and(archive::is_comic);
+                if let Some(folder) = folder.as_ref().filter(|_| comic && form.series.is_some()) {
+                    if !series.contains(&folder.id) {
+                        series.push(folder.id);
+                    }
+                }
+                if let Err(err) = batch_file::delete_by_id(&data.pool, file.id).await {
+                    eprintln!("Failed to delete upload {}. {:?}", file.id, err);
                 }
+            }
+            Err(message) => {
+                if let Err(err) =
+                    batch_file::update_status(&data.pool, file.id, "rejected", Some(message)).await
+                {
+                    eprintln!("Failed to update upload {}. {:?}", file.id, err);
+                }
+            }
+        }
+        results.push((file.id, result));
+    }
+
+    // Tags of a series go on its album, which lists the pages in order
+    for id in series {
+        let mut album_tags = tags.clone();
+        album_tags.push("series");
+        if let Err(err) = tag::update_item_tags(&data.pool, id, album_tags, current.id()).await {
+            eprintln!("Failed to tag item {}. {:?}", id, err);
+        }
+    }
+
+    for folder in created {
+        create_thumbnail(
+            data.root_dir.to_str().unwrap(),
+            data.thumbnail_dir.to_str().unwrap(),
+            data.root_dir.join(&folder).to_str().unwrap(),
+            "folder",
+            true,
+        );
+    }
+    results
+}
 
-                create_thumbnail(
-                    data.root_dir.to_str().unwrap(),
-                    data.thumbnail_dir.to_str().unwrap(),
-                    dest_file.to_str().unwrap(),
-                    &item.file_type,
-                    false,
-                );
-                return redirect!(format!("/?id={}", id));
+#[post("/post_upload/")]
+pub async fn post_upload(
+    data: web::Data<AppState>,
+    form: web::Form<PostData>,
+    current: CurrentUser,
+) -> impl Responder {
+    let (real_name, md5) = match (&form.real_name, &form.md5) {
+        // A file directly in `tmp/`
+        (Some(real_name), Some(md5)) if is_safe(real_name) && !real_name.contains('/') => (real_name, md5),
+        _ => return upload_error(&[("error", "No file was sent")]),
+    };
+    let name = form.name.as_deref().unwrap_or(real_name);
+    let original_name = form.original_name.as_deref().unwrap_or(name);
+    let source = form.source.as_deref().filter(|source| !source.is_empty());
+    // The form again, as the file still waits in `tmp/`
+    let back = |error: &str| {
+        upload_error(&[
+            ("file_name", original_name),
+            ("real_file_name", real_name),
+            ("md5", md5),
+            ("source", source.unwrap_or_default()),
+            ("error", error),
+        ])
+    };
+    let waiting = batch_file::find_new_by_real_name(&data.pool, real_name, current.id()).await;
+    let waiting = match waiting {
+        Ok(waiting) if data.root_dir.join("tmp").join(real_name).is_file() => waiting,
+        _ => return upload_error(&[("error", "The file is no longer there, upload it again")]),
+    };
+    let parent = find_album(&data, &form.parent, &current).await;
+    if wants_album(&form.parent) && parent.is_none() {
+        return back("Album not found");
+    }
+
+    let tags: Vec<&str> = form.tags.as_deref().unwrap_or_default().split_whitespace().collect();
+    let origin = Origin {
+        original_name,
+        uploader: current.id(),
+        method: if source.is_some() { "url" } else { "upload" },
+    };
+    match add_item(&data, real_name, name, md5, parent.as_ref(), tags, origin).await {
+        Some(id) => {
+            if let Err(err) = batch_file::delete_by_id(&data.pool, waiting.id).await {
+                eprintln!("Failed to delete upload {}. {:?}", waiting.id, err);
+            }
+            // The URL a file was downloaded from
+            if let Some(source) = source {
+                if let Err(err) = item_source::insert(&data.pool, id, source).await {
+                    eprintln!("Failed to save source of item {}. {:?}", id, err);
+                }
             }
+            redirect!(format!("/?id={}", id))
         }
+        None => back("Could not be added"),
+    }
+}
+
+// Archives waiting in the import folder
+#[get("/upload/import/")]
+pub async fn import(data: web::Data<AppState>, tmpl: web::Data<tera::Tera>, csrf: csrf::Token) -> impl Responder {
+    let import_dir = match &data.import_dir {
+        Some(import_dir) => import_dir,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+    let mut archives: Vec<ImportArchive> = std::fs::read_dir(import_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+                    let name = entry.file_name().into_string().ok()?;
+                    archive::is_archive(&name).then_some(ImportArchive {
+                        name,
+                        size: metadata.len(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    archives.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut ctx = tera::Context::new();
+
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("archives", &archives);
+    let template = tmpl
+        .render("upload_import.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+// Extracts an archive of the import folder into a new upload, and removes it from the folder
+#[post("/upload/import/")]
+pub async fn post_import(
+    data: web::Data<AppState>,
+    form: web::Form<ImportForm>,
+    current: CurrentUser,
+) -> impl Responder {
+    let import_dir = match &data.import_dir {
+        Some(import_dir) => import_dir,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+    let name = match form.name.as_deref() {
+        Some(name) if is_safe(name) && !name.contains('/') && archive::is_archive(name) => name,
+        _ => return HttpResponse::NotFound().body("Not found!"),
+    };
+    let path = import_dir.join(name);
+    if !path.is_file() || create_dir_all(data.root_dir.join("tmp")).is_err() {
+        return HttpResponse::NotFound().body("Not found!");
     }
 
-    redirect!("/upload/")
+    let batch_id = csrf::new_token();
+    let file = BatchFile::new(&batch_id, current.id(), name, "");
+    if store_archive(&data, &path, &file).await {
+        remove_file(&path);
+    }
+    redirect!(format!("/upload/batch/{}/", batch_id))
 }
@@ -0,0 +1,114 @@
+use actix_web::{error, get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+use super::auth::{hash_password, CurrentUser};
+use super::{csrf, redirect, AppState};
+use crate::db::session;
+use crate::db::user::{self, Role};
+
+#[derive(Deserialize)]
+pub struct UserForm {
+    id: Option<i64>,
+    name: Option<String>,
+    password: Option<String>,
+    role: Option<String>,
+}
+
+// Whether removing the admin role from a user would leave the board without an admin
+async fn is_last_admin(data: &AppState, id: i64) -> bool {
+    match user::find_by_id(&data.pool, id).await {
+        Ok(user) if user.role() == Role::Admin => {
+            user::count_by_role(&data.pool, Role::Admin)
+                .await
+                .unwrap_or_default()
+                <= 1
+        }
+        _ => false,
+    }
+}
+
+#[get("/admin/users/")]
+pub async fn list(
+    data: web::Data<AppState>,
+    tmpl: web::Data<tera::Tera>,
+    current: CurrentUser,
+    csrf: csrf::Token,
+) -> impl Responder {
+    let mut ctx = tera::Context::new();
+    ctx.insert(csrf::FIELD, &csrf.0);
+    ctx.insert("users", &user::find_all(&data.pool).await.unwrap_or_default());
+    ctx.insert("current_user", &current.0);
+
+    let template = tmpl
+        .render("users.html", &ctx)
+        .map_err(|_| error::ErrorInternalServerError("Template error"))
+        .unwrap();
+    HttpResponse::Ok().content_type("text/html").body(template)
+}
+
+#[post("/admin/users/")]
+pub async fn create(data: web::Data<AppState>, form: web::Form<UserForm>) -> impl Responder {
+    let name = form.name.as_deref().unwrap_or_default().trim();
+    let password = form.password.as_deref().unwrap_or_default();
+    let role = form.role.as_deref().and_then(Role::parse).unwrap_or(Role::Viewer);
+    if name.is_empty() || password.is_empty() {
+        return redirect!("/admin/users/");
+    }
+
+    match hash_password(password) {
+        Some(hash) => {
+            if let Err(err) = user::insert(&data.pool, name, &hash, role).await {
+                eprintln!("Failed to create user {}. {:?}", name, err);
+            }
+        }
+        None => eprintln!("Failed to hash password of {}", name),
+    }
+    redirect!("/admin/users/")
+}
+
+#[post("/admin/users/role/")]
+pub async fn update_role(data: web::Data<AppState>, form: web::Form<UserForm>) -> impl Responder {
+    if let (Some(id), Some(role)) = (form.id, form.role.as_deref().and_then(Role::parse)) {
+        if role != Role::Admin && is_last_admin(&data, id).await {
+            eprintln!("Refusing to remove the last admin");
+        } else if let Err(err) = user::update_role(&data.pool, id, role).await {
+            eprintln!("Failed to update role of user {}. {:?}", id, err);
+        }
+    }
+    redirect!("/admin/users/")
+}
+
+// Also logs the user out everywhere
+#[post("/admin/users/password/")]
+pub async fn update_password(
+    data: web::Data<AppState>,
+    form: web::Form<UserForm>,
+) -> impl Responder {
+    let password = form.password.as_deref().unwrap_or_default();
+    if let (Some(id), false) = (form.id, password.is_empty()) {
+        match hash_password(password) {
+            Some(hash) => {
+                if let Err(err) = user::update_password(&data.pool, id, &hash).await {
+                    eprintln!("Failed to update password of user {}. {:?}", id, err);
+                }
+                if let Err(err) = session::delete_by_user(&data.pool, id).await {
+                    eprintln!("Failed to delete sessions of user {}. {:?}", id, err);
+                }
+            }
+            None => eprintln!("Failed to hash password of user {}", id),
+        }
+    }
+    redirect!("/admin/users/")
+}
+
+#[post("/admin/users/delete/")]
+pub async fn delete(data: web::Data<AppState>, form: web::Form<UserForm>) -> impl Responder {
+    if let Some(id) = form.id {
+        if is_last_admin(&data, id).await {
+            eprintln!("Refusing to delete the last admin");
+        } else if let Err(err) = user::delete_by_id(&data.pool, id).await {
+            eprintln!("Failed to delete user {}. {:?}", id, err);
+        }
+    }
+    redirect!("/admin/users/")
+}
@@ -0,0 +1,12 @@
+// Helpers shared by the tests of several modules
+
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::PathBuf;
+
+// An empty folder for the files of a test, named after it so tests running at once do not share one
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mediaboard-{}-{}", name, std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}